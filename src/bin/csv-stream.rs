@@ -0,0 +1,930 @@
+//! Convert JSON Lines or JSON arrays into CSV.
+//!
+//! Records are read from the given files, or from stdin, and are written to
+//! stdout as they are parsed, so that arbitrarily large inputs can be
+//! converted in constant memory.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::sync::mpsc::{self, SyncSender};
+use std::{fmt, thread};
+
+use clap::{Parser, ValueEnum};
+use csv_stream::{
+    ErrorPolicy, HeaderOrder, LimitPolicy, QuoteStyle, Sanitize, Terminator, WriterBuilder,
+};
+use indexmap::IndexMap;
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{Serialize, Serializer};
+use serde_json::Number;
+
+/// Convert JSON Lines (NDJSON) or a JSON array of objects into CSV.
+///
+/// Each input may hold either one JSON value per line, or a single array of
+/// values if it starts with `[`. If the first value is an object, every
+/// value must be an object, and they are written as records with their keys
+/// as the header row. Otherwise arrays are written as records as they are,
+/// and any other value is written as a record with a single field.
+#[derive(Debug, Parser)]
+#[command(name = "csv-stream", version)]
+struct Args {
+    /// The files to read, or `-` for stdin. Reads stdin if none are given.
+    files: Vec<PathBuf>,
+
+    /// The field delimiter, such as `;` or `\t`.
+    #[arg(short, long, default_value = ",", value_parser = parse_byte)]
+    delimiter: u8,
+
+    /// When to quote fields.
+    #[arg(long, value_enum, default_value_t = QuoteStyleArg::Necessary)]
+    quote_style: QuoteStyleArg,
+
+    /// When to quote the fields of one column, such as `id=always`. May be
+    /// given multiple times.
+    #[arg(long, value_name = "NAME=STYLE", value_parser = parse_column::<String>)]
+    column_quote_style: Vec<(String, QuoteStyleArg)>,
+
+    /// When to quote the fields of the column at an index, starting from
+    /// `0`, such as `2=never`. May be given multiple times.
+    #[arg(long, value_name = "INDEX=STYLE", value_parser = parse_column::<usize>)]
+    column_index_quote_style: Vec<(usize, QuoteStyleArg)>,
+
+    /// The quote character.
+    #[arg(long, default_value = "\"", value_parser = parse_byte)]
+    quote: u8,
+
+    /// Escape quotes inside fields with the escape character, instead of
+    /// doubling them.
+    #[arg(long)]
+    no_double_quote: bool,
+
+    /// The escape character, used with `--no-double-quote`.
+    #[arg(long, default_value = "\\", value_parser = parse_byte)]
+    escape: u8,
+
+    /// The record terminator: `lf`, `crlf` or any single character.
+    #[arg(long, default_value = "lf", value_parser = parse_terminator)]
+    terminator: Terminator,
+
+    /// The comment character, used to write the preamble.
+    #[arg(long, value_parser = parse_byte)]
+    comment: Option<u8>,
+
+    /// A comment line to write before the data. May be given multiple times.
+    #[arg(long, value_name = "LINE", requires = "comment")]
+    preamble: Vec<String>,
+
+    /// Do not write a header row.
+    #[arg(long)]
+    no_headers: bool,
+
+    /// Allow records with a different number of fields. Keys that are not
+    /// in the header row are then added as new columns at the end of every
+    /// following record, instead of being an error.
+    #[arg(long)]
+    flexible: bool,
+
+    /// What to do with records that fail to be written.
+    #[arg(long, value_enum, default_value_t = ErrorPolicyArg::Continue)]
+    error_policy: ErrorPolicyArg,
+
+    /// How to sanitize line breaks and other control characters in fields.
+    #[arg(long, value_enum, default_value_t = SanitizeArg::None)]
+    sanitize: SanitizeArg,
+
+    /// The replacement for control characters with `--sanitize replace`.
+    #[arg(long, value_name = "TEXT", default_value = " ")]
+    sanitize_replacement: String,
+
+    /// The maximum length of a field, in bytes.
+    #[arg(long, value_name = "BYTES")]
+    max_field_len: Option<u64>,
+
+    /// The maximum length of a record, in bytes.
+    #[arg(long, value_name = "BYTES")]
+    max_record_len: Option<u64>,
+
+    /// The maximum number of records to write, not including the header row.
+    #[arg(long, value_name = "N")]
+    max_records: Option<u64>,
+
+    /// The maximum length of the output, in bytes.
+    #[arg(long, value_name = "BYTES")]
+    max_bytes: Option<u64>,
+
+    /// What to do with records that go over one of the `--max-*` limits.
+    #[arg(long, value_enum, default_value_t = LimitPolicyArg::Error)]
+    limit_policy: LimitPolicyArg,
+
+    /// The marker that truncated fields end with.
+    #[arg(long, value_name = "MARKER", default_value = "...")]
+    ellipsis: String,
+
+    /// The size of the output buffer, in bytes.
+    #[arg(long, value_name = "BYTES", default_value_t = 8 * (1 << 10))]
+    buffer_capacity: usize,
+
+    /// Only write these columns, in this order. Other keys are ignored.
+    #[arg(short, long, value_name = "NAME", value_delimiter = ',')]
+    columns: Vec<String>,
+
+    /// Flatten nested objects into columns named by joining their keys.
+    /// Otherwise, nested objects and arrays are written as JSON.
+    #[arg(long)]
+    flatten: bool,
+
+    /// The separator used to join the keys of nested objects.
+    #[arg(long, value_name = "SEP", default_value = ".", requires = "flatten")]
+    flatten_separator: String,
+
+    /// Read every input as JSON Lines, even if it starts with an array.
+    #[arg(long)]
+    lines: bool,
+
+    /// The number of records to read the keys of before writing the header
+    /// row, for objects that do not all have the same keys.
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    infer_headers: usize,
+
+    /// The order of the columns of the header row.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = HeaderOrderArg::FirstSeen,
+        conflicts_with = "columns"
+    )]
+    header_order: HeaderOrderArg,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum QuoteStyleArg {
+    Always,
+    Necessary,
+    NonNumeric,
+    Never,
+    Strings,
+}
+
+impl QuoteStyleArg {
+    fn to_quote_style(self) -> QuoteStyle {
+        match self {
+            QuoteStyleArg::Always => QuoteStyle::Always,
+            QuoteStyleArg::Necessary => QuoteStyle::Necessary,
+            QuoteStyleArg::NonNumeric => QuoteStyle::NonNumeric,
+            QuoteStyleArg::Never => QuoteStyle::Never,
+            QuoteStyleArg::Strings => QuoteStyle::Strings,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum HeaderOrderArg {
+    FirstSeen,
+    Sorted,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ErrorPolicyArg {
+    Continue,
+    Abort,
+    Skip,
+    Placeholder,
+    Comment,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum SanitizeArg {
+    None,
+    Replace,
+    Escape,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum LimitPolicyArg {
+    Error,
+    Truncate,
+    Stop,
+}
+
+/// Parse a single byte argument, such as a delimiter.
+fn parse_byte(s: &str) -> Result<u8, String> {
+    match s {
+        "\\t" | "tab" => Ok(b'\t'),
+        _ if s.len() == 1 => Ok(s.as_bytes()[0]),
+        _ => Err("must be a single ASCII character".to_owned()),
+    }
+}
+
+/// Parse a `COLUMN=STYLE` argument, where the column is a name or an index.
+fn parse_column<T: FromStr>(s: &str) -> Result<(T, QuoteStyleArg), String> {
+    let (column, style) = s
+        .rsplit_once('=')
+        .ok_or_else(|| "must be of the form COLUMN=STYLE".to_owned())?;
+    let column = column
+        .parse()
+        .map_err(|_| format!("invalid column '{}'", column))?;
+    Ok((column, QuoteStyleArg::from_str(style, true)?))
+}
+
+fn parse_terminator(s: &str) -> Result<Terminator, String> {
+    match s {
+        "lf" => Ok(Terminator::Any(b'\n')),
+        "crlf" => Ok(Terminator::CRLF),
+        _ => parse_byte(s).map(Terminator::Any),
+    }
+}
+
+impl Args {
+    /// Configure a writer, inferring the header row from the keys of objects
+    /// if `objects` is set.
+    fn builder(&self, objects: bool) -> WriterBuilder {
+        let mut builder = WriterBuilder::default();
+        builder
+            .delimiter(self.delimiter)
+            .quote_style(self.quote_style.to_quote_style())
+            .quote(self.quote)
+            .double_quote(!self.no_double_quote)
+            .escape(self.escape)
+            .terminator(self.terminator)
+            .has_headers(!self.no_headers)
+            .flexible(self.flexible)
+            .buffer_capacity(self.buffer_capacity)
+            .sanitize(match self.sanitize {
+                SanitizeArg::None => Sanitize::None,
+                SanitizeArg::Replace => Sanitize::Replace,
+                SanitizeArg::Escape => Sanitize::Escape,
+            })
+            .sanitize_replacement(&self.sanitize_replacement)
+            .error_policy(match self.error_policy {
+                ErrorPolicyArg::Continue => ErrorPolicy::Continue,
+                ErrorPolicyArg::Abort => ErrorPolicy::Abort,
+                ErrorPolicyArg::Skip => ErrorPolicy::Skip,
+                ErrorPolicyArg::Placeholder => ErrorPolicy::Placeholder,
+                ErrorPolicyArg::Comment => ErrorPolicy::Comment,
+            });
+        for (name, style) in &self.column_quote_style {
+            builder.column_quote_style(name, style.to_quote_style());
+        }
+        for &(index, style) in &self.column_index_quote_style {
+            builder.column_index_quote_style(index, style.to_quote_style());
+        }
+        let limit_policy = match self.limit_policy {
+            LimitPolicyArg::Error => LimitPolicy::Error,
+            LimitPolicyArg::Truncate => LimitPolicy::Truncate,
+            LimitPolicyArg::Stop => LimitPolicy::Stop,
+        };
+        if let Some(len) = self.max_field_len {
+            builder.max_field_len(len, limit_policy);
+        }
+        if let Some(len) = self.max_record_len {
+            builder.max_record_len(len, limit_policy);
+        }
+        if let Some(records) = self.max_records {
+            builder.max_records(records, limit_policy);
+        }
+        if let Some(bytes) = self.max_bytes {
+            builder.max_bytes(bytes, limit_policy);
+        }
+        builder.ellipsis(&self.ellipsis);
+        if let Some(comment) = self.comment {
+            builder.comment(comment).preamble(&self.preamble);
+        }
+        if objects {
+            builder
+                .infer_headers(self.infer_headers.max(1))
+                .header_order(match self.header_order {
+                    HeaderOrderArg::FirstSeen => HeaderOrder::FirstSeen,
+                    HeaderOrderArg::Sorted => HeaderOrder::Sorted,
+                });
+        }
+        builder
+    }
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let mut inputs: Vec<Input> = vec![];
+    if args.files.is_empty() {
+        inputs.push(("<stdin>".to_owned(), Box::new(io::stdin())));
+    }
+    for path in &args.files {
+        if path.as_os_str() == "-" {
+            inputs.push(("<stdin>".to_owned(), Box::new(io::stdin())));
+            continue;
+        }
+        match File::open(path) {
+            Ok(file) => inputs.push((path.display().to_string(), Box::new(file))),
+            Err(err) => {
+                eprintln!("csv-stream: {}: {}", path.display(), err);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let stdout = BufWriter::with_capacity(args.buffer_capacity, io::stdout().lock());
+    match convert(&args, inputs, stdout, io::stderr()) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        // Stop quietly when the output is closed, such as by `head`.
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("csv-stream: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// A named input to read JSON from.
+type Input = (String, Box<dyn Read + Send>);
+
+/// Convert all inputs, writing the CSV to `out` and any errors to `errors`.
+///
+/// Returns whether every record was converted successfully.
+fn convert(
+    args: &Args,
+    inputs: Vec<Input>,
+    mut out: impl Write,
+    mut errors: impl Write,
+) -> io::Result<bool> {
+    // Parse on another thread, so that a JSON array can be streamed through
+    // a visitor one element at a time.
+    let (tx, rx) = mpsc::sync_channel(1024);
+    let lines = args.lines;
+    let reader = thread::spawn(move || read_inputs(inputs, lines, tx));
+
+    // The first value decides whether the header row is made up of the keys
+    // of objects.
+    let mut values = rx.into_iter().peekable();
+    let objects = !args.columns.is_empty() || matches!(values.peek(), Some(Json::Object(_)));
+    let records = values.map(|value| Record::new(value, args));
+    let mut ok = true;
+    for res in args.builder(objects).build_iter(records) {
+        match res {
+            Ok(buf) => out.write_all(&buf)?,
+            Err(err) => {
+                ok = false;
+                writeln!(errors, "csv-stream: {}", err)?;
+            }
+        }
+    }
+    out.flush()?;
+
+    if let Err(err) = reader.join().expect("reader thread panicked") {
+        ok = false;
+        writeln!(errors, "csv-stream: {}", err)?;
+    }
+    Ok(ok)
+}
+
+/// Parse every input in turn, sending each value down `tx`.
+///
+/// Stops at the first invalid input, or once the receiver hangs up.
+fn read_inputs(inputs: Vec<Input>, lines: bool, tx: SyncSender<Json>) -> Result<(), String> {
+    for (name, input) in inputs {
+        let mut closed = false;
+        let res = read_input(BufReader::new(input), lines, &tx, &mut closed);
+        if closed {
+            break;
+        }
+        res.map_err(|err| format!("{}: {}", name, err))?;
+    }
+    Ok(())
+}
+
+fn read_input(
+    mut input: impl BufRead,
+    lines: bool,
+    tx: &SyncSender<Json>,
+    closed: &mut bool,
+) -> serde_json::Result<()> {
+    // Look at the first byte to tell a JSON array from JSON Lines.
+    let is_array = loop {
+        let buf = input.fill_buf().map_err(serde_json::Error::io)?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        match buf.iter().position(|b| !b.is_ascii_whitespace()) {
+            Some(i) => {
+                let is_array = !lines && buf[i] == b'[';
+                input.consume(i);
+                break is_array;
+            }
+            None => {
+                let len = buf.len();
+                input.consume(len);
+            }
+        }
+    };
+
+    let mut de = serde_json::Deserializer::from_reader(input);
+    if is_array {
+        de.deserialize_seq(SendValues { tx, closed })?;
+        de.end()
+    } else {
+        for value in de.into_iter() {
+            if tx.send(value?).is_err() {
+                *closed = true;
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sends each element of a JSON array as it is parsed.
+struct SendValues<'a> {
+    tx: &'a SyncSender<Json>,
+    closed: &'a mut bool,
+}
+
+impl<'de> Visitor<'de> for SendValues<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a JSON array")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(value) = seq.next_element()? {
+            if self.tx.send(value).is_err() {
+                *self.closed = true;
+                return Err(de::Error::custom("output closed"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A JSON value that keeps the keys of objects in the order they were read.
+#[derive(Clone, Debug)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(Number),
+    String(String),
+    Array(Vec<Json>),
+    Object(IndexMap<String, Json>),
+}
+
+impl<'de> Deserialize<'de> for Json {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Json, D::Error> {
+        deserializer.deserialize_any(JsonVisitor)
+    }
+}
+
+struct JsonVisitor;
+
+impl<'de> Visitor<'de> for JsonVisitor {
+    type Value = Json;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("any JSON value")
+    }
+
+    fn visit_unit<E>(self) -> Result<Json, E> {
+        Ok(Json::Null)
+    }
+
+    fn visit_none<E>(self) -> Result<Json, E> {
+        Ok(Json::Null)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Json, D::Error> {
+        Json::deserialize(deserializer)
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Json, E> {
+        Ok(Json::Bool(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Json, E> {
+        Ok(Json::Number(v.into()))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Json, E> {
+        Ok(Json::Number(v.into()))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Json, E> {
+        Ok(Number::from_f64(v).map_or(Json::Null, Json::Number))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Json, E> {
+        Ok(Json::String(v.to_owned()))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Json, E> {
+        Ok(Json::String(v))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Json, A::Error> {
+        let mut values = vec![];
+        while let Some(value) = seq.next_element()? {
+            values.push(value);
+        }
+        Ok(Json::Array(values))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Json, A::Error> {
+        let mut object = IndexMap::new();
+        while let Some((key, value)) = map.next_entry()? {
+            object.insert(key, value);
+        }
+        Ok(Json::Object(object))
+    }
+}
+
+impl Serialize for Json {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Json::Null => serializer.serialize_unit(),
+            Json::Bool(v) => serializer.serialize_bool(*v),
+            Json::Number(v) => v.serialize(serializer),
+            Json::String(v) => serializer.serialize_str(v),
+            Json::Array(values) => serializer.collect_seq(values),
+            Json::Object(map) => serializer.collect_map(map),
+        }
+    }
+}
+
+/// A record to write for a JSON value.
+enum Record {
+    /// The keys and values of an object, in order.
+    Object(Vec<(String, Json)>),
+    /// The values of an array or a single other value.
+    Row(Vec<Json>),
+}
+
+impl Record {
+    fn new(value: Json, args: &Args) -> Record {
+        match value {
+            Json::Object(map) => {
+                let mut fields = vec![];
+                flatten(&mut fields, None, map, args);
+                if !args.columns.is_empty() {
+                    fields = select(fields, &args.columns);
+                }
+                Record::Object(fields)
+            }
+            Json::Array(values) => Record::Row(values.into_iter().map(scalar).collect()),
+            value => Record::Row(vec![value]),
+        }
+    }
+}
+
+impl Serialize for Record {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Record::Object(fields) => serializer.collect_map(fields.iter().map(|(k, v)| (k, v))),
+            Record::Row(values) => values.serialize(serializer),
+        }
+    }
+}
+
+/// Keep only the selected columns of an object, in order, with a null value
+/// for any that are missing.
+fn select(fields: Vec<(String, Json)>, columns: &[String]) -> Vec<(String, Json)> {
+    let fields: HashMap<String, Json> = fields.into_iter().collect();
+    columns
+        .iter()
+        .map(|name| {
+            (
+                name.clone(),
+                fields.get(name).cloned().unwrap_or(Json::Null),
+            )
+        })
+        .collect()
+}
+
+/// Collect the fields of `map`, with the keys of nested objects joined to
+/// `prefix` if flattening.
+fn flatten(
+    fields: &mut Vec<(String, Json)>,
+    prefix: Option<&str>,
+    map: IndexMap<String, Json>,
+    args: &Args,
+) {
+    for (key, value) in map {
+        let key = match prefix {
+            Some(prefix) => format!("{}{}{}", prefix, args.flatten_separator, key),
+            None => key,
+        };
+        match value {
+            Json::Object(map) if args.flatten => flatten(fields, Some(&key), map, args),
+            value => fields.push((key, scalar(value))),
+        }
+    }
+}
+
+/// Write nested objects and arrays as JSON text.
+fn scalar(value: Json) -> Json {
+    match value {
+        Json::Object(_) | Json::Array(_) => {
+            Json::String(serde_json::to_string(&value).expect("JSON values serialize"))
+        }
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use clap::Parser;
+
+    use super::{convert, Args};
+
+    /// Run the conversion on the given inputs, returning the output, the
+    /// errors and whether it succeeded.
+    fn run(args: &[&str], inputs: &[&'static str]) -> (String, String, bool) {
+        let args = Args::parse_from(["csv-stream"].iter().chain(args));
+        let inputs = inputs
+            .iter()
+            .map(|input| {
+                let input: Box<dyn Read + Send> = Box::new(input.as_bytes());
+                ("<test>".to_owned(), input)
+            })
+            .collect();
+
+        let (mut out, mut errors) = (vec![], vec![]);
+        let ok = convert(&args, inputs, &mut out, &mut errors).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        (out, String::from_utf8(errors).unwrap(), ok)
+    }
+
+    #[test]
+    fn json_lines() {
+        let input = r#"{"city": "Boston", "population": 4628910, "tags": ["a", "b"]}
+            {"city": "Concord, NH", "population": null, "tags": {"c": true}}
+        "#;
+        let (out, errors, ok) = run(&[], &[input]);
+        assert_eq!(
+            out,
+            "city,population,tags\n\
+             Boston,4628910,\"[\"\"a\"\",\"\"b\"\"]\"\n\
+             \"Concord, NH\",,\"{\"\"c\"\":true}\"\n"
+        );
+        assert_eq!(errors, "");
+        assert!(ok);
+
+        // Keys keep the order they were read in, even when nested.
+        let input = r#"{"z": 1, "a": {"y": 2, "b": 3}, "m": [4]}"#;
+        let (out, errors, ok) = run(&[], &[input]);
+        assert_eq!(out, "z,a,m\n1,\"{\"\"y\"\":2,\"\"b\"\":3}\",[4]\n");
+        assert_eq!(errors, "");
+        assert!(ok);
+        let (out, _, ok) = run(&["--flatten"], &[input]);
+        assert_eq!(out, "z,a.y,a.b,m\n1,2,3,[4]\n");
+        assert!(ok);
+
+        let (out, _, ok) = run(&[], &["\"x\"\n2\nnull\n"]);
+        assert_eq!(out, "x\n2\n\"\"\n");
+        assert!(ok);
+
+        // Once the header row is made up of keys, every value must be an
+        // object.
+        let (out, errors, ok) = run(&[], &["{\"a\": 1}\n[2]\n"]);
+        assert_eq!(out, "a\n1\n");
+        assert!(
+            errors.contains("records must be maps or structs"),
+            "{}",
+            errors
+        );
+        assert!(!ok);
+    }
+
+    #[test]
+    fn json_array_flatten() {
+        let first = r#"[
+            {"id": 1, "owner": {"name": "a", "address": {"city": "Boston"}}},
+            {"id": 2, "owner": {"name": "b", "address": {}}}
+        ]"#;
+        let second = r#"{"id": 3, "owner": {"name": "c", "address": {"city": "Rome"}}}"#;
+        let args = ["--flatten", "--flatten-separator", "_"];
+        let (out, errors, ok) = run(&args, &[first, second]);
+        assert_eq!(
+            out,
+            "id,owner_name,owner_address_city\n1,a,Boston\n2,b,\n3,c,Rome\n"
+        );
+        assert_eq!(errors, "");
+        assert!(ok);
+    }
+
+    #[test]
+    fn infer_headers() {
+        let input = r#"{"a": 1}
+            {"b": 2, "a": 3}
+            {"c": 4, "d": 5}
+        "#;
+        let (out, errors, ok) = run(&[], &[input]);
+        assert_eq!(out, "a\n1\n");
+        assert!(
+            errors.contains("(\"b\"): field is not in the inferred header row\n"),
+            "{}",
+            errors
+        );
+        assert!(
+            errors.contains("(\"c\"): field is not in the inferred header row\n"),
+            "{}",
+            errors
+        );
+        assert!(!ok);
+
+        let (out, _, ok) = run(&["--infer-headers", "2"], &[input]);
+        assert_eq!(out, "a,b\n1,\n3,2\n");
+        assert!(!ok);
+
+        let (out, errors, ok) = run(&["--infer-headers", "2", "--flexible"], &[input]);
+        assert_eq!(out, "a,b\n1,\n3,2\n,,4,5\n");
+        assert_eq!(errors, "");
+        assert!(ok);
+
+        let args = ["--infer-headers", "3", "--header-order", "sorted"];
+        let (out, errors, ok) = run(&args, &[input]);
+        assert_eq!(out, "a,b,c,d\n1,,,\n3,2,,\n,,4,5\n");
+        assert_eq!(errors, "");
+        assert!(ok);
+    }
+
+    #[test]
+    fn columns() {
+        let input = r#"{"a": 1, "b": 2, "c": 3}
+            {"c": 4, "d": 5}
+        "#;
+        let (out, errors, ok) = run(&["--columns", "c,a", "-c", "e"], &[input]);
+        assert_eq!(out, "c,a,e\n3,1,\n4,,\n");
+        assert_eq!(errors, "");
+        assert!(ok);
+    }
+
+    #[test]
+    fn writer_options() {
+        let input = r#"[{"a": "x y", "b": 1}, {"a": "z", "b": 2}]"#;
+        let args = [
+            "-d",
+            "\\t",
+            "--quote-style",
+            "non-numeric",
+            "--terminator",
+            "crlf",
+            "--no-headers",
+            "--comment",
+            "#",
+            "--preamble",
+            "exported",
+        ];
+        let (out, _, ok) = run(&args, &[input]);
+        assert_eq!(out, "#exported\r\n\"x y\"\t1\r\n\"z\"\t2\r\n");
+        assert!(ok);
+    }
+
+    #[test]
+    fn column_quote_style() {
+        let input = r#"{"id": "007", "name": "Bond", "score": 1.5}"#;
+        let args = [
+            "--quote-style",
+            "always",
+            "--column-quote-style",
+            "score=never",
+            "--column-quote-style",
+            "name=necessary",
+        ];
+        let (out, _, ok) = run(&args, &[input]);
+        assert_eq!(out, "\"id\",name,score\n\"007\",Bond,1.5\n");
+        assert!(ok);
+
+        let args = ["--column-index-quote-style", "1=always"];
+        let (out, _, ok) = run(&args, &["[[\"a\", 1, 2]]"]);
+        assert_eq!(out, "a,\"1\",2\n");
+        assert!(ok);
+
+        let args = ["csv-stream", "--column-quote-style", "score"];
+        assert!(Args::try_parse_from(args).is_err());
+        let args = ["csv-stream", "--column-index-quote-style", "x=never"];
+        assert!(Args::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn error_policy() {
+        let input = r#"{"a": 1}
+            {"b": 2}
+            {"a": 3}
+        "#;
+        let (out, errors, ok) = run(&["--error-policy", "skip"], &[input]);
+        assert_eq!(out, "a\n1\n3\n");
+        assert_eq!(errors, "");
+        assert!(ok);
+
+        let (out, errors, ok) = run(&["--error-policy", "abort"], &[input]);
+        assert_eq!(out, "a\n1\n");
+        assert!(
+            errors.contains("field is not in the inferred header row"),
+            "{}",
+            errors
+        );
+        assert!(!ok);
+    }
+
+    #[test]
+    fn sanitize() {
+        let input = r#"{"a": "first\r\nsecond", "b": "tab\there"}"#;
+        let (out, _, ok) = run(&[], &[input]);
+        assert_eq!(out, "a,b\n\"first\r\nsecond\",tab\there\n");
+        assert!(ok);
+
+        let (out, _, ok) = run(&["--sanitize", "replace"], &[input]);
+        assert_eq!(out, "a,b\nfirst  second,tab here\n");
+        assert!(ok);
+
+        let args = ["--sanitize", "replace", "--sanitize-replacement", ""];
+        let (out, _, ok) = run(&args, &[input]);
+        assert_eq!(out, "a,b\nfirstsecond,tabhere\n");
+        assert!(ok);
+
+        let (out, _, ok) = run(&["--sanitize", "escape"], &[input]);
+        assert_eq!(out, "a,b\nfirst\\r\\nsecond,tab\\there\n");
+        assert!(ok);
+    }
+
+    #[test]
+    fn limits() {
+        let input = r#"{"a": "short", "b": 1}
+            {"a": "a very long field", "b": 2}
+            {"a": "x", "b": 3}
+        "#;
+        let args = ["--max-field-len", "8", "--limit-policy", "truncate"];
+        let (out, errors, ok) = run(&args, &[input]);
+        assert_eq!(out, "a,b\nshort,1\na ver...,2\nx,3\n");
+        assert_eq!(errors, "");
+        assert!(ok);
+
+        let args = [
+            "--max-field-len",
+            "8",
+            "--limit-policy",
+            "truncate",
+            "--ellipsis",
+            "~",
+        ];
+        let (out, _, ok) = run(&args, &[input]);
+        assert_eq!(out, "a,b\nshort,1\na very ~,2\nx,3\n");
+        assert!(ok);
+
+        let (out, errors, ok) = run(&["--max-record-len", "8"], &[input]);
+        assert_eq!(out, "a,b\nshort,1\nx,3\n");
+        assert!(
+            errors.contains("record is longer than the maximum of 8 bytes"),
+            "{}",
+            errors
+        );
+        assert!(!ok);
+
+        let args = [
+            "--max-records",
+            "2",
+            "--limit-policy",
+            "stop",
+            "--comment",
+            "#",
+        ];
+        let (out, errors, ok) = run(&args, &[input]);
+        assert_eq!(
+            out,
+            "a,b\nshort,1\na very long field,2\n#truncated: more than the maximum of 2 records\n"
+        );
+        assert_eq!(errors, "");
+        assert!(ok);
+
+        let args = ["--max-bytes", "16", "--limit-policy", "truncate"];
+        let (out, errors, ok) = run(&args, &[input]);
+        assert_eq!(out, "a,b\nshort,1\n");
+        assert_eq!(errors, "");
+        assert!(ok);
+    }
+
+    #[test]
+    fn invalid_json() {
+        let (out, errors, ok) = run(&[], &["{\"a\": 1}\n{\"a\": 2\n"]);
+        assert_eq!(out, "a\n1\n");
+        assert_eq!(
+            errors,
+            "csv-stream: <test>: EOF while parsing an object at line 3 column 0\n"
+        );
+        assert!(!ok);
+
+        let (out, errors, ok) = run(&[], &["[1, 2]\n[3, 4]\n"]);
+        assert_eq!(out, "1\n2\n");
+        assert!(errors.contains("trailing characters"), "{}", errors);
+        assert!(!ok);
+
+        let (out, errors, ok) = run(&["--lines"], &["[1, 2]\n[3, 4]\n"]);
+        assert_eq!(out, "1,2\n3,4\n");
+        assert_eq!(errors, "");
+        assert!(ok);
+    }
+}
@@ -0,0 +1,36 @@
+use bytes::Bytes;
+use http::HeaderMap;
+use http_body::Frame;
+
+use crate::{Result, Stats};
+
+/// A hook called once all records have been written, to produce the HTTP
+/// trailers of a response body.
+pub(crate) type HttpTrailers = Box<dyn FnOnce(&Stats) -> HeaderMap + Send>;
+
+/// The state an [`Iter`](crate::Iter) or [`Stream`](crate::Stream) keeps to
+/// act as an HTTP body.
+#[derive(Default)]
+pub(crate) struct BodyState {
+    /// The number of bytes every record is declared to take.
+    pub(crate) record_len: Option<u64>,
+    pub(crate) trailers: Option<HttpTrailers>,
+}
+
+impl BodyState {
+    /// Turn the next item into a body frame, or once there are no more items,
+    /// into the trailers frame.
+    pub(crate) fn frame(
+        &mut self,
+        item: Option<Result<Vec<u8>>>,
+        stats: impl FnOnce() -> Stats,
+    ) -> Option<Result<Frame<Bytes>>> {
+        match item {
+            Some(res) => Some(res.map(|buf| Frame::data(Bytes::from(buf)))),
+            None => {
+                let trailers = self.trailers.take()?;
+                Some(Ok(Frame::trailers(trailers(&stats()))))
+            }
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use std::str;
+
+use crate::error::{Error, ErrorKind, Result};
+
+/// A buffer that a [`Writer`](crate::Writer) can write CSV data into.
+///
+/// This is implemented for `Vec<u8>`, for `String` (which fails to write any
+/// data that would not be valid UTF-8), for [`SliceBuf`] (a fixed size
+/// buffer which fails once it is full) and, with the `bytes` feature, for
+/// `bytes::BytesMut`.
+///
+/// Implement this trait to write CSV data directly into some other kind of
+/// buffer, such as an arena or a pre-allocated page, without an intermediate
+/// copy.
+pub trait CsvBuf {
+    /// Returns the number of bytes in the buffer.
+    fn len(&self) -> usize;
+
+    /// Returns true if the buffer is empty.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Append `data` to the end of the buffer.
+    ///
+    /// The writer never splits field data except around quotes, so every
+    /// call gets a whole delimiter, terminator, quote, or run of field data
+    /// between quotes.
+    ///
+    /// If this returns an error, nothing should have been appended.
+    fn put(&mut self, data: &[u8]) -> Result<()>;
+
+    /// Shorten the buffer to `len` bytes.
+    ///
+    /// This is used to remove a partially written record after an error, so
+    /// `len` is always the length of the buffer at the start of a record.
+    fn truncate(&mut self, len: usize);
+}
+
+impl CsvBuf for Vec<u8> {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    fn put(&mut self, data: &[u8]) -> Result<()> {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.truncate(len)
+    }
+}
+
+impl CsvBuf for String {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    fn put(&mut self, data: &[u8]) -> Result<()> {
+        let data = str::from_utf8(data).map_err(|err| Error::new(ErrorKind::Utf8(err)))?;
+        self.push_str(data);
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.truncate(len)
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl CsvBuf for bytes::BytesMut {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    fn put(&mut self, data: &[u8]) -> Result<()> {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.truncate(len)
+    }
+}
+
+/// A fixed size buffer backed by a mutable byte slice.
+///
+/// Writing more data than fits into the slice fails with
+/// [`ErrorKind::BufferFull`], and the record that did not fit is removed
+/// again.
+///
+/// # Example
+///
+/// ```
+/// use std::error::Error;
+/// use csv_stream::{SliceBuf, WriterBuilder};
+///
+/// # fn main() { example().unwrap(); }
+/// fn example() -> Result<(), Box<dyn Error>> {
+///     let mut wtr = WriterBuilder::default().build();
+///     let mut page = [0; 8];
+///     let mut buf = SliceBuf::new(&mut page);
+///     wtr.write_record(&mut buf, &["a", "b"])?;
+///     assert!(wtr.write_record(&mut buf, &["c", "d", "e"]).is_err());
+///
+///     assert_eq!(buf.as_bytes(), b"a,b\n");
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug)]
+pub struct SliceBuf<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> SliceBuf<'a> {
+    /// Create an empty buffer that writes into `buf`.
+    pub fn new(buf: &'a mut [u8]) -> SliceBuf<'a> {
+        SliceBuf { buf, len: 0 }
+    }
+
+    /// Returns the data written so far.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// Returns the number of bytes that can still be written.
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.len
+    }
+
+    /// Unwrap the underlying slice, returning only the part that was
+    /// written to.
+    pub fn into_inner(self) -> &'a mut [u8] {
+        &mut self.buf[..self.len]
+    }
+}
+
+impl CsvBuf for SliceBuf<'_> {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn put(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() > self.remaining() {
+            return Err(Error::new(ErrorKind::BufferFull));
+        }
+        self.buf[self.len..self.len + data.len()].copy_from_slice(data);
+        self.len += data.len();
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SliceBuf;
+    use crate::{ErrorKind, WriterBuilder};
+
+    #[test]
+    fn string() {
+        let mut wtr = WriterBuilder::default().build();
+        let mut buf = String::new();
+        wtr.write_record(&mut buf, ["a\"b", "ä"]).unwrap();
+
+        assert_eq!(buf, "\"a\"\"b\",ä\n");
+    }
+
+    #[test]
+    fn string_invalid_utf8() {
+        let mut wtr = WriterBuilder::default().build();
+        let mut buf = String::new();
+        wtr.write_record(&mut buf, ["a", "b"]).unwrap();
+        let err = wtr
+            .write_record(&mut buf, [&b"c"[..], b"\xFF"])
+            .unwrap_err();
+        match *err.kind() {
+            ErrorKind::Utf8(_) => {}
+            ref x => panic!("expected ErrorKind::Utf8 but got '{:?}'", x),
+        }
+        wtr.write_record(&mut buf, ["d", "e"]).unwrap();
+
+        assert_eq!(buf, "a,b\nd,e\n");
+    }
+
+    #[test]
+    fn string_invalid_delimiter() {
+        let mut wtr = WriterBuilder::default().delimiter(0xFF).build();
+        let mut buf = String::new();
+        wtr.write_record(&mut buf, ["a"]).unwrap();
+        assert!(wtr.write_record(&mut buf, ["a", "b"]).is_err());
+
+        assert_eq!(buf, "a\n");
+    }
+
+    #[test]
+    fn slice_full() {
+        #[derive(serde::Serialize)]
+        struct Row {
+            foo: &'static str,
+        }
+
+        let mut wtr = WriterBuilder::default().build();
+        let mut page = [0; 12];
+        let mut buf = SliceBuf::new(&mut page);
+        wtr.serialize(&mut buf, Row { foo: "a" }).unwrap();
+        let err = wtr.serialize(&mut buf, Row { foo: "bcdefg" }).unwrap_err();
+        match *err.kind() {
+            ErrorKind::BufferFull => {}
+            ref x => panic!("expected ErrorKind::BufferFull but got '{:?}'", x),
+        }
+        assert_eq!(err.position().unwrap().record(), 2);
+        wtr.serialize(&mut buf, Row { foo: "b" }).unwrap();
+
+        assert_eq!(buf.as_bytes(), b"foo\na\nb\n");
+        assert_eq!(buf.remaining(), 4);
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn bytes_mut() {
+        let mut wtr = WriterBuilder::default().build();
+        let mut buf = bytes::BytesMut::new();
+        wtr.write_record(&mut buf, ["a", "b,c"]).unwrap();
+
+        assert_eq!(&buf[..], b"a,\"b,c\"\n");
+    }
+}
@@ -0,0 +1,638 @@
+use std::marker::PhantomData;
+
+use bytes::{Buf, BytesMut};
+use csv_core::{ReadRecordResult, Reader as CoreReader, ReaderBuilder as CoreReaderBuilder};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::error::{Error, ErrorKind, Position, Result};
+use crate::{Stats, Terminator, Trim, Writer};
+
+/// An [`Encoder`] that writes records as CSV
+///
+/// Each record is serialized into the output buffer as a single line, with
+/// the header row (and any preamble) written in front of the first one. This
+/// lets a [`FramedWrite`](tokio_util::codec::FramedWrite) over a file or
+/// socket `send` records directly.
+///
+/// If a record fails to serialize, nothing of it is written and the encoder
+/// can carry on encoding records.
+///
+/// # Example
+///
+/// ```
+/// use std::error::Error;
+/// use csv_stream::WriterBuilder;
+/// use futures::SinkExt;
+/// use serde::Serialize;
+/// use tokio_util::codec::FramedWrite;
+///
+/// # #[tokio::main]
+/// # async fn main() { example().await.unwrap(); }
+/// async fn example() -> Result<(), Box<dyn Error>> {
+///     #[derive(Serialize)]
+///     struct Row { foo: usize, bar: usize }
+///
+///     let encoder = WriterBuilder::default().build_encoder();
+///     let mut framed = FramedWrite::new(vec![], encoder);
+///     framed.send(Row { foo: 1, bar: 2 }).await?;
+///     framed.send(Row { foo: 3, bar: 4 }).await?;
+///
+///     assert_eq!(framed.encoder().stats().records(), 2);
+///     let data = String::from_utf8(framed.into_inner())?;
+///     assert_eq!(data, "foo,bar\n1,2\n3,4\n");
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug)]
+pub struct CsvEncoder<T> {
+    writer: Writer,
+    _record: PhantomData<fn(T)>,
+}
+
+impl<T> CsvEncoder<T> {
+    pub fn new(writer: Writer) -> Self {
+        Self {
+            writer,
+            _record: PhantomData,
+        }
+    }
+
+    /// Returns statistics about the data encoded so far.
+    pub fn stats(&self) -> Stats {
+        self.writer.stats()
+    }
+
+    /// Returns the writer used to encode records, to inspect its
+    /// configuration.
+    pub fn writer(&self) -> &Writer {
+        &self.writer
+    }
+}
+
+impl<T: Serialize> Encoder<T> for CsvEncoder<T> {
+    type Error = Error;
+
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<()> {
+        self.writer.serialize(dst, item)
+    }
+}
+
+/// A [`Decoder`] that reads records from CSV
+///
+/// This lets a [`FramedRead`](tokio_util::codec::FramedRead) over a file or
+/// socket yield typed records. Records may be split across any number of
+/// reads, and quoted fields may contain delimiters and newlines.
+///
+/// If headers are enabled (the default), the first record is captured as the
+/// header row, and is used to deserialize structs and maps by field name.
+///
+/// Each frame is the result of reading a single record, so that a record
+/// that fails to deserialize, or has the wrong number of fields, is yielded
+/// as an error of its own and a `FramedRead` carries on with the next
+/// record. The decoder itself only fails, ending a `FramedRead`, when the
+/// rest of the input can not be decoded: on I/O errors, and with
+/// [`ErrorKind::RecordTooLarge`] once a record grows beyond the
+/// [`max_record_size`](CsvDecoder::max_record_size), since the decoder only
+/// buffers a single record at a time.
+///
+/// # Example
+///
+/// ```
+/// use std::error::Error;
+/// use csv_stream::CsvDecoder;
+/// use futures::StreamExt;
+/// use serde::Deserialize;
+/// use tokio_util::codec::FramedRead;
+///
+/// # #[tokio::main]
+/// # async fn main() { example().await.unwrap(); }
+/// async fn example() -> Result<(), Box<dyn Error>> {
+///     #[derive(Debug, Deserialize, PartialEq)]
+///     struct Row { foo: usize, bar: String }
+///
+///     let data = &b"foo,bar\n1,\"a\nb\"\n2,c\n"[..];
+///     let mut framed = FramedRead::new(data, CsvDecoder::new());
+///
+///     let row: Row = framed.next().await.unwrap()??;
+///     assert_eq!(row, Row { foo: 1, bar: "a\nb".to_owned() });
+///     let row: Row = framed.next().await.unwrap()??;
+///     assert_eq!(row, Row { foo: 2, bar: "c".to_owned() });
+///     assert!(framed.next().await.is_none());
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug)]
+pub struct CsvDecoder<T> {
+    builder: CoreReaderBuilder,
+    core: CoreReader,
+    has_headers: bool,
+    flexible: bool,
+    trim: Trim,
+    max_record_size: usize,
+    /// The header row, once it has been read.
+    headers: Option<csv::ByteRecord>,
+    /// The number of fields in the first record. This is compared with the
+    /// number of fields in all subsequent records, unless `flexible` is
+    /// enabled.
+    first_field_count: Option<u64>,
+    /// The unescaped field data of the record currently being read.
+    fields: Vec<u8>,
+    /// The end offsets of the fields in `fields`.
+    ends: Vec<usize>,
+    /// The number of bytes of `fields` used so far.
+    fields_len: usize,
+    /// The number of entries of `ends` used so far.
+    ends_len: usize,
+    /// The number of input bytes consumed by the record currently being
+    /// read.
+    consumed: usize,
+    /// The byte offset in the input at which the record currently being
+    /// read starts.
+    byte: u64,
+    /// The number of records read so far, including the header row.
+    records: u64,
+    _record: PhantomData<fn() -> T>,
+}
+
+impl<T> Default for CsvDecoder<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> CsvDecoder<T> {
+    /// Create a new decoder for comma separated data with a header row.
+    pub fn new() -> Self {
+        let builder = CoreReaderBuilder::new();
+        Self {
+            core: builder.build(),
+            builder,
+            has_headers: true,
+            flexible: false,
+            trim: Trim::None,
+            max_record_size: 1 << 20,
+            headers: None,
+            first_field_count: None,
+            fields: vec![0; 1024],
+            ends: vec![0; 32],
+            fields_len: 0,
+            ends_len: 0,
+            consumed: 0,
+            byte: 0,
+            records: 0,
+            _record: PhantomData,
+        }
+    }
+
+    /// The field delimiter to use when reading CSV.
+    ///
+    /// The default is `b','`.
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.builder.delimiter(delimiter);
+        self.core = self.builder.build();
+        self
+    }
+
+    /// The quote character to use when reading CSV.
+    ///
+    /// The default is `b'"'`.
+    pub fn quote(mut self, quote: u8) -> Self {
+        self.builder.quote(quote);
+        self.core = self.builder.build();
+        self
+    }
+
+    /// The record terminator to use when reading CSV.
+    ///
+    /// The default is [`Terminator::CRLF`], which treats `\r`, `\n` or
+    /// `\r\n` as a single record terminator.
+    pub fn terminator(mut self, term: Terminator) -> Self {
+        self.builder.terminator(term.to_core());
+        self.core = self.builder.build();
+        self
+    }
+
+    /// The comment character to use when reading CSV.
+    ///
+    /// Lines starting with this character are ignored. This is disabled by
+    /// default.
+    pub fn comment(mut self, comment: Option<u8>) -> Self {
+        self.builder.comment(comment);
+        self.core = self.builder.build();
+        self
+    }
+
+    /// Whether the first record is a header row.
+    ///
+    /// This is enabled by default.
+    pub fn has_headers(mut self, yes: bool) -> Self {
+        self.has_headers = yes;
+        self
+    }
+
+    /// Whether records of different lengths are allowed.
+    ///
+    /// When disabled (the default), a record with a different number of
+    /// fields than the first record fails with
+    /// [`ErrorKind::UnequalLengths`].
+    pub fn flexible(mut self, yes: bool) -> Self {
+        self.flexible = yes;
+        self
+    }
+
+    /// Whether whitespace is trimmed from headers and fields.
+    ///
+    /// Nothing is trimmed by default.
+    pub fn trim(mut self, trim: Trim) -> Self {
+        self.trim = trim;
+        self
+    }
+
+    /// The maximum number of input bytes a single record may span.
+    ///
+    /// This bounds how much data the decoder buffers while waiting for the
+    /// end of a record. The default is 1MiB.
+    pub fn max_record_size(mut self, max_record_size: usize) -> Self {
+        self.max_record_size = max_record_size;
+        self
+    }
+
+    /// Returns the header row, once it has been read.
+    ///
+    /// This is `None` if headers are disabled.
+    pub fn headers(&self) -> Option<impl Iterator<Item = &[u8]>> {
+        self.headers.as_ref().map(|headers| headers.iter())
+    }
+
+    /// Returns the position of the record currently being read.
+    fn position(&self) -> Position {
+        Position::new(self.byte, self.records)
+    }
+
+    /// Read the fields of the next record from `src`.
+    ///
+    /// All of `src` is consumed, and the fields of a partial record are kept
+    /// until the rest of it is available.
+    fn read_record(&mut self, src: &mut BytesMut, eof: bool) -> Result<Option<csv::ByteRecord>> {
+        loop {
+            let (res, nin, nout, nend) = self.core.read_record(
+                src,
+                &mut self.fields[self.fields_len..],
+                &mut self.ends[self.ends_len..],
+            );
+            src.advance(nin);
+            self.consumed += nin;
+            self.fields_len += nout;
+            self.ends_len += nend;
+            if self.consumed > self.max_record_size {
+                let kind = ErrorKind::RecordTooLarge {
+                    max_len: self.max_record_size as u64,
+                };
+                return Err(Error::new(kind).or_position(self.position()));
+            }
+
+            match res {
+                // An empty input signals the end of the data, so only pass
+                // one once the input is really done.
+                ReadRecordResult::InputEmpty if !eof => return Ok(None),
+                ReadRecordResult::InputEmpty => {}
+                ReadRecordResult::OutputFull => {
+                    let len = self.fields.len() * 2;
+                    self.fields.resize(len, 0);
+                }
+                ReadRecordResult::OutputEndsFull => {
+                    let len = self.ends.len() * 2;
+                    self.ends.resize(len, 0);
+                }
+                ReadRecordResult::Record => {
+                    let mut record = csv::ByteRecord::with_capacity(self.fields_len, self.ends_len);
+                    let mut start = 0;
+                    for &end in &self.ends[..self.ends_len] {
+                        record.push_field(&self.fields[start..end]);
+                        start = end;
+                    }
+                    self.fields_len = 0;
+                    self.ends_len = 0;
+                    return Ok(Some(record));
+                }
+                ReadRecordResult::End => return Ok(None),
+            }
+        }
+    }
+
+    /// Finish the record that was just read, and move on to the next one.
+    fn end_record(&mut self) {
+        self.byte += self.consumed as u64;
+        self.consumed = 0;
+        self.records += 1;
+    }
+
+    /// Check that a record has the same number of fields as the first one.
+    fn check_field_count(&mut self, record: &csv::ByteRecord) -> Result<()> {
+        let len = record.len() as u64;
+        match self.first_field_count {
+            None => self.first_field_count = Some(len),
+            Some(expected_len) if !self.flexible && len != expected_len => {
+                let kind = ErrorKind::UnequalLengths { expected_len, len };
+                return Err(Error::new(kind));
+            }
+            Some(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Decode the next record, if all of it has been read.
+    ///
+    /// Only errors that leave the rest of the input unreadable are returned
+    /// as the outer error.
+    fn decode_record(&mut self, src: &mut BytesMut, eof: bool) -> Result<Option<Result<T>>>
+    where
+        T: DeserializeOwned,
+    {
+        loop {
+            let mut record = match self.read_record(src, eof)? {
+                Some(record) => record,
+                None => return Ok(None),
+            };
+            let pos = self.position();
+            self.end_record();
+
+            if self.has_headers && self.headers.is_none() {
+                if matches!(self.trim, Trim::Headers | Trim::All) {
+                    record.trim();
+                }
+                self.check_field_count(&record)
+                    .map_err(|err| err.or_position(pos))?;
+                self.headers = Some(record);
+                continue;
+            }
+
+            if matches!(self.trim, Trim::Fields | Trim::All) {
+                record.trim();
+            }
+            if let Err(err) = self.check_field_count(&record) {
+                return Ok(Some(Err(err.or_position(pos))));
+            }
+            return Ok(Some(
+                record
+                    .deserialize(self.headers.as_ref())
+                    .map_err(|err| self.deserialize_error(err).or_position(pos)),
+            ));
+        }
+    }
+
+    /// Convert an error from deserializing a record, attaching the field it
+    /// occurred in.
+    fn deserialize_error(&self, err: csv::Error) -> Error {
+        let err = match *err.kind() {
+            csv::ErrorKind::Deserialize { ref err, .. } => err,
+            _ => return Error::new(ErrorKind::Deserialize(err.to_string())),
+        };
+        let kind = ErrorKind::Deserialize(err.kind().to_string());
+        match err.field() {
+            Some(index) => {
+                let header = self
+                    .headers
+                    .as_ref()
+                    .and_then(|headers| headers.get(index as usize))
+                    .and_then(|header| std::str::from_utf8(header).ok());
+                Error::new(kind).or_field(index, header)
+            }
+            None => Error::new(kind),
+        }
+    }
+}
+
+impl<T: DeserializeOwned> Decoder for CsvDecoder<T> {
+    type Item = Result<T>;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Result<T>>> {
+        self.decode_record(src, false)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Result<T>>> {
+        self.decode_record(src, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use futures::{SinkExt, StreamExt};
+    use serde::{Deserialize, Serialize};
+    use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
+
+    use super::CsvDecoder;
+    use crate::{ErrorKind, QuoteStyle, Trim, WriterBuilder};
+
+    #[derive(Serialize)]
+    struct Row {
+        foo: u32,
+        bar: &'static str,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct OwnedRow {
+        foo: u32,
+        bar: String,
+    }
+
+    fn row(foo: u32, bar: &str) -> OwnedRow {
+        OwnedRow {
+            foo,
+            bar: bar.to_owned(),
+        }
+    }
+
+    /// Decode all of `data`, feeding it to the decoder one byte at a time.
+    fn decode_bytewise<T: serde::de::DeserializeOwned>(
+        mut decoder: CsvDecoder<T>,
+        data: &[u8],
+    ) -> Vec<crate::Result<T>> {
+        let mut src = BytesMut::new();
+        let mut records = vec![];
+        for &b in data {
+            src.extend_from_slice(&[b]);
+            while let Some(record) = decoder.decode(&mut src).unwrap() {
+                records.push(record);
+            }
+        }
+        while let Some(record) = decoder.decode_eof(&mut src).unwrap() {
+            records.push(record);
+        }
+        records
+    }
+
+    #[test]
+    fn encode() {
+        let mut encoder = WriterBuilder::default().build_encoder();
+        let mut dst = BytesMut::new();
+        encoder.encode(Row { foo: 1, bar: "a" }, &mut dst).unwrap();
+        assert_eq!(&dst[..], b"foo,bar\n1,a\n");
+
+        dst.clear();
+        encoder
+            .encode(Row { foo: 2, bar: "b,c" }, &mut dst)
+            .unwrap();
+        assert_eq!(&dst[..], b"2,\"b,c\"\n");
+
+        let stats = encoder.stats();
+        assert_eq!(stats.records(), 2);
+        assert_eq!(stats.quoted_fields(), 1);
+    }
+
+    #[test]
+    fn serialize_error() {
+        let mut encoder = WriterBuilder::default().build_encoder();
+        let mut dst = BytesMut::new();
+        encoder.encode(vec![1, 2], &mut dst).unwrap();
+        assert!(encoder.encode(vec![1, 2, 3], &mut dst).is_err());
+        encoder.encode(vec![3, 4], &mut dst).unwrap();
+
+        assert_eq!(&dst[..], b"1,2\n3,4\n");
+    }
+
+    #[test]
+    fn config() {
+        let encoder = WriterBuilder::default()
+            .delimiter(b';')
+            .quote_style(QuoteStyle::Always)
+            .has_headers(false)
+            .build_encoder::<Row>();
+
+        let writer = encoder.writer();
+        assert_eq!(writer.delimiter(), b';');
+        assert!(matches!(writer.quote_style(), QuoteStyle::Always));
+        assert!(!writer.has_headers());
+        assert!(!writer.is_flexible());
+    }
+
+    #[tokio::test]
+    async fn framed_write() {
+        let encoder = WriterBuilder::default().build_encoder();
+        let mut framed = FramedWrite::new(vec![], encoder);
+        framed.send(Row { foo: 1, bar: "a" }).await.unwrap();
+        framed.send(Row { foo: 2, bar: "b" }).await.unwrap();
+
+        assert_eq!(framed.into_inner(), b"foo,bar\n1,a\n2,b\n");
+    }
+
+    #[test]
+    fn decode_split_records() {
+        let data = b"foo,bar\r\n1,\"a,\"\"b\"\"\nc\"\r\n2,d";
+        let records = decode_bytewise(CsvDecoder::new(), data);
+        let records: Vec<OwnedRow> = records.into_iter().map(Result::unwrap).collect();
+
+        assert_eq!(records, [row(1, "a,\"b\"\nc"), row(2, "d")]);
+    }
+
+    #[test]
+    fn headers() {
+        let mut decoder = CsvDecoder::<OwnedRow>::new().trim(Trim::All);
+        let mut src = BytesMut::from(&b"bar , foo\n"[..]);
+        assert!(decoder.decode(&mut src).unwrap().is_none());
+
+        let headers: Vec<&[u8]> = decoder.headers().unwrap().collect();
+        assert_eq!(headers, [&b"bar"[..], b"foo"]);
+
+        src.extend_from_slice(b" x , 1\n");
+        let record = decoder.decode(&mut src).unwrap().unwrap();
+        assert_eq!(record.unwrap(), row(1, "x"));
+    }
+
+    #[test]
+    fn no_headers() {
+        let decoder = CsvDecoder::new().has_headers(false).delimiter(b';');
+        let records = decode_bytewise(decoder, b"1;a\n2;b\n");
+        let records: Vec<(u32, String)> = records.into_iter().map(Result::unwrap).collect();
+
+        assert_eq!(records, [(1, "a".to_owned()), (2, "b".to_owned())]);
+    }
+
+    #[test]
+    fn deserialize_error() {
+        let decoder = CsvDecoder::<OwnedRow>::new();
+        let records = decode_bytewise(decoder, b"foo,bar\n1,a\nx,b\n3,c\n");
+        assert_eq!(records.len(), 3);
+
+        let err = records[1].as_ref().unwrap_err();
+        match *err.kind() {
+            ErrorKind::Deserialize(_) => {}
+            ref x => panic!("expected ErrorKind::Deserialize but got '{:?}'", x),
+        }
+        assert_eq!(err.position().unwrap().record(), 2);
+        assert_eq!(err.position().unwrap().byte(), 12);
+        assert_eq!(err.field(), Some(0));
+        assert_eq!(err.header(), Some("foo"));
+        assert_eq!(records[2].as_ref().unwrap(), &row(3, "c"));
+    }
+
+    #[tokio::test]
+    async fn framed_read_deserialize_error() {
+        let data = &b"foo,bar\n1,a\nx,b\n3,c\n"[..];
+        let framed = FramedRead::new(data, CsvDecoder::<OwnedRow>::new());
+        let records: Vec<_> = framed.map(Result::unwrap).collect().await;
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].as_ref().unwrap(), &row(1, "a"));
+        let err = records[1].as_ref().unwrap_err();
+        assert_eq!(err.position().unwrap().record(), 2);
+        assert_eq!(records[2].as_ref().unwrap(), &row(3, "c"));
+
+        // A record that is too large ends the input.
+        let data = &b"foo,bar\n1,a\n2,bcdefghijklmnop\n3,c\n"[..];
+        let decoder = CsvDecoder::<OwnedRow>::new().max_record_size(8);
+        let mut framed = FramedRead::new(data, decoder);
+        assert_eq!(framed.next().await.unwrap().unwrap().unwrap(), row(1, "a"));
+        assert!(framed.next().await.unwrap().is_err());
+        assert!(framed.next().await.is_none());
+    }
+
+    #[test]
+    fn unequal_lengths() {
+        let records = decode_bytewise(CsvDecoder::<Vec<u32>>::new(), b"a,b\n1,2\n3\n");
+        match *records[1].as_ref().unwrap_err().kind() {
+            ErrorKind::UnequalLengths { expected_len, len } => {
+                assert_eq!(expected_len, 2);
+                assert_eq!(len, 1);
+            }
+            ref x => panic!("expected ErrorKind::UnequalLengths but got '{:?}'", x),
+        }
+
+        let decoder = CsvDecoder::<Vec<u32>>::new().flexible(true);
+        let records = decode_bytewise(decoder, b"a,b\n1,2\n3\n");
+        assert_eq!(records[1].as_ref().unwrap(), &[3]);
+    }
+
+    #[test]
+    fn max_record_size() {
+        let mut decoder = CsvDecoder::<OwnedRow>::new().max_record_size(16);
+        let mut src = BytesMut::from(&b"foo,bar\n1,\"a\n"[..]);
+        assert!(decoder.decode(&mut src).unwrap().is_none());
+
+        src.extend_from_slice(b"bcdefghijklmnop");
+        let err = decoder.decode(&mut src).unwrap_err();
+        match *err.kind() {
+            ErrorKind::RecordTooLarge { max_len } => assert_eq!(max_len, 16),
+            ref x => panic!("expected ErrorKind::RecordTooLarge but got '{:?}'", x),
+        }
+        assert_eq!(err.position().unwrap().byte(), 8);
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let rows = vec![row(1, "a\nb"), row(2, "\"c\""), row(3, "")];
+
+        let mut framed = FramedWrite::new(vec![], WriterBuilder::default().build_encoder());
+        for row in &rows {
+            framed.send(row).await.unwrap();
+        }
+        let data = framed.into_inner();
+
+        let framed = FramedRead::new(&data[..], CsvDecoder::new());
+        let decoded: Vec<OwnedRow> = framed.map(|res| res.unwrap().unwrap()).collect().await;
+        assert_eq!(decoded, rows);
+    }
+}
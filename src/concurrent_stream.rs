@@ -0,0 +1,323 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::stream::FuturesOrdered;
+use futures::StreamExt;
+use pin_project::pin_project;
+use serde::Serialize;
+use tokio::task::JoinHandle;
+
+use crate::writer::{Detached, Recovery, Trailer};
+use crate::{Result, Stats, Writer};
+
+/// A Streamable CSV creator that serializes records on tokio's blocking
+/// thread pool
+///
+/// Records are read from the input stream in batches, and each batch is
+/// serialized with [`tokio::task::spawn_blocking`], so that reading the input
+/// and serializing records can happen at the same time. Up to a fixed number
+/// of batches are in flight at once. The output is yielded in the same order
+/// as the input, one record at a time, and is identical to the output of
+/// [`Stream`](crate::Stream).
+///
+/// The first records are serialized one at a time, until the header row has
+/// been written and the number of fields per record is known.
+///
+/// This must be polled from within a tokio runtime.
+///
+/// # Example
+///
+/// ```
+/// use std::error::Error;
+/// use csv_stream::WriterBuilder;
+/// use serde::Serialize;
+/// use futures::StreamExt;
+///
+/// # #[tokio::main]
+/// # async fn main() { example().await.unwrap(); }
+/// async fn example() -> Result<(), Box<dyn Error>> {
+///     #[derive(Serialize)]
+///     struct Row { foo: usize, bar: usize }
+///     let rows = (0..1000).map(|i| Row { foo: i, bar: i * 2 });
+///     let stream = futures::stream::iter(rows);
+///
+///     let mut csv_stream = WriterBuilder::default()
+///         .build_concurrent_stream(stream, 4)
+///         .batch_size(100);
+///
+///     let mut buf = vec![];
+///     while let Some(row) = csv_stream.next().await {
+///         buf.extend_from_slice(&row?);
+///     }
+///
+///     let data = String::from_utf8(buf)?;
+///     assert!(data.starts_with("foo,bar\n0,0\n1,2\n"));
+///     assert!(data.ends_with("999,1998\n"));
+///     Ok(())
+/// }
+/// ```
+#[pin_project]
+pub struct ConcurrentStream<S: futures::Stream> {
+    #[pin]
+    stream: S,
+
+    writer: Writer,
+    /// The number of records to serialize in a single task.
+    batch_size: usize,
+    /// The maximum number of tasks in flight at once.
+    max_in_flight: usize,
+    /// Records read from the input, that have not been sent to a task yet.
+    pending: Vec<S::Item>,
+    in_flight: FuturesOrdered<JoinHandle<Vec<Detached>>>,
+    /// Records serialized by the last task to finish, waiting to be merged.
+    batch: std::vec::IntoIter<Detached>,
+    /// Set once the input stream is exhausted.
+    input_done: bool,
+    /// Set once all input has been written, or the error policy has decided
+    /// that no more records should be written.
+    done: bool,
+    trailer: Option<Trailer>,
+}
+
+impl<S: futures::Stream> ConcurrentStream<S> {
+    pub fn new(stream: S, writer: Writer, max_in_flight: usize) -> Self {
+        Self {
+            stream,
+            writer,
+            batch_size: 1024,
+            max_in_flight: max_in_flight.max(1),
+            pending: Vec::new(),
+            in_flight: FuturesOrdered::new(),
+            batch: Vec::new().into_iter(),
+            input_done: false,
+            done: false,
+            trailer: None,
+        }
+    }
+
+    /// Set the number of records to read from the input and serialize in a
+    /// single task.
+    ///
+    /// If the input stream is not ready, and no other task is in flight, a
+    /// smaller batch is sent rather than waiting for more input. The default
+    /// is `1024`.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Set a hook to be called once the input stream is exhausted, to write
+    /// any final records such as a trailer or summary record.
+    ///
+    /// See [`Stream::trailer`](crate::Stream::trailer) for more details.
+    pub fn trailer<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&Stats, &mut Writer, &mut Vec<u8>) -> Result<()> + Send + 'static,
+    {
+        self.trailer = Some(Box::new(f));
+        self
+    }
+
+    /// Returns the number of records that have been dropped so far according
+    /// to the configured [`ErrorPolicy`](crate::ErrorPolicy).
+    pub fn skipped(&self) -> u64 {
+        self.writer.skipped()
+    }
+
+    /// Returns statistics about the data yielded so far.
+    ///
+    /// This does not include records that are in flight, but not yielded
+    /// yet.
+    pub fn stats(&self) -> Stats {
+        self.writer.stats()
+    }
+}
+
+/// Serialize a batch of records on the blocking thread pool.
+fn spawn<T>(writer: &Writer, records: Vec<T>) -> JoinHandle<Vec<Detached>>
+where
+    T: Serialize + Send + 'static,
+{
+    let writer = writer.clone();
+    tokio::task::spawn_blocking(move || {
+        records
+            .into_iter()
+            .map(|s| writer.serialize_detached(s))
+            .collect()
+    })
+}
+
+impl<S: futures::Stream> futures::Stream for ConcurrentStream<S>
+where
+    S::Item: Serialize + Send + 'static,
+{
+    type Item = Result<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut p = self.project();
+        loop {
+            let mut buf = vec![];
+            let res = if let Some(record) = p.batch.next() {
+                p.writer.merge(&mut buf, record)
+            } else if *p.done {
+                return Poll::Ready(None);
+            } else if let Err(err) = p.writer.check_record_limit() {
+                Err(err)
+            } else if !p.writer.is_settled() {
+                let s = match p.stream.as_mut().poll_next(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(None) => {
+                        *p.done = true;
+                        return Poll::Ready(p.writer.finish(vec![], p.trailer.take()));
+                    }
+                    Poll::Ready(Some(s)) => s,
+                };
+                p.writer.serialize(&mut buf, s)
+            } else {
+                while !*p.input_done && p.in_flight.len() < *p.max_in_flight {
+                    match p.stream.as_mut().poll_next(cx) {
+                        Poll::Ready(Some(s)) => {
+                            p.pending.push(s);
+                            if p.pending.len() >= *p.batch_size {
+                                let records = std::mem::take(p.pending);
+                                p.in_flight.push_back(spawn(p.writer, records));
+                            }
+                        }
+                        Poll::Ready(None) => {
+                            *p.input_done = true;
+                            if !p.pending.is_empty() {
+                                let records = std::mem::take(p.pending);
+                                p.in_flight.push_back(spawn(p.writer, records));
+                            }
+                        }
+                        Poll::Pending => {
+                            // Don't hold on to records while there is nothing
+                            // else to do.
+                            if !p.pending.is_empty() && p.in_flight.is_empty() {
+                                let records = std::mem::take(p.pending);
+                                p.in_flight.push_back(spawn(p.writer, records));
+                            }
+                            break;
+                        }
+                    }
+                }
+
+                match p.in_flight.poll_next_unpin(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Some(Ok(batch))) => {
+                        *p.batch = batch.into_iter();
+                        continue;
+                    }
+                    Poll::Ready(Some(Err(err))) => match err.try_into_panic() {
+                        Ok(panic) => std::panic::resume_unwind(panic),
+                        Err(err) => panic!("CSV serialization task failed: {}", err),
+                    },
+                    // Nothing is in flight, and the input is not ready.
+                    Poll::Ready(None) if !*p.input_done => return Poll::Pending,
+                    Poll::Ready(None) => {
+                        *p.done = true;
+                        return Poll::Ready(p.writer.finish(vec![], p.trailer.take()));
+                    }
+                }
+            };
+
+            let err = match res {
+                Ok(()) => return Poll::Ready(Some(Ok(buf))),
+                Err(err) => err,
+            };
+            match p.writer.recover(&mut buf, err) {
+                Recovery::Yield(err) => return Poll::Ready(Some(Err(err))),
+                Recovery::Abort(err) => {
+                    *p.done = true;
+                    *p.batch = Vec::new().into_iter();
+                    *p.in_flight = FuturesOrdered::new();
+                    return Poll::Ready(Some(Err(err)));
+                }
+                Recovery::Skip => continue,
+                Recovery::Placeholder => return Poll::Ready(Some(Ok(buf))),
+                Recovery::Stop => {
+                    *p.done = true;
+                    *p.batch = Vec::new().into_iter();
+                    *p.in_flight = FuturesOrdered::new();
+                    return Poll::Ready(p.writer.finish(buf, p.trailer.take()));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use crate::test_util::{collect_stream, rows, Fallible};
+    use crate::{ErrorKind, ErrorPolicy, WriterBuilder};
+
+    #[tokio::test]
+    async fn same_as_stream() {
+        let builder = WriterBuilder::default();
+        let concurrent = builder
+            .build_concurrent_stream(futures::stream::iter(rows(1000)), 4)
+            .batch_size(64);
+        let stream = builder.build_stream(futures::stream::iter(rows(1000)));
+
+        assert_eq!(
+            collect_stream(concurrent).await,
+            collect_stream(stream).await
+        );
+    }
+
+    #[tokio::test]
+    async fn unequal_lengths() {
+        let rows = vec![vec![1, 2], vec![3, 4], vec![5, 6, 7], vec![8, 9]];
+        let mut stream = WriterBuilder::default()
+            .build_concurrent_stream(futures::stream::iter(rows), 2)
+            .batch_size(2);
+
+        assert_eq!(stream.next().await.unwrap().unwrap(), b"1,2\n");
+        assert_eq!(stream.next().await.unwrap().unwrap(), b"3,4\n");
+        let err = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(err.position().unwrap().record(), 2);
+        assert_eq!(err.position().unwrap().byte(), 8);
+        match *err.kind() {
+            ErrorKind::UnequalLengths { expected_len, len } => {
+                assert_eq!(expected_len, 2);
+                assert_eq!(len, 3);
+            }
+            ref x => panic!("expected ErrorKind::UnequalLengths but got '{:?}'", x),
+        }
+        assert_eq!(stream.next().await.unwrap().unwrap(), b"8,9\n");
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn error_policy_placeholder() {
+        let rows = [
+            Fallible(Some(1)),
+            Fallible(Some(2)),
+            Fallible(None),
+            Fallible(Some(3)),
+        ];
+        let stream = WriterBuilder::default()
+            .error_policy(ErrorPolicy::Placeholder)
+            .build_concurrent_stream(futures::stream::iter(rows), 2)
+            .batch_size(2);
+
+        assert_eq!(
+            collect_stream(stream).await,
+            "1,2\n2,4\n\"CSV write error: record 2 (byte: 8), field 0: missing value\",\n3,6\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn trailer() {
+        let csv_stream =
+            WriterBuilder::default().build_concurrent_stream(futures::stream::iter(rows(10)), 2);
+        let csv_stream = csv_stream.batch_size(3).trailer(|stats, wtr, buf| {
+            wtr.write_flexible_record(buf, ["TRAILER", &stats.records().to_string()])
+        });
+
+        let data = collect_stream(csv_stream).await;
+        assert!(data.ends_with("9,\"x,9\"\nTRAILER,10\n"));
+    }
+}
@@ -1,6 +1,8 @@
 use std::error::Error as StdError;
 use std::fmt;
+use std::io;
 use std::result;
+use std::str::Utf8Error;
 
 /// A type alias for `Result<T, csv_stream::Error>`.
 pub type Result<T> = result::Result<T, Error>;
@@ -14,22 +16,107 @@ pub type Result<T> = result::Result<T, Error>;
 /// `flexible` option enabled and one is reading records as raw byte strings,
 /// then no error can occur.
 #[derive(Debug)]
-pub struct Error(Box<ErrorKind>);
+pub struct Error(Box<ErrorImpl>);
+
+#[derive(Debug)]
+struct ErrorImpl {
+    kind: ErrorKind,
+    pos: Option<Position>,
+    field: Option<Field>,
+}
+
+/// The field that a serializer error occurred in.
+#[derive(Debug)]
+struct Field {
+    index: u64,
+    header: Option<String>,
+}
 
 impl Error {
     /// A crate private constructor for `Error`.
     pub(crate) fn new(kind: ErrorKind) -> Error {
-        Error(Box::new(kind))
+        Error(Box::new(ErrorImpl {
+            kind,
+            pos: None,
+            field: None,
+        }))
+    }
+
+    /// Attach the position of the record being written, unless this error
+    /// already has one.
+    pub(crate) fn or_position(mut self, pos: Position) -> Error {
+        if self.0.pos.is_none() {
+            self.0.pos = Some(pos);
+        }
+        self
+    }
+
+    /// Attach the position of the record being written, replacing any
+    /// position this error already has.
+    #[cfg(any(feature = "rayon", feature = "tokio"))]
+    pub(crate) fn at_position(mut self, pos: Position) -> Error {
+        self.0.pos = Some(pos);
+        self
+    }
+
+    /// Attach the field being written, unless this error already has one.
+    ///
+    /// This only applies to serializer and deserializer errors.
+    pub(crate) fn or_field(mut self, index: u64, header: Option<&str>) -> Error {
+        let has_field = matches!(
+            self.0.kind,
+            ErrorKind::Serialize(_) | ErrorKind::Deserialize(_)
+        );
+        if has_field && self.0.field.is_none() {
+            self.0.field = Some(Field {
+                index,
+                header: header.map(str::to_owned),
+            });
+        }
+        self
     }
 
     /// Return the specific type of this error.
     pub fn kind(&self) -> &ErrorKind {
-        &self.0
+        &self.0.kind
     }
 
     /// Unwrap this error into its underlying type.
     pub fn into_kind(self) -> ErrorKind {
-        *self.0
+        self.0.kind
+    }
+
+    /// Return the position of the record that caused this error, if one is
+    /// known.
+    pub fn position(&self) -> Option<&Position> {
+        self.0.pos.as_ref()
+    }
+
+    /// Return the index of the field, within its record, that caused this
+    /// error.
+    ///
+    /// This is only available for serializer and deserializer errors.
+    pub fn field(&self) -> Option<u64> {
+        self.0.field.as_ref().map(|field| field.index)
+    }
+
+    /// Return the header name of the field that caused this error.
+    ///
+    /// This is only available for serializer and deserializer errors that
+    /// occur inside a struct field.
+    pub fn header(&self) -> Option<&str> {
+        self.0.field.as_ref()?.header.as_deref()
+    }
+
+    /// Returns true if this is an error that occurred while serializing a
+    /// value.
+    pub fn is_serialize(&self) -> bool {
+        matches!(self.0.kind, ErrorKind::Serialize(_))
+    }
+
+    /// Returns true if this is an I/O error.
+    pub fn is_io_error(&self) -> bool {
+        matches!(self.0.kind, ErrorKind::Io(_))
     }
 }
 
@@ -37,6 +124,9 @@ impl Error {
 #[derive(Debug)]
 #[non_exhaustive]
 pub enum ErrorKind {
+    /// An I/O error that occurred while reading or writing CSV data, such as
+    /// with an [`IoWriter`](crate::IoWriter).
+    Io(io::Error),
     /// This error occurs when two records with an unequal number of fields
     /// are found. This error only occurs when the `flexible` option in a
     /// CSV reader/writer is disabled.
@@ -50,35 +140,218 @@ pub enum ErrorKind {
     },
     /// An error of this kind occurs only when using the Serde serializer.
     Serialize(String),
+    /// This error occurs when writing a comment (or a preamble) without
+    /// having set a comment character.
+    CommentsDisabled,
+    /// This error occurs when writing to a fixed size buffer, such as a
+    /// [`SliceBuf`](crate::SliceBuf), that does not have enough room left.
+    BufferFull,
+    /// This error occurs when writing data that is not valid UTF-8 to a
+    /// `String`, or bytes that are not valid UTF-8 with
+    /// [`BytesEncoding::Utf8`](crate::BytesEncoding::Utf8).
+    Utf8(Utf8Error),
+    /// An error of this kind occurs only when decoding records with a
+    /// `CsvDecoder`.
+    Deserialize(String),
+    /// This error occurs when a record read by a `CsvDecoder` spans more
+    /// than its maximum record size.
+    RecordTooLarge {
+        /// The maximum number of bytes a record may span.
+        max_len: u64,
+    },
+    /// An error returned by the database while fetching the rows written by
+    /// a [`RowStream`](crate::RowStream).
+    #[cfg(any(feature = "sqlx-sqlite", feature = "sqlx-postgres"))]
+    Database(sqlx::Error),
+    /// This error occurs when a record goes over one of the limits set on
+    /// the [`WriterBuilder`](crate::WriterBuilder), such as
+    /// [`max_field_len`](crate::WriterBuilder::max_field_len), with
+    /// [`LimitPolicy::Error`](crate::LimitPolicy::Error), or once a limit
+    /// has stopped the output.
+    LimitExceeded(Limit),
+    /// This error occurs when the records of an HTTP body frame do not take
+    /// the number of bytes declared with `Iter::record_len`, which would
+    /// make the size reported for the body wrong.
+    RecordLenMismatch {
+        /// The number of bytes the records were declared to take.
+        expected_len: u64,
+        /// The number of bytes the records took.
+        len: u64,
+    },
+}
+
+/// A limit on the size of the output of a writer, and its maximum.
+///
+/// Lengths are in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Limit {
+    /// The maximum length of a field, set with
+    /// [`max_field_len`](crate::WriterBuilder::max_field_len).
+    FieldLen(u64),
+    /// The maximum length of a record, set with
+    /// [`max_record_len`](crate::WriterBuilder::max_record_len).
+    RecordLen(u64),
+    /// The maximum number of records, set with
+    /// [`max_records`](crate::WriterBuilder::max_records).
+    Records(u64),
+    /// The maximum length of the output, set with
+    /// [`max_bytes`](crate::WriterBuilder::max_bytes).
+    Bytes(u64),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Limit::FieldLen(max) => write!(f, "field is longer than the maximum of {} bytes", max),
+            Limit::RecordLen(max) => {
+                write!(f, "record is longer than the maximum of {} bytes", max)
+            }
+            Limit::Records(max) => write!(f, "more than the maximum of {} records", max),
+            Limit::Bytes(max) => write!(f, "output is longer than the maximum of {} bytes", max),
+        }
+    }
+}
+
+/// A position in CSV output, or in CSV input read by a `CsvDecoder`.
+///
+/// This records the record index (where the header row, if any, is record
+/// `0`) and the byte offset into the data at which that record starts.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Position {
+    byte: u64,
+    record: u64,
+}
+
+impl Position {
+    /// A crate private constructor for `Position`.
+    pub(crate) fn new(byte: u64, record: u64) -> Position {
+        Position { byte, record }
+    }
+
+    /// The byte offset, starting at `0`, of this position.
+    pub fn byte(&self) -> u64 {
+        self.byte
+    }
+
+    /// The record index, starting at `0`, of this position.
+    pub fn record(&self) -> u64 {
+        self.record
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::new(ErrorKind::Io(err))
+    }
 }
 
 impl StdError for Error {
     fn source(&self) -> Option<&(dyn StdError + 'static)> {
-        match *self.0 {
+        match self.0.kind {
+            ErrorKind::Io(ref err) => Some(err),
             ErrorKind::UnequalLengths { .. } => None,
             ErrorKind::Serialize(_) => None,
+            ErrorKind::CommentsDisabled => None,
+            ErrorKind::BufferFull => None,
+            ErrorKind::Utf8(ref err) => Some(err),
+            ErrorKind::Deserialize(_) => None,
+            ErrorKind::RecordTooLarge { .. } => None,
+            ErrorKind::LimitExceeded(_) => None,
+            ErrorKind::RecordLenMismatch { .. } => None,
+            #[cfg(any(feature = "sqlx-sqlite", feature = "sqlx-postgres"))]
+            ErrorKind::Database(ref err) => Some(err),
         }
     }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self.0 {
-            ErrorKind::UnequalLengths {
-                expected_len,
-                len,
-            } => {
+        match self.0.kind {
+            ErrorKind::Io(ref err) => err.fmt(f),
+            ErrorKind::UnequalLengths { expected_len, len } => {
+                write!(f, "CSV error: ")?;
+                self.fmt_location(f)?;
                 write!(
                     f,
-                    "CSV error: \
-                     found record with {} fields, but the previous record \
+                    "found record with {} fields, but the previous record \
                      has {} fields",
                     len, expected_len
                 )
             }
             ErrorKind::Serialize(ref err) => {
-                write!(f, "CSV write error: {}", err)
+                write!(f, "CSV write error: ")?;
+                self.fmt_location(f)?;
+                write!(f, "{}", err)
+            }
+            ErrorKind::CommentsDisabled => {
+                write!(f, "CSV write error: ")?;
+                self.fmt_location(f)?;
+                write!(f, "cannot write a comment without a comment character")
+            }
+            ErrorKind::BufferFull => {
+                write!(f, "CSV write error: ")?;
+                self.fmt_location(f)?;
+                write!(f, "output buffer is full")
+            }
+            ErrorKind::Utf8(ref err) => {
+                write!(f, "CSV write error: ")?;
+                self.fmt_location(f)?;
+                write!(f, "output is not valid UTF-8: {}", err)
             }
+            ErrorKind::Deserialize(ref err) => {
+                write!(f, "CSV read error: ")?;
+                self.fmt_location(f)?;
+                write!(f, "{}", err)
+            }
+            ErrorKind::RecordTooLarge { max_len } => {
+                write!(f, "CSV read error: ")?;
+                self.fmt_location(f)?;
+                write!(f, "record is longer than the maximum of {} bytes", max_len)
+            }
+            ErrorKind::LimitExceeded(limit) => {
+                write!(f, "CSV write error: ")?;
+                self.fmt_location(f)?;
+                write!(f, "{}", limit)
+            }
+            ErrorKind::RecordLenMismatch { expected_len, len } => {
+                write!(f, "CSV write error: ")?;
+                self.fmt_location(f)?;
+                write!(
+                    f,
+                    "records took {} bytes, but were declared to take {} bytes",
+                    len, expected_len
+                )
+            }
+            #[cfg(any(feature = "sqlx-sqlite", feature = "sqlx-postgres"))]
+            ErrorKind::Database(ref err) => {
+                write!(f, "CSV write error: ")?;
+                self.fmt_location(f)?;
+                write!(f, "database error: {}", err)
+            }
+        }
+    }
+}
+
+impl Error {
+    /// Write the record position and field of this error, if known, as a
+    /// prefix for the error message.
+    fn fmt_location(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref pos) = self.0.pos {
+            write!(f, "record {} (byte: {})", pos.record, pos.byte)?;
+            if self.0.field.is_some() {
+                write!(f, ", ")?;
+            }
+        }
+        if let Some(ref field) = self.0.field {
+            write!(f, "field {}", field.index)?;
+            if let Some(ref header) = field.header {
+                write!(f, " ({:?})", header)?;
+            }
+        }
+        if self.0.pos.is_some() || self.0.field.is_some() {
+            write!(f, ": ")?;
         }
+        Ok(())
     }
 }
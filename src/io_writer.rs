@@ -0,0 +1,318 @@
+use std::io;
+
+use serde::Serialize;
+
+use crate::{Result, Stats, Writer};
+
+/// A CSV writer that writes to an [`io::Write`], such as a `File` or a
+/// `TcpStream`.
+///
+/// Output is buffered internally, and written to the underlying writer
+/// whenever the buffer reaches the configured
+/// [`buffer_capacity`](crate::WriterBuilder::buffer_capacity), only ever
+/// between records. Any remaining output is written when the writer is
+/// flushed or dropped. Errors while flushing on drop are ignored, so call
+/// [`flush`](IoWriter::flush) or [`into_inner`](IoWriter::into_inner) to
+/// handle them.
+///
+/// # Example
+///
+/// ```
+/// use std::error::Error;
+/// use csv_stream::WriterBuilder;
+///
+/// # fn main() { example().unwrap(); }
+/// fn example() -> Result<(), Box<dyn Error>> {
+///     let mut wtr = WriterBuilder::default().build_io(vec![]);
+///     wtr.write_record(&["a", "b", "c"])?;
+///     wtr.write_record(&["x", "y", "z"])?;
+///
+///     let data = String::from_utf8(wtr.into_inner()?)?;
+///     assert_eq!(data, "a,b,c\nx,y,z\n");
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug)]
+pub struct IoWriter<W: io::Write> {
+    wtr: Writer,
+    buf: Vec<u8>,
+    capacity: usize,
+    /// This is only `None` after `into_inner` has been called.
+    inner: Option<W>,
+    /// Set while writing to the underlying writer, so that a panic in it
+    /// doesn't cause the same data to be written again on drop.
+    panicked: bool,
+}
+
+impl<W: io::Write> IoWriter<W> {
+    pub fn new(inner: W, writer: Writer, capacity: usize) -> Self {
+        Self {
+            wtr: writer,
+            buf: Vec::with_capacity(capacity),
+            capacity,
+            inner: Some(inner),
+            panicked: false,
+        }
+    }
+
+    /// Serialize a single record using Serde.
+    ///
+    /// See [`Writer::serialize`] for more details.
+    pub fn serialize<S: Serialize>(&mut self, record: S) -> Result<()> {
+        self.wtr.serialize(&mut self.buf, record)?;
+        self.flush_if_full()
+    }
+
+    /// Write a single record.
+    ///
+    /// See [`Writer::write_record`] for more details.
+    pub fn write_record<I, T>(&mut self, record: I) -> Result<()>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        self.wtr.write_record(&mut self.buf, record)?;
+        self.flush_if_full()
+    }
+
+    /// Write a single field.
+    ///
+    /// See [`Writer::write_field`] for more details.
+    pub fn write_field<T: AsRef<[u8]>>(&mut self, field: T) -> Result<()> {
+        self.wtr.write_field(&mut self.buf, field)
+    }
+
+    /// Write a comment line.
+    ///
+    /// See [`Writer::write_comment`] for more details.
+    pub fn write_comment<T: AsRef<[u8]>>(&mut self, text: T) -> Result<()> {
+        self.wtr.write_comment(&mut self.buf, text)?;
+        self.flush_if_full()
+    }
+
+    /// Returns statistics about the data written so far, including data that
+    /// is still buffered.
+    pub fn stats(&self) -> Stats {
+        self.wtr.stats()
+    }
+
+    /// Write all buffered output to the underlying writer, and flush it.
+    ///
+    /// Note that a record that was only partially written with
+    /// [`write_field`](IoWriter::write_field) is written as it is.
+    pub fn flush(&mut self) -> Result<()> {
+        self.flush_buf()?;
+        self.get_mut().flush()?;
+        Ok(())
+    }
+
+    /// Flush this writer and return the underlying writer.
+    pub fn into_inner(mut self) -> Result<W> {
+        self.flush()?;
+        Ok(self.inner.take().unwrap())
+    }
+
+    /// Returns a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        self.inner.as_ref().unwrap()
+    }
+
+    fn get_mut(&mut self) -> &mut W {
+        self.inner.as_mut().unwrap()
+    }
+
+    fn flush_if_full(&mut self) -> Result<()> {
+        if self.buf.len() >= self.capacity {
+            self.flush_buf()?;
+        }
+        Ok(())
+    }
+
+    /// Write out the buffer.
+    ///
+    /// Like `std::io::BufWriter`, only the bytes the underlying writer
+    /// accepted are removed from the buffer if this fails part way, so they
+    /// are never written twice.
+    fn flush_buf(&mut self) -> Result<()> {
+        let mut written = 0;
+        let mut res = Ok(());
+        while written < self.buf.len() {
+            self.panicked = true;
+            let r = self.inner.as_mut().unwrap().write(&self.buf[written..]);
+            self.panicked = false;
+            match r {
+                Ok(0) => {
+                    res = Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write the buffered data",
+                    ));
+                    break;
+                }
+                Ok(n) => written += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    res = Err(err);
+                    break;
+                }
+            }
+        }
+        self.buf.drain(..written);
+        Ok(res?)
+    }
+}
+
+impl<W: io::Write> Drop for IoWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() && !self.panicked {
+            let _ = self.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+    use std::io;
+
+    use crate::{ErrorKind, WriterBuilder};
+
+    /// A writer that records every call to `write`.
+    #[derive(Default)]
+    struct Recorder {
+        writes: Vec<Vec<u8>>,
+    }
+
+    impl io::Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Broken;
+
+    impl io::Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("broken pipe"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A writer that accepts `budget` bytes, then fails once.
+    struct Flaky {
+        out: Vec<u8>,
+        budget: Option<usize>,
+    }
+
+    impl io::Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = match self.budget {
+                Some(0) => {
+                    self.budget = None;
+                    return Err(io::Error::other("broken pipe"));
+                }
+                Some(ref mut budget) => {
+                    let len = buf.len().min(*budget);
+                    *budget -= len;
+                    len
+                }
+                None => buf.len(),
+            };
+            self.out.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn serialize() {
+        #[derive(serde::Serialize)]
+        struct Row {
+            foo: i32,
+            bar: &'static str,
+        }
+
+        let mut wtr = WriterBuilder::default().build_io(vec![]);
+        wtr.serialize(Row { foo: 1, bar: "a" }).unwrap();
+        wtr.serialize(Row { foo: 2, bar: "b" }).unwrap();
+
+        let data = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+        assert_eq!(data, "foo,bar\n1,a\n2,b\n");
+    }
+
+    #[test]
+    fn write_field() {
+        let mut wtr = WriterBuilder::default().build_io(vec![]);
+        wtr.write_field("a").unwrap();
+        wtr.write_field("b").unwrap();
+        wtr.write_record(None::<&[u8]>).unwrap();
+
+        assert_eq!(wtr.into_inner().unwrap(), b"a,b\n");
+    }
+
+    #[test]
+    fn buffer_capacity() {
+        let mut wtr = WriterBuilder::default()
+            .buffer_capacity(8)
+            .build_io(Recorder::default());
+        wtr.write_record(["a", "b"]).unwrap();
+        assert!(wtr.get_ref().writes.is_empty());
+        wtr.write_record(["c", "d"]).unwrap();
+        assert_eq!(wtr.get_ref().writes, [b"a,b\nc,d\n"]);
+        wtr.write_record(["e", "f"]).unwrap();
+
+        let inner = wtr.into_inner().unwrap();
+        assert_eq!(inner.writes, [&b"a,b\nc,d\n"[..], b"e,f\n"]);
+    }
+
+    #[test]
+    fn flush_on_drop() {
+        let mut out = vec![];
+        {
+            let mut wtr = WriterBuilder::default().build_io(&mut out);
+            wtr.write_record(["a", "b"]).unwrap();
+        }
+
+        assert_eq!(out, b"a,b\n");
+    }
+
+    #[test]
+    fn io_error() {
+        let mut wtr = WriterBuilder::default().build_io(Broken);
+        wtr.write_record(["a", "b"]).unwrap();
+        let err = wtr.flush().unwrap_err();
+
+        match *err.kind() {
+            ErrorKind::Io(ref err) => assert_eq!(err.to_string(), "broken pipe"),
+            ref x => panic!("expected ErrorKind::Io but got '{:?}'", x),
+        }
+        assert_eq!(err.source().unwrap().to_string(), "broken pipe");
+    }
+
+    #[test]
+    fn partial_flush() {
+        let flaky = Flaky {
+            out: vec![],
+            budget: Some(3),
+        };
+        let mut wtr = WriterBuilder::default().build_io(flaky);
+        wtr.write_record(["a", "b"]).unwrap();
+        wtr.write_record(["c", "d"]).unwrap();
+        assert!(wtr.flush().is_err());
+        assert_eq!(wtr.get_ref().out, b"a,b");
+
+        // Only the rest of the buffer is written on the next flush.
+        wtr.flush().unwrap();
+        assert_eq!(wtr.into_inner().unwrap().out, b"a,b\nc,d\n");
+    }
+}
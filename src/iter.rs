@@ -1,6 +1,11 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
 use serde::Serialize;
 
-use crate::{Result, Writer};
+use crate::serializer::{capture_fields, Fields};
+use crate::writer::{Recovery, Trailer};
+use crate::{Result, Stats, Writer};
 
 /// An iterable CSV creator
 ///
@@ -34,20 +39,203 @@ use crate::{Result, Writer};
 /// }
 /// ```
 pub struct Iter<I> {
-    iter: I,
+    /// The input, in a cell so that the first record can be read ahead from
+    /// `size_hint`.
+    iter: RefCell<I>,
 
     writer: Writer,
+    /// Set once the input is exhausted, or the error policy has decided that
+    /// no more records should be written.
+    done: bool,
+    trailer: Option<Trailer>,
+    /// Records read ahead of time to infer the header row.
+    captured: VecDeque<Result<Fields>>,
+    #[cfg(feature = "http-body")]
+    body: crate::body::BodyState,
+    /// The first item, read ahead of time so that the size of the header row
+    /// is known.
+    #[cfg(feature = "http-body")]
+    peeked: RefCell<Option<Peeked>>,
+}
+
+/// An item read ahead of time, along with the state of the iterator after
+/// it.
+#[cfg(feature = "http-body")]
+struct Peeked {
+    writer: Writer,
+    captured: VecDeque<Result<Fields>>,
+    done: bool,
+    step: Step,
+}
+
+/// What writing the next record produced.
+enum Step {
+    /// An item to yield.
+    Item(Result<Vec<u8>>),
+    /// The output ends, after what has been written to the buffer and
+    /// whatever the trailer hook writes.
+    Finish(Vec<u8>),
+    /// Nothing more is written.
+    Done,
 }
 
 impl<I: Iterator> Iter<I> {
     pub fn new(iter: impl IntoIterator<IntoIter = I>, writer: Writer) -> Self {
         Self {
-            iter: iter.into_iter(),
+            iter: RefCell::new(iter.into_iter()),
             writer,
+            done: false,
+            trailer: None,
+            captured: VecDeque::new(),
+            #[cfg(feature = "http-body")]
+            body: Default::default(),
+            #[cfg(feature = "http-body")]
+            peeked: RefCell::new(None),
         }
     }
 }
 
+impl<I> Iter<I> {
+    /// Set a hook to be called once the input is exhausted, to write any
+    /// final records such as a trailer or summary record.
+    ///
+    /// The hook receives the statistics of the data written so far and the
+    /// writer, with a buffer to write into. Whatever it writes is yielded as
+    /// the final item of the iterator. Use
+    /// [`Writer::write_flexible_record`] to write records that do not have
+    /// the same number of fields as the data.
+    ///
+    /// The hook is not called if the iterator was ended early by
+    /// [`ErrorPolicy::Abort`](crate::ErrorPolicy::Abort).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::error::Error;
+    /// use csv_stream::WriterBuilder;
+    ///
+    /// # fn main() { example().unwrap(); }
+    /// fn example() -> Result<(), Box<dyn Error>> {
+    ///     let rows = [("a", 10), ("b", 20)];
+    ///     let total: u64 = rows.iter().map(|row| row.1).sum();
+    ///
+    ///     let csv_iter = WriterBuilder::default()
+    ///         .build_iter(rows)
+    ///         .trailer(move |stats, wtr, buf| {
+    ///             let count = stats.records().to_string();
+    ///             wtr.write_flexible_record(buf, ["TRAILER", &count, &total.to_string()])
+    ///         });
+    ///
+    ///     let mut buf = vec![];
+    ///     for row in csv_iter {
+    ///         buf.extend_from_slice(&row?);
+    ///     }
+    ///
+    ///     let data = String::from_utf8(buf)?;
+    ///     assert_eq!(data, "a,10\nb,20\nTRAILER,2,30\n");
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn trailer<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&Stats, &mut Writer, &mut Vec<u8>) -> Result<()> + Send + 'static,
+    {
+        self.trailer = Some(Box::new(f));
+        self
+    }
+
+    /// Returns the number of records that have been dropped so far according
+    /// to the configured [`ErrorPolicy`](crate::ErrorPolicy).
+    pub fn skipped(&self) -> u64 {
+        self.writer.skipped()
+    }
+
+    /// Returns statistics about the data written so far.
+    ///
+    /// This can be called while iterating, or once the iterator is finished.
+    pub fn stats(&self) -> Stats {
+        self.writer.stats()
+    }
+
+    /// Returns the writer used to write records, to inspect its
+    /// configuration.
+    pub fn writer(&self) -> &Writer {
+        &self.writer
+    }
+
+    /// Declare that every record takes exactly `len` bytes, including the
+    /// record terminator, such as with fixed width data.
+    ///
+    /// When the number of remaining input records is known exactly, as it is
+    /// for an [`ExactSizeIterator`], this lets the [`http_body::Body`]
+    /// implementation report the exact size of the body, so that it can be
+    /// sent with a `Content-Length`. The size is wrong if any record fails to
+    /// be written. A frame whose records do not take `len` bytes each fails
+    /// with [`ErrorKind::RecordLenMismatch`](crate::ErrorKind::RecordLenMismatch)
+    /// instead of being sent.
+    ///
+    /// When the size of the body is first asked for, the first record is
+    /// read and written ahead of time, along with the header row, so that its
+    /// size is known. Hooks such as
+    /// [`on_skip`](crate::WriterBuilder::on_skip) may then be called from the
+    /// size query rather than when the first frame is polled. A
+    /// [`trailer`](Iter::trailer) hook writes a record of unknown size, so no
+    /// exact size is reported until it has been called.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use csv_stream::WriterBuilder;
+    /// use http_body::Body;
+    ///
+    /// let rows = [("a", 1), ("b", 2), ("c", 3)];
+    /// let body = WriterBuilder::default()
+    ///     .build_iter(rows)
+    ///     .record_len(4);
+    ///
+    /// assert_eq!(Body::size_hint(&body).exact(), Some(12));
+    /// ```
+    #[cfg(feature = "http-body")]
+    pub fn record_len(mut self, len: u64) -> Self {
+        self.body.record_len = Some(len);
+        self
+    }
+
+    /// Set a hook to be called once all records have been written, to
+    /// produce the HTTP trailers sent at the end of the body, such as a row
+    /// count or checksum.
+    ///
+    /// The hook receives the statistics of the data written. It is not
+    /// called if the body ends with an error.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use csv_stream::WriterBuilder;
+    /// use http::{HeaderMap, HeaderValue};
+    ///
+    /// let rows = [("a", 10), ("b", 20)];
+    /// let total: u64 = rows.iter().map(|row| row.1).sum();
+    ///
+    /// let body = WriterBuilder::default()
+    ///     .build_iter(rows)
+    ///     .http_trailers(move |stats| {
+    ///         let mut trailers = HeaderMap::new();
+    ///         trailers.insert("x-row-count", HeaderValue::from(stats.records()));
+    ///         trailers.insert("x-total", HeaderValue::from(total));
+    ///         trailers
+    ///     });
+    /// ```
+    #[cfg(feature = "http-body")]
+    pub fn http_trailers<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&Stats) -> http::HeaderMap + Send + 'static,
+    {
+        self.body.trailers = Some(Box::new(f));
+        self
+    }
+}
+
 impl<I: Iterator> Iterator for Iter<I>
 where
     I::Item: Serialize,
@@ -55,18 +243,225 @@ where
     type Item = Result<Vec<u8>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let s = self.iter.next()?;
+        // Carry on from the state after the item read ahead, if any.
+        #[cfg(feature = "http-body")]
+        if let Some(peeked) = self.peeked.get_mut().take() {
+            self.writer = peeked.writer;
+            self.captured = peeked.captured;
+            self.done = peeked.done;
+            return self.output(peeked.step);
+        }
+        let step = advance(
+            self.iter.get_mut(),
+            &mut self.writer,
+            &mut self.captured,
+            &mut self.done,
+        );
+        self.output(step)
+    }
+}
+
+impl<I> Iter<I> {
+    /// Turn a step into the next item, running the trailer hook if the
+    /// output ends.
+    fn output(&mut self, step: Step) -> Option<Result<Vec<u8>>> {
+        match step {
+            Step::Item(item) => Some(item),
+            Step::Finish(buf) => self.writer.finish(buf, self.trailer.take()),
+            Step::Done => None,
+        }
+    }
+}
+
+/// Write the next record, or note that the output ends once the input is
+/// exhausted.
+fn advance<I>(
+    iter: &mut I,
+    writer: &mut Writer,
+    captured: &mut VecDeque<Result<Fields>>,
+    done: &mut bool,
+) -> Step
+where
+    I: Iterator,
+    I::Item: Serialize,
+{
+    if let Some(window) = writer.infer_window() {
+        while captured.len() < window {
+            match iter.next() {
+                Some(s) => captured.push_back(capture_fields(s)),
+                None => break,
+            }
+        }
+        writer.infer_columns(&*captured);
+    }
+    while !*done {
         let mut buf = vec![];
-        Some(self.writer.serialize(&mut buf, s).map(|_| buf))
+        let res = if let Some(fields) = captured.pop_front() {
+            writer.write_fields(&mut buf, fields)
+        } else if let Err(err) = writer.check_record_limit() {
+            Err(err)
+        } else {
+            match iter.next() {
+                Some(s) => writer.serialize(&mut buf, s),
+                None => {
+                    *done = true;
+                    return Step::Finish(buf);
+                }
+            }
+        };
+        let err = match res {
+            Ok(()) => return Step::Item(Ok(buf)),
+            Err(err) => err,
+        };
+        match writer.recover(&mut buf, err) {
+            Recovery::Yield(err) => return Step::Item(Err(err)),
+            Recovery::Abort(err) => {
+                *done = true;
+                return Step::Item(Err(err));
+            }
+            Recovery::Skip => continue,
+            Recovery::Placeholder => return Step::Item(Ok(buf)),
+            Recovery::Stop => {
+                *done = true;
+                return Step::Finish(buf);
+            }
+        }
+    }
+    Step::Done
+}
+
+#[cfg(feature = "http-body")]
+impl<I: Iterator> Iter<I>
+where
+    I::Item: Serialize,
+{
+    /// Write the first item ahead of time, on a copy of the writer, if a
+    /// record length is declared so that the size of the body can be known.
+    ///
+    /// If the output ends there, the trailer hook is left for `next` to
+    /// call.
+    fn peek(&self) {
+        if self.body.record_len.is_none()
+            || self.done
+            || !self.captured.is_empty()
+            || self.writer.stats().bytes() > 0
+        {
+            return;
+        }
+        let mut peeked = self.peeked.borrow_mut();
+        if peeked.is_some() {
+            return;
+        }
+        let mut writer = self.writer.clone();
+        let mut captured = VecDeque::new();
+        let mut done = false;
+        let step = advance(
+            &mut *self.iter.borrow_mut(),
+            &mut writer,
+            &mut captured,
+            &mut done,
+        );
+        *peeked = Some(Peeked {
+            writer,
+            captured,
+            done,
+            step,
+        });
+    }
+}
+
+#[cfg(feature = "http-body")]
+impl<I: Iterator + Unpin> http_body::Body for Iter<I>
+where
+    I::Item: Serialize,
+{
+    type Data = bytes::Bytes;
+    type Error = crate::Error;
+
+    fn poll_frame(
+        self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<http_body::Frame<bytes::Bytes>>>> {
+        let this = self.get_mut();
+        let stats = this.writer.stats();
+        let pos = this.writer.position();
+        let done = this.done;
+        let mut item = this.next();
+
+        // The size of the first item, with the header row, is measured
+        // rather than declared, and the item that ends the output may hold
+        // a comment for a stopping limit and records of any size from the
+        // trailer hook.
+        if let (Some(len), Some(Ok(buf))) = (this.body.record_len, &item) {
+            let records = this.writer.stats().records() - stats.records();
+            let expected_len = records * len;
+            if stats.bytes() > 0 && this.done == done && buf.len() as u64 != expected_len {
+                let kind = crate::ErrorKind::RecordLenMismatch {
+                    expected_len,
+                    len: buf.len() as u64,
+                };
+                item = Some(Err(crate::Error::new(kind).or_position(pos)));
+            }
+        }
+
+        let writer = &this.writer;
+        std::task::Poll::Ready(this.body.frame(item, || writer.stats()))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done && self.peeked.borrow().is_none() && self.body.trailers.is_none()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.peek();
+        let peeked = self.peeked.borrow();
+        let (done, captured, finish) = match *peeked {
+            Some(ref peeked) => (
+                peeked.done,
+                peeked.captured.len(),
+                matches!(peeked.step, Step::Finish(_)),
+            ),
+            None => (self.done, self.captured.len(), false),
+        };
+        let remaining = match self.iter.borrow().size_hint() {
+            // The trailer hook is yet to write records of unknown size.
+            _ if finish && self.trailer.is_some() => None,
+            _ if done => Some(0),
+            (lower, Some(upper)) if lower == upper && self.trailer.is_none() => {
+                let records = (lower + captured) as u64;
+                self.body.record_len.map(|len| records * len)
+            }
+            _ => None,
+        };
+
+        let peeked = match *peeked {
+            Some(Peeked {
+                step: Step::Item(Ok(ref buf)) | Step::Finish(ref buf),
+                ..
+            }) => buf.len() as u64,
+            _ => 0,
+        };
+        match remaining {
+            Some(remaining) => http_body::SizeHint::with_exact(peeked + remaining),
+            None => {
+                let mut hint = http_body::SizeHint::new();
+                hint.set_lower(peeked);
+                hint
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{Terminator, WriterBuilder};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::{ErrorPolicy, HeaderOrder, LimitPolicy, Terminator, WriterBuilder};
     use serde::Serialize;
 
     use super::Iter;
+    use crate::test_util::Fallible;
 
     #[derive(Serialize)]
     struct Row<'a> {
@@ -98,10 +493,7 @@ mod tests {
 
         let i = Iter::new(ROWS, writer);
 
-        let buf = i
-            .map(Result::unwrap)
-            .flatten()
-            .collect();
+        let buf = i.flat_map(Result::unwrap).collect();
 
         let buf = String::from_utf8(buf).unwrap();
 
@@ -124,10 +516,7 @@ Concord,United States,42695
 
         let i = Iter::new(ROWS, writer);
 
-        let buf = i
-            .map(Result::unwrap)
-            .flatten()
-            .collect();
+        let buf = i.flat_map(Result::unwrap).collect();
 
         let buf = String::from_utf8(buf).unwrap();
 
@@ -138,4 +527,384 @@ Concord;United States;42695
 "#.replace("\n", "\r\n")
         )
     }
+
+    const FALLIBLE: [Fallible; 3] = [Fallible(Some(1)), Fallible(None), Fallible(Some(3))];
+
+    #[test]
+    fn error_policy_continue() {
+        let i = WriterBuilder::default().build_iter(FALLIBLE);
+
+        let rows: Vec<_> = i.collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].as_ref().unwrap(), b"1,2\n");
+        assert!(rows[1].is_err());
+        assert_eq!(rows[2].as_ref().unwrap(), b"3,6\n");
+    }
+
+    #[test]
+    fn error_policy_abort() {
+        let i = WriterBuilder::default()
+            .error_policy(ErrorPolicy::Abort)
+            .build_iter(FALLIBLE);
+
+        let rows: Vec<_> = i.collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].as_ref().unwrap(), b"1,2\n");
+        assert!(rows[1].is_err());
+    }
+
+    #[test]
+    fn error_policy_skip() {
+        let logged = Arc::new(AtomicUsize::new(0));
+        let hook = logged.clone();
+
+        let mut i = WriterBuilder::default()
+            .error_policy(ErrorPolicy::Skip)
+            .on_skip(move |err| {
+                assert_eq!(err.position().unwrap().record(), 1);
+                hook.fetch_add(1, Ordering::SeqCst);
+            })
+            .build_iter(FALLIBLE);
+
+        let buf: Vec<u8> = i.by_ref().flat_map(Result::unwrap).collect();
+        assert_eq!(String::from_utf8(buf).unwrap(), "1,2\n3,6\n");
+        assert_eq!(i.skipped(), 1);
+        assert_eq!(logged.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn error_policy_placeholder() {
+        let i = WriterBuilder::default()
+            .error_policy(ErrorPolicy::Placeholder)
+            .build_iter(FALLIBLE);
+
+        let buf: Vec<u8> = i.flat_map(Result::unwrap).collect();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "1,2\n\"CSV write error: record 1 (byte: 4), field 0: missing value\",\n3,6\n"
+        );
+    }
+
+    #[test]
+    fn error_policy_skip_partial_record() {
+        let rows = [vec![1, 2], vec![1, 2, 3], vec![4, 5]];
+        let i = WriterBuilder::default()
+            .error_policy(ErrorPolicy::Skip)
+            .build_iter(rows);
+
+        let buf: Vec<u8> = i.flat_map(Result::unwrap).collect();
+        assert_eq!(String::from_utf8(buf).unwrap(), "1,2\n4,5\n");
+    }
+
+    #[test]
+    fn error_policy_comment() {
+        let i = WriterBuilder::default()
+            .comment(b'#')
+            .error_policy(ErrorPolicy::Comment)
+            .build_iter(FALLIBLE);
+
+        let buf: Vec<u8> = i.flat_map(Result::unwrap).collect();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "1,2\n#CSV write error: record 1 (byte: 4), field 0: missing value\n3,6\n"
+        );
+    }
+
+    #[test]
+    fn error_policy_comment_disabled() {
+        let mut i = WriterBuilder::default()
+            .error_policy(ErrorPolicy::Comment)
+            .build_iter(FALLIBLE);
+
+        assert_eq!(i.next().unwrap().unwrap(), b"1,2\n");
+        let err = i.next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("missing value"), "{}", err);
+        assert_eq!(i.next().unwrap().unwrap(), b"3,6\n");
+        assert_eq!(i.skipped(), 0);
+    }
+
+    #[test]
+    fn trailer() {
+        let i = WriterBuilder::default()
+            .build_iter(ROWS)
+            .trailer(|stats, wtr, buf| {
+                wtr.write_flexible_record(buf, ["TRAILER", &stats.records().to_string()])
+            });
+
+        let buf: Vec<u8> = i.flat_map(Result::unwrap).collect();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            r#"city,country,popcount
+Boston,United States,4628910
+Concord,United States,42695
+TRAILER,2
+"#
+        );
+    }
+
+    #[test]
+    fn trailer_not_called_after_abort() {
+        let mut i = WriterBuilder::default()
+            .error_policy(ErrorPolicy::Abort)
+            .build_iter(FALLIBLE)
+            .trailer(|_, _, _| panic!("trailer called after abort"));
+
+        assert!(i.next().unwrap().is_ok());
+        assert!(i.next().unwrap().is_err());
+        assert!(i.next().is_none());
+    }
+
+    #[test]
+    fn stats() {
+        let mut i = WriterBuilder::default().build_iter(ROWS);
+
+        i.next().unwrap().unwrap();
+        assert_eq!(i.stats().records(), 1);
+        assert_eq!(i.stats().fields(), 3);
+
+        assert!(i.by_ref().all(|r| r.is_ok()));
+        let stats = i.stats();
+        assert_eq!(stats.records(), 2);
+        assert_eq!(stats.fields(), 6);
+        assert_eq!(stats.max_record_width(), 3);
+        assert_eq!(stats.bytes(), 79);
+    }
+
+    #[cfg(feature = "http-body")]
+    #[tokio::test]
+    async fn http_body() {
+        use http_body::Body;
+        use http_body_util::BodyExt;
+
+        let rows = [("a", 1), ("b", 2), ("c", 3)];
+        let body = WriterBuilder::default()
+            .build_iter(rows)
+            .http_trailers(|stats| {
+                let mut trailers = http::HeaderMap::new();
+                trailers.insert("x-row-count", stats.records().into());
+                trailers
+            })
+            .record_len(4);
+        assert_eq!(Body::size_hint(&body).exact(), Some(12));
+
+        let collected = BodyExt::collect(body).await.unwrap();
+        assert_eq!(collected.trailers().unwrap()["x-row-count"], "3");
+        assert_eq!(&collected.to_bytes()[..], b"a,1\nb,2\nc,3\n");
+    }
+
+    #[cfg(feature = "http-body")]
+    #[test]
+    fn http_body_size_hint() {
+        use http_body::Body;
+
+        // The first record is read ahead, along with the header row, so only
+        // the last record needs to have the declared size.
+        let body = WriterBuilder::default().build_iter(ROWS).record_len(28);
+        assert_eq!(Body::size_hint(&body).exact(), Some(79));
+
+        let body = WriterBuilder::default().build_iter(ROWS);
+        assert_eq!(Body::size_hint(&body).exact(), None);
+
+        let body = WriterBuilder::default()
+            .build_iter(ROWS.into_iter().filter(|_| true))
+            .record_len(28);
+        assert_eq!(Body::size_hint(&body).exact(), None);
+        assert_eq!(Body::size_hint(&body).lower(), 51);
+    }
+
+    #[cfg(feature = "http-body")]
+    #[test]
+    fn http_body_lazy_peek() {
+        use http_body::Body;
+
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let rows = ROWS.into_iter().inspect({
+            let pulled = pulled.clone();
+            move |_| {
+                pulled.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        let body = WriterBuilder::default().build_iter(rows).record_len(28);
+        assert_eq!(pulled.load(Ordering::SeqCst), 0);
+        assert_eq!(body.stats().bytes(), 0);
+
+        assert_eq!(Body::size_hint(&body).exact(), Some(79));
+        assert_eq!(Body::size_hint(&body).exact(), Some(79));
+        assert_eq!(pulled.load(Ordering::SeqCst), 1);
+
+        let buf: Vec<u8> = body.flat_map(Result::unwrap).collect();
+        assert_eq!(buf.len(), 79);
+    }
+
+    #[cfg(feature = "http-body")]
+    #[test]
+    fn http_body_peek_trailer() {
+        use http_body::Body;
+
+        let build = || {
+            WriterBuilder::default()
+                .has_headers(false)
+                .comment(b'#')
+                .max_records(0, LimitPolicy::Stop)
+                .build_iter([("a", 1), ("b", 2)])
+                .trailer(|stats, wtr, buf| {
+                    wtr.write_flexible_record(buf, ["TRAILER", &stats.records().to_string()])
+                })
+                .record_len(4)
+        };
+        let expected = "#truncated: more than the maximum of 0 records\nTRAILER,0\n";
+
+        let body = build();
+        let buf: Vec<u8> = body.flat_map(Result::unwrap).collect();
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+
+        let body = build();
+        let hint = Body::size_hint(&body);
+        assert_eq!(hint.exact(), None);
+        assert_eq!(hint.lower(), 47);
+        let buf: Vec<u8> = body.flat_map(Result::unwrap).collect();
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+    }
+
+    #[cfg(feature = "http-body")]
+    #[tokio::test]
+    async fn http_body_record_len_mismatch() {
+        use http_body_util::BodyExt;
+
+        use crate::ErrorKind;
+
+        let rows = [("a", 1), ("bb", 2), ("c", 3)];
+        let mut body = WriterBuilder::default().build_iter(rows).record_len(4);
+
+        let frame = body.frame().await.unwrap().unwrap();
+        assert_eq!(frame.into_data().unwrap(), "a,1\n");
+        let err = body.frame().await.unwrap().unwrap_err();
+        match *err.kind() {
+            ErrorKind::RecordLenMismatch { expected_len, len } => {
+                assert_eq!((expected_len, len), (4, 5));
+            }
+            ref x => panic!("expected ErrorKind::RecordLenMismatch but got '{:?}'", x),
+        }
+        assert_eq!(err.position().unwrap().record(), 1);
+    }
+
+    #[test]
+    fn infer_headers() {
+        let rows = [
+            serde_json::json!({ "b": 1, "c": 2 }),
+            serde_json::json!({ "a": 3, "b": 4 }),
+            serde_json::json!({ "c": 5 }),
+        ];
+        let i = WriterBuilder::default()
+            .infer_headers(2)
+            .build_iter(rows.iter());
+        let buf: Vec<u8> = i.flat_map(Result::unwrap).collect();
+        assert_eq!(String::from_utf8(buf).unwrap(), "b,c,a\n1,2,\n4,,3\n,5,\n");
+
+        let i = WriterBuilder::default()
+            .infer_headers(2)
+            .header_order(HeaderOrder::Sorted)
+            .build_iter(rows.iter());
+        let buf: Vec<u8> = i.flat_map(Result::unwrap).collect();
+        assert_eq!(String::from_utf8(buf).unwrap(), "a,b,c\n,1,2\n3,4,\n,,5\n");
+    }
+
+    #[test]
+    fn infer_headers_unknown_field() {
+        let rows = [
+            serde_json::json!({ "b": 1, "c": 2 }),
+            serde_json::json!({ "a": 3, "b": 4 }),
+            serde_json::json!({ "c": 5 }),
+        ];
+        let mut i = WriterBuilder::default()
+            .infer_headers(1)
+            .build_iter(rows.iter());
+        assert_eq!(i.next().unwrap().unwrap(), b"b,c\n1,2\n");
+        let err = i.next().unwrap().unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("field is not in the inferred header row"),
+            "{err}"
+        );
+        assert!(err.to_string().contains("\"a\""), "{err}");
+        assert_eq!(i.next().unwrap().unwrap(), b",5\n");
+        assert!(i.next().is_none());
+
+        let i = WriterBuilder::default()
+            .infer_headers(1)
+            .flexible(true)
+            .build_iter(rows.iter());
+        let buf: Vec<u8> = i.flat_map(Result::unwrap).collect();
+        assert_eq!(String::from_utf8(buf).unwrap(), "b,c\n1,2\n4,,3\n,5,\n");
+    }
+
+    #[test]
+    fn infer_headers_not_a_map() {
+        let rows = [
+            serde_json::json!({ "a": 1 }),
+            serde_json::json!(2),
+            serde_json::json!({ "a": [3] }),
+            serde_json::json!({ "a": null }),
+        ];
+        let mut i = WriterBuilder::default()
+            .infer_headers(4)
+            .build_iter(rows.iter());
+        assert_eq!(i.next().unwrap().unwrap(), b"a\n1\n");
+        let err = i.next().unwrap().unwrap_err();
+        assert!(
+            err.to_string().contains("records must be maps or structs"),
+            "{err}"
+        );
+        assert!(i.next().unwrap().is_err());
+        assert_eq!(i.next().unwrap().unwrap(), b"\"\"\n");
+        assert!(i.next().is_none());
+    }
+
+    #[test]
+    fn max_records() {
+        let read = Arc::new(AtomicUsize::new(0));
+        let counter = read.clone();
+        let rows = (1..).inspect(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let i = WriterBuilder::default()
+            .max_records(2, LimitPolicy::Truncate)
+            .build_iter(rows);
+        let buf: Vec<u8> = i.flat_map(Result::unwrap).collect();
+        assert_eq!(String::from_utf8(buf).unwrap(), "1\n2\n");
+        assert_eq!(read.load(Ordering::SeqCst), 2);
+
+        let i = WriterBuilder::default()
+            .max_records(2, LimitPolicy::Error)
+            .error_policy(ErrorPolicy::Comment)
+            .comment(b'#')
+            .build_iter(1..=3);
+        let buf: Vec<u8> = i.flat_map(Result::unwrap).collect();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "1\n2\n#CSV write error: record 2 (byte: 4): more than the maximum of 2 records\n"
+        );
+    }
+
+    #[test]
+    fn limit_stop() {
+        let i = WriterBuilder::default()
+            .comment(b'#')
+            .max_bytes(24, LimitPolicy::Stop)
+            .build_iter(ROWS)
+            .trailer(|stats, wtr, buf| {
+                wtr.write_flexible_record(buf, ["TRAILER", &stats.records().to_string()])
+            });
+        let buf: Vec<u8> = i.flat_map(Result::unwrap).collect();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "#truncated: output is longer than the maximum of 24 bytes\nTRAILER,0\n"
+        );
+
+        let mut i = WriterBuilder::default()
+            .max_field_len(5, LimitPolicy::Stop)
+            .build_iter([["a"], ["bcdefg"], ["h"]]);
+        assert_eq!(i.next().unwrap().unwrap(), b"a\n");
+        assert!(i.next().is_none());
+    }
 }
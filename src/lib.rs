@@ -1,18 +1,62 @@
+#[cfg(feature = "http-body")]
+mod body;
+mod buf;
+#[cfg(feature = "tokio-util")]
+mod codec;
+#[cfg(feature = "tokio")]
+mod concurrent_stream;
 mod error;
+mod io_writer;
 mod iter;
+#[cfg(feature = "rayon")]
+mod par_iter;
+#[cfg(feature = "sqlx-postgres")]
+mod postgres;
+#[cfg(feature = "arrow")]
+mod record_batch;
+#[cfg(feature = "axum")]
+mod response;
 mod serializer;
 #[cfg(feature = "stream")]
+mod sink;
+mod split;
+#[cfg(any(feature = "sqlx-sqlite", feature = "sqlx-postgres"))]
+mod sql;
+#[cfg(feature = "stream")]
 mod stream;
+#[cfg(test)]
+mod test_util;
 mod writer;
 
-pub use error::{Error, ErrorKind, Result};
+pub use buf::{CsvBuf, SliceBuf};
+#[cfg(feature = "tokio-util")]
+pub use codec::{CsvDecoder, CsvEncoder};
+#[cfg(feature = "tokio")]
+pub use concurrent_stream::ConcurrentStream;
+pub use error::{Error, ErrorKind, Limit, Position, Result};
+pub use io_writer::IoWriter;
 pub use iter::Iter;
+#[cfg(feature = "rayon")]
+pub use par_iter::ParIter;
+#[cfg(feature = "arrow")]
+pub use record_batch::RecordBatchIter;
+#[cfg(all(feature = "arrow", feature = "stream"))]
+pub use record_batch::RecordBatchStream;
+#[cfg(feature = "axum")]
+pub use response::CsvResponse;
+#[cfg(feature = "stream")]
+pub use sink::CsvSink;
+pub use split::{Part, Split, SplitIter};
+#[cfg(feature = "stream")]
+pub use split::{SplitStream, StreamPart};
+#[cfg(any(feature = "sqlx-sqlite", feature = "sqlx-postgres"))]
+pub use sql::RowStream;
 #[cfg(feature = "stream")]
 pub use stream::Stream;
-pub use writer::{Writer, WriterBuilder};
+pub use writer::{Stats, Writer, WriterBuilder};
 
 /// The quoting style to use when writing CSV data.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Default)]
 #[non_exhaustive]
 pub enum QuoteStyle {
     /// This puts quotes around every field. Always.
@@ -24,6 +68,7 @@ pub enum QuoteStyle {
     /// (which is indistinguishable from a record with one empty field).
     ///
     /// This is the default.
+    #[default]
     Necessary,
     /// This puts quotes around all fields that are non-numeric. Namely, when
     /// writing a field that does not parse as a valid float or integer, then
@@ -31,6 +76,17 @@ pub enum QuoteStyle {
     NonNumeric,
     /// This *never* writes quotes, even if it would produce invalid CSV data.
     Never,
+    /// This puts quotes around all fields that are serialized from strings,
+    /// characters or bytes, whatever their content, and only around other
+    /// fields, such as numbers and booleans, when necessary.
+    ///
+    /// Unlike `NonNumeric`, this keeps a string such as a ZIP code `"02134"`
+    /// quoted, and tells a missing value apart from an empty string. Arrow
+    /// values are quoted by their data type in the same way, with nulls
+    /// written like missing values. Other fields that are not written with
+    /// Serde, such as those written with `write_record`, are quoted like
+    /// strings.
+    Strings,
 }
 
 impl QuoteStyle {
@@ -40,25 +96,145 @@ impl QuoteStyle {
             QuoteStyle::Necessary => csv_core::QuoteStyle::Necessary,
             QuoteStyle::NonNumeric => csv_core::QuoteStyle::NonNumeric,
             QuoteStyle::Never => csv_core::QuoteStyle::Never,
+            // Fields are quoted by their type in `Writer::should_quote`.
+            QuoteStyle::Strings => csv_core::QuoteStyle::Always,
         }
     }
 }
 
-impl Default for QuoteStyle {
-    fn default() -> QuoteStyle {
-        QuoteStyle::Necessary
-    }
+/// What `Iter` and `Stream` do when a record fails to be written.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub enum ErrorPolicy {
+    /// Yield the error in place of the record and carry on with the next
+    /// record. This is the default.
+    #[default]
+    Continue,
+    /// Yield the error in place of the record and then end. No further
+    /// records are read from the input.
+    Abort,
+    /// Drop the record silently and carry on with the next record.
+    Skip,
+    /// Write a placeholder row in place of the record and carry on with the
+    /// next record.
+    ///
+    /// The first field of the placeholder row is the error message. When the
+    /// number of fields per record is known, the row is padded with empty
+    /// fields to match it.
+    Placeholder,
+    /// Write a comment line containing the error message in place of the
+    /// record and carry on with the next record.
+    ///
+    /// This requires a comment character to be set with
+    /// [`WriterBuilder::comment`]. Without one, errors are yielded as with
+    /// [`ErrorPolicy::Continue`].
+    Comment,
+}
+
+/// What to do with a record that goes over one of the limits set on a
+/// [`WriterBuilder`], such as [`WriterBuilder::max_field_len`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub enum LimitPolicy {
+    /// Fail the record with an [`ErrorKind::LimitExceeded`] error, which is
+    /// then handled according to the [`ErrorPolicy`]. This is the default.
+    #[default]
+    Error,
+    /// Truncate the field that goes over the limit, and end it with the
+    /// marker set with [`WriterBuilder::ellipsis`].
+    ///
+    /// For the limits on the number of records and the number of bytes of
+    /// the whole output, this ends the output before the record that goes
+    /// over the limit.
+    Truncate,
+    /// Drop the record and end the output cleanly, with a comment saying
+    /// that it was truncated if a comment character was set with
+    /// [`WriterBuilder::comment`].
+    Stop,
+}
+
+/// How to sanitize line breaks and other control characters in fields.
+///
+/// See [`WriterBuilder::sanitize`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub enum Sanitize {
+    /// Writes fields as they are, in quotes if they contain a line break.
+    /// This is the default.
+    #[default]
+    None,
+    /// Replaces each control character with the replacement set with
+    /// [`WriterBuilder::sanitize_replacement`].
+    Replace,
+    /// Escapes control characters as `\n`, `\r`, `\t` and `\0`, or as
+    /// `\xNN` for the others. Backslashes are escaped as `\\`, so that
+    /// the original field can be recovered.
+    Escape,
+}
+
+/// The order of the columns of a header row inferred from records.
+///
+/// See [`WriterBuilder::infer_headers`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub enum HeaderOrder {
+    /// Columns are in the order their names are first seen. This is the
+    /// default.
+    #[default]
+    FirstSeen,
+    /// Columns are sorted by name.
+    Sorted,
+}
+
+/// The encoding of fields serialized from bytes, such as with
+/// [`serde_bytes`](https://docs.rs/serde_bytes).
+///
+/// See [`WriterBuilder::bytes_encoding`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub enum BytesEncoding {
+    /// Writes the bytes as they are, even if they are not valid UTF-8. This
+    /// is the default.
+    #[default]
+    Raw,
+    /// Writes the bytes as they are, but returns an error if they are not
+    /// valid UTF-8.
+    Utf8,
+    /// Writes the bytes as lowercase hexadecimal.
+    Hex,
+    /// Writes the bytes as uppercase hexadecimal.
+    HexUpper,
+    /// Writes the bytes as padded base64, with the standard alphabet.
+    ///
+    /// This requires the `base64` feature.
+    #[cfg(feature = "base64")]
+    Base64,
+    /// Writes the bytes as unpadded base64, with the standard alphabet.
+    ///
+    /// This requires the `base64` feature.
+    #[cfg(feature = "base64")]
+    Base64NoPad,
+    /// Writes the bytes as padded base64, with the URL-safe alphabet.
+    ///
+    /// This requires the `base64` feature.
+    #[cfg(feature = "base64")]
+    Base64UrlSafe,
+    /// Writes the bytes as unpadded base64, with the URL-safe alphabet.
+    ///
+    /// This requires the `base64` feature.
+    #[cfg(feature = "base64")]
+    Base64UrlSafeNoPad,
 }
 
 /// A record terminator.
 ///
 /// Use this to specify the record terminator while parsing CSV. The default is
 /// CRLF, which treats `\r`, `\n` or `\r\n` as a single record terminator.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Default)]
 #[non_exhaustive]
-
 pub enum Terminator {
     /// Parses `\r`, `\n` or `\r\n` as a single record terminator.
+    #[default]
     CRLF,
     /// Parses the byte given as a record terminator.
     Any(u8),
@@ -72,20 +248,22 @@ impl Terminator {
             Terminator::Any(b) => csv_core::Terminator::Any(b),
         }
     }
-}
 
-impl Default for Terminator {
-    fn default() -> Terminator {
-        Terminator::CRLF
+    /// Convert the csv_core type of the same name to this.
+    fn from_core(term: csv_core::Terminator) -> Terminator {
+        match term {
+            csv_core::Terminator::Any(b) => Terminator::Any(b),
+            _ => Terminator::CRLF,
+        }
     }
 }
 
 /// The whitespace preservation behaviour when reading CSV data.
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 #[non_exhaustive]
-
 pub enum Trim {
     /// Preserves fields and headers. This is the default.
+    #[default]
     None,
     /// Trim whitespace from headers.
     Headers,
@@ -94,9 +272,3 @@ pub enum Trim {
     /// Trim whitespace from fields and headers.
     All,
 }
-
-impl Default for Trim {
-    fn default() -> Trim {
-        Trim::None
-    }
-}
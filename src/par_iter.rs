@@ -0,0 +1,255 @@
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::writer::{Detached, Recovery, Trailer};
+use crate::{Result, Stats, Writer};
+
+/// A CSV creator that serializes records in parallel
+///
+/// Records are read from the input in batches, and each batch is serialized
+/// on the rayon thread pool. The output is yielded in the same order as the
+/// input, one record at a time, and is identical to the output of
+/// [`Iter`](crate::Iter).
+///
+/// The first records are serialized one at a time, until the header row has
+/// been written and the number of fields per record is known.
+///
+/// # Example
+///
+/// ```
+/// use std::error::Error;
+/// use csv_stream::WriterBuilder;
+/// use serde::Serialize;
+///
+/// # fn main() { example().unwrap(); }
+/// fn example() -> Result<(), Box<dyn Error>> {
+///     #[derive(Serialize)]
+///     struct Row { foo: usize, bar: usize }
+///     let rows = (0..1000).map(|i| Row { foo: i, bar: i * 2 });
+///
+///     let csv_iter = WriterBuilder::default()
+///         .build_par_iter(rows)
+///         .batch_size(100);
+///
+///     let mut buf = vec![];
+///     for row in csv_iter {
+///         buf.extend_from_slice(&row?);
+///     }
+///
+///     let data = String::from_utf8(buf)?;
+///     assert!(data.starts_with("foo,bar\n0,0\n1,2\n"));
+///     assert!(data.ends_with("999,1998\n"));
+///     Ok(())
+/// }
+/// ```
+pub struct ParIter<I> {
+    iter: I,
+
+    writer: Writer,
+    /// The number of records to serialize in parallel at a time.
+    batch_size: usize,
+    /// Records serialized from the current batch, waiting to be merged.
+    batch: std::vec::IntoIter<Detached>,
+    /// Set once the input is exhausted, or the error policy has decided that
+    /// no more records should be written.
+    done: bool,
+    trailer: Option<Trailer>,
+}
+
+impl<I: Iterator> ParIter<I> {
+    pub fn new(iter: impl IntoIterator<IntoIter = I>, writer: Writer) -> Self {
+        Self {
+            iter: iter.into_iter(),
+            writer,
+            batch_size: 1024,
+            batch: Vec::new().into_iter(),
+            done: false,
+            trailer: None,
+        }
+    }
+}
+
+impl<I> ParIter<I> {
+    /// Set the number of records to read from the input and serialize in
+    /// parallel at a time.
+    ///
+    /// Larger batches spread the work better across threads, at the cost of
+    /// holding more records in memory. The default is `1024`.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Set a hook to be called once the input is exhausted, to write any
+    /// final records such as a trailer or summary record.
+    ///
+    /// See [`Iter::trailer`](crate::Iter::trailer) for more details.
+    pub fn trailer<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&Stats, &mut Writer, &mut Vec<u8>) -> Result<()> + Send + 'static,
+    {
+        self.trailer = Some(Box::new(f));
+        self
+    }
+
+    /// Returns the number of records that have been dropped so far according
+    /// to the configured [`ErrorPolicy`](crate::ErrorPolicy).
+    pub fn skipped(&self) -> u64 {
+        self.writer.skipped()
+    }
+
+    /// Returns statistics about the data yielded so far.
+    ///
+    /// This does not include records that have been serialized as part of
+    /// the current batch, but not yielded yet.
+    pub fn stats(&self) -> Stats {
+        self.writer.stats()
+    }
+}
+
+impl<I: Iterator> Iterator for ParIter<I>
+where
+    I::Item: Serialize + Send,
+{
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut buf = vec![];
+            let res = if let Some(record) = self.batch.next() {
+                self.writer.merge(&mut buf, record)
+            } else if self.done {
+                return None;
+            } else if let Err(err) = self.writer.check_record_limit() {
+                Err(err)
+            } else if !self.writer.is_settled() {
+                let s = match self.iter.next() {
+                    Some(s) => s,
+                    None => {
+                        self.done = true;
+                        return self.writer.finish(vec![], self.trailer.take());
+                    }
+                };
+                self.writer.serialize(&mut buf, s)
+            } else {
+                let batch: Vec<I::Item> = self.iter.by_ref().take(self.batch_size).collect();
+                if batch.is_empty() {
+                    self.done = true;
+                    return self.writer.finish(vec![], self.trailer.take());
+                }
+                let writer = &self.writer;
+                self.batch = batch
+                    .into_par_iter()
+                    .map(|s| writer.serialize_detached(s))
+                    .collect::<Vec<_>>()
+                    .into_iter();
+                continue;
+            };
+
+            let err = match res {
+                Ok(()) => return Some(Ok(buf)),
+                Err(err) => err,
+            };
+            match self.writer.recover(&mut buf, err) {
+                Recovery::Yield(err) => return Some(Err(err)),
+                Recovery::Abort(err) => {
+                    self.done = true;
+                    self.batch = Vec::new().into_iter();
+                    return Some(Err(err));
+                }
+                Recovery::Skip => continue,
+                Recovery::Placeholder => return Some(Ok(buf)),
+                Recovery::Stop => {
+                    self.done = true;
+                    self.batch = Vec::new().into_iter();
+                    return self.writer.finish(buf, self.trailer.take());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{collect, rows, Fallible};
+    use crate::{ErrorKind, ErrorPolicy, WriterBuilder};
+
+    #[test]
+    fn same_as_iter() {
+        let builder = WriterBuilder::default();
+        let par_iter = builder.build_par_iter(rows(1000)).batch_size(64);
+        let iter = builder.build_iter(rows(1000));
+
+        assert_eq!(collect(par_iter), collect(iter));
+    }
+
+    #[test]
+    fn stats() {
+        let mut par_iter = WriterBuilder::default()
+            .build_par_iter(rows(100))
+            .batch_size(8);
+        assert!(par_iter.by_ref().all(|r| r.is_ok()));
+
+        let mut iter = WriterBuilder::default().build_iter(rows(100));
+        assert!(iter.by_ref().all(|r| r.is_ok()));
+
+        assert_eq!(par_iter.stats(), iter.stats());
+    }
+
+    #[test]
+    fn unequal_lengths() {
+        let rows = vec![vec![1, 2], vec![3, 4], vec![5, 6, 7], vec![8, 9]];
+        let mut par_iter = WriterBuilder::default().build_par_iter(rows).batch_size(8);
+
+        assert_eq!(par_iter.next().unwrap().unwrap(), b"1,2\n");
+        assert_eq!(par_iter.next().unwrap().unwrap(), b"3,4\n");
+        let err = par_iter.next().unwrap().unwrap_err();
+        assert_eq!(err.position().unwrap().record(), 2);
+        assert_eq!(err.position().unwrap().byte(), 8);
+        match *err.kind() {
+            ErrorKind::UnequalLengths { expected_len, len } => {
+                assert_eq!(expected_len, 2);
+                assert_eq!(len, 3);
+            }
+            ref x => panic!("expected ErrorKind::UnequalLengths but got '{:?}'", x),
+        }
+        assert_eq!(par_iter.next().unwrap().unwrap(), b"8,9\n");
+        assert!(par_iter.next().is_none());
+    }
+
+    #[test]
+    fn serialize_error_position() {
+        let rows = [
+            Fallible(Some(1)),
+            Fallible(Some(2)),
+            Fallible(None),
+            Fallible(Some(3)),
+        ];
+        let mut par_iter = WriterBuilder::default()
+            .error_policy(ErrorPolicy::Placeholder)
+            .build_par_iter(rows)
+            .batch_size(8);
+
+        assert_eq!(par_iter.next().unwrap().unwrap(), b"1,2\n");
+        assert_eq!(par_iter.next().unwrap().unwrap(), b"2,4\n");
+        assert_eq!(
+            par_iter.next().unwrap().unwrap(),
+            b"\"CSV write error: record 2 (byte: 8), field 0: missing value\",\n"
+        );
+        assert_eq!(par_iter.next().unwrap().unwrap(), b"3,6\n");
+        assert!(par_iter.next().is_none());
+        assert_eq!(par_iter.skipped(), 1);
+    }
+
+    #[test]
+    fn abort() {
+        let rows = vec![vec![1, 2], vec![3], vec![4, 5]];
+        let mut par_iter = WriterBuilder::default()
+            .error_policy(ErrorPolicy::Abort)
+            .build_par_iter(rows);
+
+        assert!(par_iter.next().unwrap().is_ok());
+        assert!(par_iter.next().unwrap().is_err());
+        assert!(par_iter.next().is_none());
+    }
+}
@@ -0,0 +1,580 @@
+//! Conversion of Postgres values in the binary format to their text format.
+//!
+//! `sqlx` receives query results in the binary format, and only decodes types
+//! such as `NUMERIC`, dates and times or `UUID` into Rust types behind
+//! features of its own. These values are written as Postgres would print
+//! them instead, with the `UTC` time zone that `sqlx` sets for the session.
+
+use std::fmt::Write as _;
+
+use sqlx::error::BoxDynError;
+
+const BOOL: u32 = 16;
+const BYTEA: u32 = 17;
+const CHAR: u32 = 18;
+const NAME: u32 = 19;
+const INT8: u32 = 20;
+const INT2: u32 = 21;
+const INT4: u32 = 23;
+const TEXT: u32 = 25;
+const OID: u32 = 26;
+const JSON: u32 = 114;
+const FLOAT4: u32 = 700;
+const FLOAT8: u32 = 701;
+const BPCHAR: u32 = 1042;
+const VARCHAR: u32 = 1043;
+const DATE: u32 = 1082;
+const TIME: u32 = 1083;
+const TIMESTAMP: u32 = 1114;
+const TIMESTAMPTZ: u32 = 1184;
+const INTERVAL: u32 = 1186;
+const TIMETZ: u32 = 1266;
+const NUMERIC: u32 = 1700;
+const UUID: u32 = 2950;
+const JSONB: u32 = 3802;
+
+/// Days from 1970-01-01 to the Postgres epoch of 2000-01-01.
+const EPOCH_DAYS: i64 = 10_957;
+const USECS_PER_DAY: i64 = 86_400_000_000;
+
+/// Returns the text format of a binary `value` of the type with `oid`, or
+/// `None` if the type is not supported.
+///
+/// Arrays are detected by their contents, so `oid` is not checked for them.
+pub fn to_text(oid: u32, is_array: bool, value: &[u8]) -> Result<Option<String>, BoxDynError> {
+    let mut out = String::new();
+    let supported = if is_array {
+        write_array(&mut out, value)?;
+        true
+    } else {
+        write_value(&mut out, oid, value)?
+    };
+    Ok(supported.then_some(out))
+}
+
+/// Write the text format of a single value, returning false if the type is
+/// not supported.
+fn write_value(out: &mut String, oid: u32, value: &[u8]) -> Result<bool, BoxDynError> {
+    let mut r = Reader(value);
+    match oid {
+        BOOL => out.push_str(if r.u8()? != 0 { "t" } else { "f" }),
+        BYTEA => {
+            out.push_str("\\x");
+            for b in r.rest() {
+                write!(out, "{:02x}", b)?;
+            }
+        }
+        CHAR => out.push(char::from(r.u8()?)),
+        INT2 => write!(out, "{}", r.i16()?)?,
+        INT4 => write!(out, "{}", r.i32()?)?,
+        INT8 => write!(out, "{}", r.i64()?)?,
+        OID => write!(out, "{}", r.u32()?)?,
+        FLOAT4 => {
+            let v = f32::from_bits(r.u32()?);
+            write_float(out, v.into(), v);
+        }
+        FLOAT8 => {
+            let v = f64::from_bits(r.u64()?);
+            write_float(out, v, v);
+        }
+        TEXT | NAME | BPCHAR | VARCHAR | JSON => out.push_str(std::str::from_utf8(r.rest())?),
+        JSONB => match r.u8()? {
+            1 => out.push_str(std::str::from_utf8(r.rest())?),
+            _ => return Err("unsupported JSONB version".into()),
+        },
+        DATE => match r.i32()? {
+            i32::MAX => out.push_str("infinity"),
+            i32::MIN => out.push_str("-infinity"),
+            days => {
+                let bc = write_date(out, i64::from(days));
+                if bc {
+                    out.push_str(" BC");
+                }
+            }
+        },
+        TIME => write_time(out, r.i64()?),
+        TIMETZ => {
+            write_time(out, r.i64()?);
+            write_zone(out, r.i32()?);
+        }
+        TIMESTAMP | TIMESTAMPTZ => match r.i64()? {
+            i64::MAX => out.push_str("infinity"),
+            i64::MIN => out.push_str("-infinity"),
+            usecs => {
+                let bc = write_date(out, usecs.div_euclid(USECS_PER_DAY));
+                out.push(' ');
+                write_time(out, usecs.rem_euclid(USECS_PER_DAY));
+                if oid == TIMESTAMPTZ {
+                    out.push_str("+00");
+                }
+                if bc {
+                    out.push_str(" BC");
+                }
+            }
+        },
+        INTERVAL => {
+            let (usecs, days, months) = (r.i64()?, r.i32()?, r.i32()?);
+            write_interval(out, usecs, days, months);
+        }
+        NUMERIC => write_numeric(out, &mut r)?,
+        UUID => {
+            for (i, b) in r.take(16)?.iter().enumerate() {
+                if matches!(i, 4 | 6 | 8 | 10) {
+                    out.push('-');
+                }
+                write!(out, "{:02x}", b)?;
+            }
+        }
+        _ => return Ok(false),
+    }
+    r.finish()?;
+    Ok(true)
+}
+
+/// Write a float the way Postgres does for the special values, and as the
+/// shortest representation that round trips, `display`, otherwise.
+fn write_float(out: &mut String, v: f64, display: impl std::fmt::Display) {
+    if v.is_nan() {
+        out.push_str("NaN");
+    } else if v.is_infinite() {
+        out.push_str(if v > 0.0 { "Infinity" } else { "-Infinity" });
+    } else {
+        let _ = write!(out, "{}", display);
+    }
+}
+
+/// Write the date `days` after the Postgres epoch, returning true if the
+/// year is before the common era.
+fn write_date(out: &mut String, days: i64) -> bool {
+    // Howard Hinnant's `civil_from_days`.
+    let z = days + EPOCH_DAYS + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    // There is no year zero, so 1 BC comes right before 1 AD.
+    let bc = year <= 0;
+    let year = if bc { 1 - year } else { year };
+    let _ = write!(out, "{:04}-{:02}-{:02}", year, month, day);
+    bc
+}
+
+/// Write a time of day given in microseconds.
+fn write_time(out: &mut String, usecs: i64) {
+    let secs = usecs / 1_000_000;
+    let _ = write!(out, "{:02}:{:02}:", secs / 3600, secs / 60 % 60);
+    write_seconds(out, secs % 60, usecs % 1_000_000);
+}
+
+/// Write seconds, followed by any fraction without trailing zeros.
+fn write_seconds(out: &mut String, secs: i64, usecs: i64) {
+    let _ = write!(out, "{:02}", secs);
+    if usecs != 0 {
+        let frac = format!("{:06}", usecs);
+        out.push('.');
+        out.push_str(frac.trim_end_matches('0'));
+    }
+}
+
+/// Write a time zone offset given in seconds west of UTC.
+fn write_zone(out: &mut String, zone: i32) {
+    out.push(if zone <= 0 { '+' } else { '-' });
+    let zone = zone.unsigned_abs();
+    let (hours, mins, secs) = (zone / 3600, zone / 60 % 60, zone % 60);
+    let _ = write!(out, "{:02}", hours);
+    if secs != 0 {
+        let _ = write!(out, ":{:02}:{:02}", mins, secs);
+    } else if mins != 0 {
+        let _ = write!(out, ":{:02}", mins);
+    }
+}
+
+/// Write an interval in the default `postgres` interval style, such as
+/// `1 year 2 mons -3 days +04:05:06.5`.
+fn write_interval(out: &mut String, usecs: i64, days: i32, months: i32) {
+    let mut is_zero = true;
+    let mut is_before = false;
+    let parts = [(months / 12, "year"), (months % 12, "mon"), (days, "day")];
+    for (value, unit) in parts {
+        if value == 0 {
+            continue;
+        }
+        let _ = write!(
+            out,
+            "{}{}{} {}{}",
+            if is_zero { "" } else { " " },
+            if is_before && value > 0 { "+" } else { "" },
+            value,
+            unit,
+            if value == 1 { "" } else { "s" },
+        );
+        is_before = value < 0;
+        is_zero = false;
+    }
+
+    if is_zero || usecs != 0 {
+        let secs = (usecs / 1_000_000).unsigned_abs();
+        let _ = write!(
+            out,
+            "{}{}{:02}:{:02}:",
+            if is_zero { "" } else { " " },
+            if usecs < 0 {
+                "-"
+            } else if is_before {
+                "+"
+            } else {
+                ""
+            },
+            secs / 3600,
+            secs / 60 % 60,
+        );
+        write_seconds(out, (secs % 60) as i64, (usecs % 1_000_000).abs());
+    }
+}
+
+/// Write a `NUMERIC`, which is stored as base 10000 digits.
+fn write_numeric(out: &mut String, r: &mut Reader<'_>) -> Result<(), BoxDynError> {
+    let ndigits = r.i16()?;
+    let weight = i32::from(r.i16()?);
+    let sign = r.u16()?;
+    let dscale = i32::from(r.i16()?);
+    let digits = (0..ndigits)
+        .map(|_| r.i16())
+        .collect::<Result<Vec<_>, _>>()?;
+    let digit = |i: i32| usize::try_from(i).ok().and_then(|i| digits.get(i)).copied();
+
+    let special = match sign {
+        0x0000 => None,
+        0x4000 => {
+            out.push('-');
+            None
+        }
+        0xC000 => Some("NaN"),
+        0xD000 => Some("Infinity"),
+        0xF000 => Some("-Infinity"),
+        _ => return Err("invalid NUMERIC sign".into()),
+    };
+    if let Some(special) = special {
+        out.push_str(special);
+        return Ok(());
+    }
+
+    if weight < 0 {
+        out.push('0');
+    }
+    for i in 0..=weight {
+        let d = digit(i).unwrap_or(0);
+        if i == 0 {
+            write!(out, "{}", d)?;
+        } else {
+            write!(out, "{:04}", d)?;
+        }
+    }
+
+    if dscale > 0 {
+        out.push('.');
+        let start = out.len();
+        let mut i = weight + 1;
+        while out.len() - start < dscale as usize {
+            write!(out, "{:04}", digit(i).unwrap_or(0))?;
+            i += 1;
+        }
+        out.truncate(start + dscale as usize);
+    }
+    Ok(())
+}
+
+/// Write an array in the Postgres text format, such as `{1,NULL,3}` or
+/// `[0:1]={"a b",c}`.
+fn write_array(out: &mut String, value: &[u8]) -> Result<(), BoxDynError> {
+    let mut r = Reader(value);
+    let ndim = r.i32()?;
+    let _has_null = r.i32()?;
+    let oid = r.u32()?;
+    let dims = (0..ndim)
+        .map(|_| Ok((r.i32()?, r.i32()?)))
+        .collect::<Result<Vec<_>, BoxDynError>>()?;
+
+    if dims.iter().any(|&(_, lower)| lower != 1) {
+        for &(len, lower) in &dims {
+            write!(out, "[{}:{}]", lower, lower + len - 1)?;
+        }
+        out.push('=');
+    }
+    if dims.is_empty() || dims.iter().any(|&(len, _)| len == 0) {
+        out.push_str("{}");
+    } else {
+        let lens = dims.iter().map(|&(len, _)| len).collect::<Vec<_>>();
+        write_elements(out, &mut r, oid, &lens)?;
+    }
+    r.finish()
+}
+
+/// Write the elements of an array with dimensions `lens`.
+fn write_elements(
+    out: &mut String,
+    r: &mut Reader<'_>,
+    oid: u32,
+    lens: &[i32],
+) -> Result<(), BoxDynError> {
+    out.push('{');
+    for i in 0..lens[0] {
+        if i > 0 {
+            out.push(',');
+        }
+        if lens.len() > 1 {
+            write_elements(out, r, oid, &lens[1..])?;
+            continue;
+        }
+
+        let len = r.i32()?;
+        if len < 0 {
+            out.push_str("NULL");
+            continue;
+        }
+        let mut element = String::new();
+        if !write_value(&mut element, oid, r.take(len as usize)?)? {
+            return Err(format!("unsupported SQL type with OID {} in array", oid).into());
+        }
+        write_array_element(out, &element);
+    }
+    out.push('}');
+    Ok(())
+}
+
+/// Write an array element, quoting it if it would be ambiguous otherwise.
+fn write_array_element(out: &mut String, element: &str) {
+    let needs_quotes = element.is_empty()
+        || element.eq_ignore_ascii_case("NULL")
+        || element.chars().any(|c| {
+            matches!(
+                c,
+                '"' | '\\' | '{' | '}' | ',' | ' ' | '\t' | '\n' | '\r' | '\x0b' | '\x0c'
+            )
+        });
+    if !needs_quotes {
+        out.push_str(element);
+        return;
+    }
+    out.push('"');
+    for c in element.chars() {
+        if matches!(c, '"' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+}
+
+/// Reads big endian values from a binary value.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], BoxDynError> {
+        if self.0.len() < len {
+            return Err("unexpected end of binary value".into());
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.0)
+    }
+
+    fn finish(&self) -> Result<(), BoxDynError> {
+        match self.0 {
+            [] => Ok(()),
+            _ => Err("unexpected data at the end of binary value".into()),
+        }
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], BoxDynError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, BoxDynError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn i16(&mut self) -> Result<i16, BoxDynError> {
+        self.array().map(i16::from_be_bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, BoxDynError> {
+        self.array().map(u16::from_be_bytes)
+    }
+
+    fn i32(&mut self) -> Result<i32, BoxDynError> {
+        self.array().map(i32::from_be_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, BoxDynError> {
+        self.array().map(u32::from_be_bytes)
+    }
+
+    fn i64(&mut self) -> Result<i64, BoxDynError> {
+        self.array().map(i64::from_be_bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64, BoxDynError> {
+        self.array().map(u64::from_be_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numeric() {
+        // 12345.678 is the base 10000 digits 1, 2345 and 6780.
+        let mut value = vec![0, 3, 0, 1, 0, 0, 0, 3];
+        value.extend([0, 1, 0x09, 0x29, 0x1a, 0x7c]);
+        assert_eq!(
+            to_text(NUMERIC, false, &value).unwrap().unwrap(),
+            "12345.678"
+        );
+
+        // -0.00012 is the digits 1 and 2000 after the decimal point, with a
+        // scale longer than its digits.
+        let value = [0, 2, 0xff, 0xff, 0x40, 0, 0, 6, 0, 1, 0x07, 0xd0];
+        assert_eq!(
+            to_text(NUMERIC, false, &value).unwrap().unwrap(),
+            "-0.000120"
+        );
+
+        let value = [0, 0, 0, 0, 0xc0, 0, 0, 0];
+        assert_eq!(to_text(NUMERIC, false, &value).unwrap().unwrap(), "NaN");
+    }
+
+    #[test]
+    fn dates() {
+        let date = |days: i32| to_text(DATE, false, &days.to_be_bytes()).unwrap().unwrap();
+        assert_eq!(date(0), "2000-01-01");
+        assert_eq!(date(-1), "1999-12-31");
+        assert_eq!(date(59), "2000-02-29");
+        assert_eq!(date(-730_119), "0001-01-01");
+        assert_eq!(date(-730_120), "0001-12-31 BC");
+        assert_eq!(date(i32::MAX), "infinity");
+    }
+
+    #[test]
+    fn array() {
+        // A one dimensional `TEXT[]` of 'a b', NULL and ''.
+        let mut value = vec![];
+        for int in [1, 1, TEXT as i32, 3, 1, 3] {
+            value.extend(int.to_be_bytes());
+        }
+        value.extend(b"a b");
+        value.extend((-1i32).to_be_bytes());
+        value.extend(0i32.to_be_bytes());
+        assert_eq!(
+            to_text(0, true, &value).unwrap().unwrap(),
+            r#"{"a b",NULL,""}"#
+        );
+
+        // Trailing data is an error rather than silently ignored.
+        value.push(0);
+        assert!(to_text(0, true, &value).is_err());
+    }
+
+    #[test]
+    fn unsupported() {
+        // `POINT`
+        assert!(to_text(600, false, &[0; 16]).unwrap().is_none());
+    }
+
+    /// Check values against the text format of a Postgres server at
+    /// `DATABASE_URL`.
+    #[tokio::test]
+    #[ignore = "requires a Postgres server at DATABASE_URL"]
+    async fn server_text_format() {
+        use futures::TryStreamExt;
+        use sqlx::{Connection, PgConnection};
+
+        use crate::{QuoteStyle, WriterBuilder};
+
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+
+        let exprs = [
+            "12345.678::numeric",
+            "-0.000120::numeric",
+            "10000000000000000000000::numeric",
+            "0::numeric(10, 2)",
+            "'NaN'::numeric",
+            "'-Infinity'::numeric",
+            "'2024-02-29'::date",
+            "'0044-03-15 BC'::date",
+            "'infinity'::date",
+            "'13:14:15.5'::time",
+            "'00:00:00'::time",
+            "'13:14:15+05:30'::timetz",
+            "'13:14:15-08'::timetz",
+            "'2024-01-02 03:04:05.000001'::timestamp",
+            "'1999-12-31 23:59:59 BC'::timestamp",
+            "'2024-01-02 03:04:05+02'::timestamptz",
+            "'-infinity'::timestamptz",
+            "'1 year 2 mons 3 days 04:05:06.5'::interval",
+            "'-1 days +02:00:00'::interval",
+            "'-3 hours -4 minutes'::interval",
+            "'1 mon -1 day'::interval",
+            "'0'::interval",
+            "'a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11'::uuid",
+            "ARRAY[1, NULL, 3]",
+            "ARRAY[['a b', ''], ['NULL', 'x\"y\\z']]",
+            "'[0:1]={1.5,2}'::numeric[]",
+            "'{}'::int4[]",
+            "ARRAY[true, false]",
+            "ARRAY['\\x6869'::bytea]",
+            "ARRAY['2024-01-02'::date]",
+            "ARRAY[1.5::float8, 'Infinity']",
+            "ARRAY['{\"a\": 1}'::jsonb]",
+        ];
+        for expr in exprs {
+            let query = format!("SELECT {0} AS v, ({0})::text AS t", expr);
+            let rows = sqlx::query(&query).fetch(&mut conn);
+            let csv_stream = WriterBuilder::default()
+                .has_headers(false)
+                .delimiter(b'\t')
+                .quote_style(QuoteStyle::Never)
+                .build_row_stream(rows);
+            let buf: Vec<Vec<u8>> = csv_stream.try_collect().await.unwrap();
+            let line = String::from_utf8(buf.concat()).unwrap();
+            let (value, text) = line.trim_end().split_once('\t').unwrap();
+            assert_eq!(value, text, "{}", expr);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a Postgres server at DATABASE_URL"]
+    async fn server_unsupported() {
+        use futures::StreamExt;
+        use sqlx::{Connection, PgConnection};
+
+        use crate::{ErrorPolicy, WriterBuilder};
+
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let rows = sqlx::query("SELECT 1 AS id, point(1, 2) AS p").fetch(&mut conn);
+        let csv_stream = WriterBuilder::default()
+            .error_policy(ErrorPolicy::Abort)
+            .build_row_stream(rows);
+
+        let rows: Vec<_> = csv_stream.collect().await;
+        assert_eq!(rows.len(), 1);
+        let err = rows[0].as_ref().unwrap_err();
+        assert!(
+            err.to_string().contains("unsupported SQL type POINT"),
+            "{}",
+            err
+        );
+    }
+}
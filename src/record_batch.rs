@@ -0,0 +1,597 @@
+use arrow::array::{Array, RecordBatch};
+use arrow::datatypes::DataType;
+use arrow::error::ArrowError;
+use arrow::util::display::{ArrayFormatter, FormatOptions};
+
+use crate::error::{Error, ErrorKind};
+use crate::writer::Recovery;
+use crate::{Result, Stats, Writer};
+
+/// How Arrow values are formatted as CSV fields.
+#[derive(Clone, Debug, Default)]
+struct Formats {
+    null: String,
+    date: Option<String>,
+    timestamp: Option<String>,
+    time: Option<String>,
+}
+
+impl Formats {
+    fn options(&self) -> FormatOptions<'_> {
+        FormatOptions::new()
+            .with_display_error(false)
+            .with_null(&self.null)
+            .with_date_format(self.date.as_deref())
+            .with_datetime_format(self.timestamp.as_deref())
+            .with_timestamp_format(self.timestamp.as_deref())
+            .with_timestamp_tz_format(self.timestamp.as_deref())
+            .with_time_format(self.time.as_deref())
+    }
+}
+
+/// Whether values of `data_type` are written like numbers and booleans
+/// serialized with Serde, rather than like strings, so that they are not
+/// quoted by `QuoteStyle::Strings`.
+fn is_scalar(data_type: &DataType) -> bool {
+    match data_type {
+        DataType::Boolean | DataType::Null => true,
+        DataType::Dictionary(_, values) => is_scalar(values),
+        data_type => data_type.is_numeric(),
+    }
+}
+
+/// Attach the column a formatting error occurred in.
+fn format_error(err: ArrowError, column: usize, name: &str) -> Error {
+    let err = Error::new(ErrorKind::Serialize(err.to_string()));
+    err.or_field(column as u64, Some(name))
+}
+
+/// Write all rows of a batch, along with the header row if it has not been
+/// written yet.
+///
+/// If this fails, nothing of the batch is written, except when a limit is
+/// reached: then the rows before it are kept, and the error is held back in
+/// `pending` until they have been yielded. Once a limit has ended the output,
+/// `done` is set and the rows before it are the last item.
+fn write_batch(
+    writer: &mut Writer,
+    formats: &Formats,
+    batch: &RecordBatch,
+    pending: &mut Option<Error>,
+    done: &mut bool,
+) -> Option<Result<Vec<u8>>> {
+    let mut buf = vec![];
+    let start = writer.checkpoint(&buf);
+    let err = match write_batch_impl(writer, formats, batch, &mut buf) {
+        Ok(()) => return Some(Ok(buf)),
+        Err(err) => err,
+    };
+
+    if writer.is_stopped() {
+        *done = true;
+        if let Recovery::Abort(err) = writer.recover(&mut buf, err) {
+            return Some(Err(err));
+        }
+        return (!buf.is_empty()).then_some(Ok(buf));
+    }
+    if matches!(err.kind(), ErrorKind::LimitExceeded(_)) && !buf.is_empty() {
+        *pending = Some(err);
+        return Some(Ok(buf));
+    }
+    writer.restore(&mut buf, start);
+    Some(Err(err))
+}
+
+/// Write the rows of a batch to `buf`.
+///
+/// If this fails, the header and row that failed are removed from `buf`, but
+/// any rows before it are left in place.
+fn write_batch_impl(
+    writer: &mut Writer,
+    formats: &Formats,
+    batch: &RecordBatch,
+    buf: &mut Vec<u8>,
+) -> Result<()> {
+    let schema = batch.schema();
+    let names = schema.fields().iter().map(|field| field.name());
+    let checkpoint = writer.checkpoint(buf);
+    writer.write_header(buf, names).inspect_err(|_| {
+        writer.restore(buf, checkpoint);
+    })?;
+
+    let options = formats.options();
+    let formatters = batch
+        .columns()
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            ArrayFormatter::try_new(column.as_ref(), &options)
+                .map_err(|err| format_error(err, i, schema.field(i).name()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let scalars: Vec<bool> = schema
+        .fields()
+        .iter()
+        .map(|field| is_scalar(field.data_type()))
+        .collect();
+
+    let mut field = String::new();
+    for row in 0..batch.num_rows() {
+        let checkpoint = writer.checkpoint(buf);
+        let res = formatters
+            .iter()
+            .enumerate()
+            .try_for_each(|(i, formatter)| {
+                field.clear();
+                formatter.value(row).write(&mut field).map_err(|err| {
+                    format_error(err, i, schema.field(i).name()).or_position(writer.position())
+                })?;
+                // Nulls are written like missing values.
+                if scalars[i] || batch.column(i).is_null(row) {
+                    writer.write_scalar(buf, &field)
+                } else {
+                    writer.write_field(buf, &field)
+                }
+            });
+        res.and_then(|()| writer.write_record(buf, None::<&[u8]>))
+            .inspect_err(|_| writer.restore(buf, checkpoint))?;
+    }
+    Ok(())
+}
+
+/// An iterable CSV creator for Arrow record batches
+///
+/// Each batch is written column by column, straight from the Arrow arrays,
+/// and yielded as a single item. The header row is taken from the schema of
+/// the first batch.
+///
+/// Values are formatted with Arrow's display formatting, which supports all
+/// Arrow data types, including dates, timestamps, decimals and
+/// dictionaries. Nulls are written as empty fields by default.
+///
+/// If a batch fails to be written, the error is yielded in its place, none
+/// of its rows are written, and writing carries on with the next batch. If a
+/// limit is reached part way through a batch, the rows before it are yielded
+/// first, followed by the error. A limit with `LimitPolicy::Stop` or
+/// `LimitPolicy::Truncate` ends the output after those rows instead.
+///
+/// # Example
+///
+/// ```
+/// use std::error::Error;
+/// use std::sync::Arc;
+/// use arrow::array::{Int32Array, RecordBatch, StringArray};
+/// use csv_stream::WriterBuilder;
+///
+/// # fn main() { example().unwrap(); }
+/// fn example() -> Result<(), Box<dyn Error>> {
+///     let batch = RecordBatch::try_from_iter([
+///         ("foo", Arc::new(Int32Array::from(vec![Some(1), None])) as _),
+///         ("bar", Arc::new(StringArray::from(vec!["a", "b,c"])) as _),
+///     ])?;
+///
+///     let mut buf = vec![];
+///     for batch in WriterBuilder::default().build_record_batch_iter([batch]) {
+///         buf.extend_from_slice(&batch?);
+///     }
+///
+///     let data = String::from_utf8(buf)?;
+///     assert_eq!(data, "foo,bar\n1,a\n,\"b,c\"\n");
+///     Ok(())
+/// }
+/// ```
+pub struct RecordBatchIter<I> {
+    iter: I,
+
+    writer: Writer,
+    formats: Formats,
+    /// An error to yield after the rows written before it.
+    pending: Option<Error>,
+    /// Set once a limit has ended the output.
+    done: bool,
+}
+
+impl<I: Iterator> RecordBatchIter<I> {
+    pub fn new(iter: impl IntoIterator<IntoIter = I>, writer: Writer) -> Self {
+        Self {
+            iter: iter.into_iter(),
+            writer,
+            formats: Formats::default(),
+            pending: None,
+            done: false,
+        }
+    }
+}
+
+impl<I> RecordBatchIter<I> {
+    /// Set the string to write for null values.
+    ///
+    /// The default is an empty string.
+    pub fn null_value(mut self, null: impl Into<String>) -> Self {
+        self.formats.null = null.into();
+        self
+    }
+
+    /// Set the `chrono` format string to write dates with.
+    ///
+    /// The default is ISO 8601, such as `2022-01-31`.
+    pub fn date_format(mut self, format: impl Into<String>) -> Self {
+        self.formats.date = Some(format.into());
+        self
+    }
+
+    /// Set the `chrono` format string to write timestamps with, with or
+    /// without a time zone.
+    ///
+    /// The default is RFC 3339, such as `2022-01-31T12:30:00Z`.
+    pub fn timestamp_format(mut self, format: impl Into<String>) -> Self {
+        self.formats.timestamp = Some(format.into());
+        self
+    }
+
+    /// Set the `chrono` format string to write times of day with.
+    ///
+    /// The default is ISO 8601, such as `12:30:00`.
+    pub fn time_format(mut self, format: impl Into<String>) -> Self {
+        self.formats.time = Some(format.into());
+        self
+    }
+
+    /// Returns statistics about the data written so far.
+    pub fn stats(&self) -> Stats {
+        self.writer.stats()
+    }
+}
+
+impl<I: Iterator<Item = RecordBatch>> Iterator for RecordBatchIter<I> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(err) = self.pending.take() {
+            return Some(Err(err));
+        }
+        if self.done {
+            return None;
+        }
+        let batch = self.iter.next()?;
+        write_batch(
+            &mut self.writer,
+            &self.formats,
+            &batch,
+            &mut self.pending,
+            &mut self.done,
+        )
+    }
+}
+
+#[cfg(feature = "stream")]
+pub use self::stream::RecordBatchStream;
+
+#[cfg(feature = "stream")]
+mod stream {
+    use arrow::array::RecordBatch;
+    use pin_project::pin_project;
+
+    use super::{write_batch, Formats};
+    use crate::{Error, Result, Stats, Writer};
+
+    /// A Streamable CSV creator for Arrow record batches
+    ///
+    /// See [`RecordBatchIter`](crate::RecordBatchIter) for more details.
+    #[pin_project]
+    pub struct RecordBatchStream<S> {
+        #[pin]
+        stream: S,
+
+        writer: Writer,
+        formats: Formats,
+        /// An error to yield after the rows written before it.
+        pending: Option<Error>,
+        /// Set once a limit has ended the output.
+        done: bool,
+    }
+
+    impl<S> RecordBatchStream<S> {
+        pub fn new(stream: S, writer: Writer) -> Self {
+            Self {
+                stream,
+                writer,
+                formats: Formats::default(),
+                pending: None,
+                done: false,
+            }
+        }
+
+        /// Set the string to write for null values.
+        ///
+        /// The default is an empty string.
+        pub fn null_value(mut self, null: impl Into<String>) -> Self {
+            self.formats.null = null.into();
+            self
+        }
+
+        /// Set the `chrono` format string to write dates with.
+        ///
+        /// The default is ISO 8601, such as `2022-01-31`.
+        pub fn date_format(mut self, format: impl Into<String>) -> Self {
+            self.formats.date = Some(format.into());
+            self
+        }
+
+        /// Set the `chrono` format string to write timestamps with, with or
+        /// without a time zone.
+        ///
+        /// The default is RFC 3339, such as `2022-01-31T12:30:00Z`.
+        pub fn timestamp_format(mut self, format: impl Into<String>) -> Self {
+            self.formats.timestamp = Some(format.into());
+            self
+        }
+
+        /// Set the `chrono` format string to write times of day with.
+        ///
+        /// The default is ISO 8601, such as `12:30:00`.
+        pub fn time_format(mut self, format: impl Into<String>) -> Self {
+            self.formats.time = Some(format.into());
+            self
+        }
+
+        /// Returns statistics about the data written so far.
+        pub fn stats(&self) -> Stats {
+            self.writer.stats()
+        }
+    }
+
+    impl<S> futures::Stream for RecordBatchStream<S>
+    where
+        S: futures::Stream<Item = RecordBatch>,
+    {
+        type Item = Result<Vec<u8>>;
+
+        fn poll_next(
+            self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Option<Self::Item>> {
+            let p = self.project();
+            if let Some(err) = p.pending.take() {
+                return std::task::Poll::Ready(Some(Err(err)));
+            }
+            if *p.done {
+                return std::task::Poll::Ready(None);
+            }
+            let batch = match futures::ready!(p.stream.poll_next(cx)) {
+                Some(batch) => batch,
+                None => return std::task::Poll::Ready(None),
+            };
+            std::task::Poll::Ready(write_batch(p.writer, p.formats, &batch, p.pending, p.done))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use arrow::array::{
+        ArrayRef, BooleanArray, Date32Array, Decimal128Array, DictionaryArray, Float64Array,
+        Int32Array, RecordBatch, StringArray, TimestampMillisecondArray,
+    };
+    use arrow::datatypes::Int8Type;
+
+    use crate::test_util::collect;
+    use crate::{ErrorKind, Limit, LimitPolicy, QuoteStyle, WriterBuilder};
+
+    fn batch(columns: Vec<(&str, ArrayRef)>) -> RecordBatch {
+        RecordBatch::try_from_iter(columns).unwrap()
+    }
+
+    #[test]
+    fn data_types() {
+        let dict: DictionaryArray<Int8Type> = vec!["x", "y", "x"].into_iter().collect();
+        let batch = batch(vec![
+            (
+                "int",
+                Arc::new(Int32Array::from(vec![Some(1), None, Some(-3)])),
+            ),
+            ("float", Arc::new(Float64Array::from(vec![0.5, 1.0, 1e20]))),
+            (
+                "decimal",
+                Arc::new(
+                    Decimal128Array::from(vec![12345, -5, 0])
+                        .with_precision_and_scale(10, 2)
+                        .unwrap(),
+                ),
+            ),
+            ("date", Arc::new(Date32Array::from(vec![0, 19023, 1]))),
+            (
+                "timestamp",
+                Arc::new(TimestampMillisecondArray::from(vec![
+                    0,
+                    1_643_632_200_000,
+                    1,
+                ])),
+            ),
+            ("dict", Arc::new(dict)),
+        ]);
+
+        let data = collect(WriterBuilder::default().build_record_batch_iter([batch]));
+        assert_eq!(
+            data,
+            "int,float,decimal,date,timestamp,dict\n\
+             1,0.5,123.45,1970-01-01,1970-01-01T00:00:00,x\n\
+             ,1.0,-0.05,2022-01-31,2022-01-31T12:30:00,y\n\
+             -3,1e20,0.00,1970-01-02,1970-01-01T00:00:00.001,x\n"
+        );
+    }
+
+    #[test]
+    fn formats() {
+        let dates = batch(vec![
+            (
+                "int",
+                Arc::new(Int32Array::from(vec![None, Some(1)])) as ArrayRef,
+            ),
+            ("date", Arc::new(Date32Array::from(vec![Some(19023), None]))),
+        ]);
+        let iter = WriterBuilder::default()
+            .quote_style(QuoteStyle::Always)
+            .build_record_batch_iter([dates])
+            .null_value("NULL")
+            .date_format("%d/%m/%Y");
+
+        assert_eq!(
+            collect(iter),
+            "\"int\",\"date\"\n\"NULL\",\"31/01/2022\"\n\"1\",\"NULL\"\n"
+        );
+
+        let scalars = batch(vec![
+            (
+                "int",
+                Arc::new(Int32Array::from(vec![None, Some(1)])) as ArrayRef,
+            ),
+            ("float", Arc::new(Float64Array::from(vec![1.5, -2.0]))),
+            ("bool", Arc::new(BooleanArray::from(vec![true, false]))),
+            (
+                "decimal",
+                Arc::new(
+                    Decimal128Array::from(vec![1234, -5])
+                        .with_precision_and_scale(10, 2)
+                        .unwrap(),
+                ),
+            ),
+            ("str", Arc::new(StringArray::from(vec![Some("a"), None]))),
+            ("date", Arc::new(Date32Array::from(vec![Some(19023), None]))),
+        ]);
+        let iter = WriterBuilder::default()
+            .quote_style(QuoteStyle::Strings)
+            .build_record_batch_iter([scalars]);
+
+        assert_eq!(
+            collect(iter),
+            "\"int\",\"float\",\"bool\",\"decimal\",\"str\",\"date\"\n\
+             ,1.5,true,12.34,\"a\",\"2022-01-31\"\n\
+             1,-2.0,false,-0.05,,\n"
+        );
+    }
+
+    #[test]
+    fn multiple_batches() {
+        let batches = (0..3).map(|i| {
+            batch(vec![
+                ("a", Arc::new(Int32Array::from(vec![i, i * 10])) as ArrayRef),
+                ("b", Arc::new(StringArray::from(vec!["x", "y,z"]))),
+            ])
+        });
+        let mut iter = WriterBuilder::default().build_record_batch_iter(batches);
+
+        assert_eq!(iter.next().unwrap().unwrap(), b"a,b\n0,x\n0,\"y,z\"\n");
+        assert_eq!(iter.next().unwrap().unwrap(), b"1,x\n10,\"y,z\"\n");
+        assert_eq!(iter.next().unwrap().unwrap(), b"2,x\n20,\"y,z\"\n");
+        assert!(iter.next().is_none());
+
+        let stats = iter.stats();
+        assert_eq!(stats.records(), 6);
+        assert_eq!(stats.fields(), 12);
+    }
+
+    #[test]
+    fn unequal_lengths() {
+        let batches = [
+            batch(vec![("a", Arc::new(Int32Array::from(vec![1])) as ArrayRef)]),
+            batch(vec![
+                ("a", Arc::new(Int32Array::from(vec![2])) as ArrayRef),
+                ("b", Arc::new(Int32Array::from(vec![3]))),
+            ]),
+            batch(vec![("a", Arc::new(Int32Array::from(vec![4])) as ArrayRef)]),
+        ];
+        let mut iter = WriterBuilder::default().build_record_batch_iter(batches);
+
+        assert_eq!(iter.next().unwrap().unwrap(), b"a\n1\n");
+        assert!(iter.next().unwrap().is_err());
+        assert_eq!(iter.next().unwrap().unwrap(), b"4\n");
+        assert_eq!(iter.stats().records(), 2);
+    }
+
+    fn int_batches(rows: &[&[i32]]) -> Vec<RecordBatch> {
+        rows.iter()
+            .map(|rows| batch(vec![("a", Arc::new(Int32Array::from(rows.to_vec())) as _)]))
+            .collect()
+    }
+
+    #[test]
+    fn limit_error() {
+        let batches = int_batches(&[&[1, 2], &[3, 4], &[5]]);
+        let mut iter = WriterBuilder::default()
+            .max_records(3, LimitPolicy::Error)
+            .build_record_batch_iter(batches);
+
+        assert_eq!(iter.next().unwrap().unwrap(), b"a\n1\n2\n");
+        // The row under the limit is kept, and the error follows it.
+        assert_eq!(iter.next().unwrap().unwrap(), b"3\n");
+        let err = iter.next().unwrap().unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::LimitExceeded(Limit::Records(3))
+        ));
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+        assert_eq!(iter.stats().records(), 3);
+    }
+
+    #[test]
+    fn limit_stop() {
+        let batches = int_batches(&[&[1, 2], &[3, 4], &[5]]);
+        let iter = WriterBuilder::default()
+            .comment(b'#')
+            .max_records(3, LimitPolicy::Stop)
+            .build_record_batch_iter(batches);
+
+        let items: Vec<_> = iter.collect();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].as_ref().unwrap(), b"a\n1\n2\n");
+        assert_eq!(
+            items[1].as_ref().unwrap(),
+            b"3\n#truncated: more than the maximum of 3 records\n"
+        );
+    }
+
+    #[test]
+    fn limit_truncate_bytes() {
+        let batches = int_batches(&[&[1, 2], &[3, 4], &[5]]);
+        let mut iter = WriterBuilder::default()
+            .max_bytes(7, LimitPolicy::Truncate)
+            .build_record_batch_iter(batches);
+
+        assert_eq!(iter.next().unwrap().unwrap(), b"a\n1\n2\n");
+        assert!(iter.next().is_none());
+        assert!(iter.next().is_none());
+    }
+
+    #[cfg(feature = "stream")]
+    #[tokio::test]
+    async fn stream_limit_stop() {
+        use futures::StreamExt;
+
+        let batches = int_batches(&[&[1], &[2, 3], &[4]]);
+        let stream = WriterBuilder::default()
+            .max_records(2, LimitPolicy::Truncate)
+            .build_record_batch_stream(futures::stream::iter(batches));
+        let buf: Vec<u8> = stream.map(Result::unwrap).concat().await;
+
+        assert_eq!(buf, b"a\n1\n2\n");
+    }
+
+    #[cfg(feature = "stream")]
+    #[tokio::test]
+    async fn stream() {
+        use futures::StreamExt;
+
+        let batches =
+            (0..2).map(|i| batch(vec![("a", Arc::new(Int32Array::from(vec![i])) as ArrayRef)]));
+        let stream =
+            WriterBuilder::default().build_record_batch_stream(futures::stream::iter(batches));
+        let buf: Vec<u8> = stream.map(Result::unwrap).concat().await;
+
+        assert_eq!(buf, b"a\n0\n1\n");
+    }
+}
@@ -0,0 +1,261 @@
+use std::fmt::Write;
+
+use axum::body::Body;
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::HeaderValue;
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+
+/// An axum response that streams CSV data
+///
+/// This is created from an [`Iter`](crate::Iter) or a
+/// [`Stream`](crate::Stream), and sets the `Content-Type` to `text/csv`,
+/// with the `header` parameter derived from whether the writer writes a
+/// header row. The `charset` parameter is `utf-8`, unless the delimiter or
+/// another special character of the writer is not ASCII, in which case it is
+/// left out. Set a [`charset`](CsvResponse::charset) to override this, such
+/// as for bytes fields that are not UTF-8, and a
+/// [`filename`](CsvResponse::filename) to send the data as a download.
+///
+/// Records are written to the response body as they are produced. An error
+/// while writing a record ends the body early, which aborts the response.
+///
+/// # Example
+///
+/// ```
+/// use csv_stream::{CsvResponse, WriterBuilder};
+/// use serde::Serialize;
+///
+/// #[derive(Serialize)]
+/// struct Row { foo: usize, bar: usize }
+///
+/// async fn handler() -> CsvResponse {
+///     let rows = futures::stream::iter((0..10).map(|i| Row { foo: i, bar: i * 2 }));
+///     let stream = WriterBuilder::default().build_stream(rows);
+///     CsvResponse::from(stream).filename("rows.csv")
+/// }
+///
+/// let app: axum::Router = axum::Router::new().route("/rows.csv", axum::routing::get(handler));
+/// ```
+#[derive(Debug)]
+pub struct CsvResponse {
+    body: Body,
+    has_headers: bool,
+    charset: Option<String>,
+    filename: Option<String>,
+}
+
+impl CsvResponse {
+    /// Set the `charset` parameter of the `Content-Type`, or leave it out
+    /// with `None`, such as for bytes fields written as they are that may
+    /// not be UTF-8.
+    ///
+    /// A charset that is not a valid header value is left out.
+    pub fn charset(mut self, charset: Option<&str>) -> Self {
+        self.charset = charset.map(str::to_owned);
+        self
+    }
+
+    /// Send the data as an attachment with the given filename.
+    ///
+    /// Filenames that are not plain ASCII are sent with an ASCII fallback,
+    /// as well as in full using RFC 5987 encoding.
+    pub fn filename(mut self, filename: impl Into<String>) -> Self {
+        self.filename = Some(filename.into());
+        self
+    }
+}
+
+impl<S> From<crate::Stream<S>> for CsvResponse
+where
+    S: futures::Stream + Send + 'static,
+    S::Item: Serialize,
+{
+    fn from(stream: crate::Stream<S>) -> Self {
+        CsvResponse {
+            has_headers: stream.writer().has_headers(),
+            charset: utf8_charset(stream.writer()),
+            body: Body::from_stream(stream),
+            filename: None,
+        }
+    }
+}
+
+impl<I> From<crate::Iter<I>> for CsvResponse
+where
+    I: Iterator + Send + 'static,
+    I::Item: Serialize,
+{
+    fn from(iter: crate::Iter<I>) -> Self {
+        CsvResponse {
+            has_headers: iter.writer().has_headers(),
+            charset: utf8_charset(iter.writer()),
+            body: Body::from_stream(futures::stream::iter(iter)),
+            filename: None,
+        }
+    }
+}
+
+impl IntoResponse for CsvResponse {
+    fn into_response(self) -> Response {
+        let header = if self.has_headers {
+            "header=present"
+        } else {
+            "header=absent"
+        };
+        let content_type = self
+            .charset
+            .and_then(|charset| {
+                let value = format!("text/csv; charset={}; {}", charset, header);
+                HeaderValue::try_from(value).ok()
+            })
+            .unwrap_or_else(|| {
+                HeaderValue::try_from(format!("text/csv; {}", header))
+                    .expect("header value is visible ASCII")
+            });
+
+        let mut res = Response::new(self.body);
+        let headers = res.headers_mut();
+        headers.insert(CONTENT_TYPE, content_type);
+        if let Some(filename) = self.filename {
+            headers.insert(CONTENT_DISPOSITION, content_disposition(&filename));
+        }
+        res
+    }
+}
+
+/// Returns `utf-8`, unless the special characters of `writer` would make
+/// the data invalid UTF-8.
+fn utf8_charset(writer: &crate::Writer) -> Option<String> {
+    writer.is_ascii_dialect().then(|| "utf-8".to_owned())
+}
+
+/// Build an attachment `Content-Disposition` header for the given filename.
+///
+/// The `filename` parameter holds the name with any characters that cannot
+/// be sent as a quoted string replaced. If that is not the same as the
+/// original name, the `filename*` parameter holds the original name as
+/// described in RFC 5987.
+fn content_disposition(filename: &str) -> HeaderValue {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii() && !c.is_ascii_control() => c,
+            _ => '_',
+        })
+        .collect();
+
+    let mut value = format!("attachment; filename=\"{}\"", fallback);
+    if fallback != filename {
+        value.push_str("; filename*=UTF-8''");
+        for &b in filename.as_bytes() {
+            if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+                value.push(b as char);
+            } else {
+                write!(value, "%{:02X}", b).unwrap();
+            }
+        }
+    }
+    HeaderValue::try_from(value).expect("header value is visible ASCII")
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+    use axum::response::IntoResponse;
+
+    use super::CsvResponse;
+    use crate::WriterBuilder;
+
+    #[derive(serde::Serialize)]
+    struct Row {
+        foo: u32,
+        bar: &'static str,
+    }
+
+    fn rows() -> Vec<Row> {
+        vec![Row { foo: 1, bar: "a" }, Row { foo: 2, bar: "b,c" }]
+    }
+
+    #[tokio::test]
+    async fn stream() {
+        let stream = WriterBuilder::default().build_stream(futures::stream::iter(rows()));
+        let res = CsvResponse::from(stream).into_response();
+
+        let headers = res.headers();
+        assert_eq!(
+            headers[CONTENT_TYPE],
+            "text/csv; charset=utf-8; header=present"
+        );
+        assert!(headers.get(CONTENT_DISPOSITION).is_none());
+
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"foo,bar\n1,a\n2,\"b,c\"\n");
+    }
+
+    #[tokio::test]
+    async fn iter_without_headers() {
+        let iter = WriterBuilder::default()
+            .has_headers(false)
+            .build_iter(rows());
+        let res = CsvResponse::from(iter).filename("rows.csv").into_response();
+
+        let headers = res.headers();
+        assert_eq!(
+            headers[CONTENT_TYPE],
+            "text/csv; charset=utf-8; header=absent"
+        );
+        assert_eq!(
+            headers[CONTENT_DISPOSITION],
+            "attachment; filename=\"rows.csv\""
+        );
+
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"1,a\n2,\"b,c\"\n");
+    }
+
+    #[test]
+    fn charset() {
+        let iter = WriterBuilder::default().delimiter(0xa7).build_iter(rows());
+        let res = CsvResponse::from(iter).into_response();
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/csv; header=present");
+
+        let iter = WriterBuilder::default().build_iter(rows());
+        let res = CsvResponse::from(iter).charset(None).into_response();
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/csv; header=present");
+
+        let iter = WriterBuilder::default().build_iter(rows());
+        let res = CsvResponse::from(iter)
+            .charset(Some("latin1"))
+            .into_response();
+        assert_eq!(
+            res.headers()[CONTENT_TYPE],
+            "text/csv; charset=latin1; header=present"
+        );
+
+        let iter = WriterBuilder::default().build_iter(rows());
+        let res = CsvResponse::from(iter)
+            .charset(Some("utf-8\n"))
+            .into_response();
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/csv; header=present");
+    }
+
+    #[test]
+    fn filename_encoding() {
+        let iter = WriterBuilder::default().build_iter(rows());
+        let res = CsvResponse::from(iter)
+            .filename("résumé \"final\".csv")
+            .into_response();
+
+        assert_eq!(
+            res.headers()[CONTENT_DISPOSITION],
+            "attachment; filename=\"r_sum_ _final_.csv\"; \
+             filename*=UTF-8''r%C3%A9sum%C3%A9%20%22final%22.csv"
+        );
+    }
+}
@@ -2,27 +2,31 @@ use std::fmt;
 use std::mem;
 
 use serde::ser::{
-    Error as SerdeError, Serialize, SerializeMap, SerializeSeq, SerializeStruct,
+    Error as SerdeError, Impossible, Serialize, SerializeMap, SerializeSeq, SerializeStruct,
     SerializeStructVariant, SerializeTuple, SerializeTupleStruct, SerializeTupleVariant,
     Serializer,
 };
-use serde::serde_if_integer128;
 
 use crate::error::{Error, ErrorKind};
 use crate::writer::Writer;
+use crate::CsvBuf;
 
 /// Serialize the given value to the given writer, and return an error if
 /// anything went wrong.
-pub fn serialize<S: Serialize>(wtr: &mut Writer, buf: &mut Vec<u8>, value: S) -> Result<(), Error> {
+pub fn serialize<B, S>(wtr: &mut Writer, buf: &mut B, value: S) -> Result<(), Error>
+where
+    B: CsvBuf + ?Sized,
+    S: Serialize,
+{
     value.serialize(&mut SeRecord { wtr, buf })
 }
 
-struct SeRecord<'w> {
+struct SeRecord<'w, B: ?Sized> {
     wtr: &'w mut Writer,
-    buf: &'w mut Vec<u8>,
+    buf: &'w mut B,
 }
 
-impl<'a, 'w> Serializer for &'a mut SeRecord<'w> {
+impl<'a, 'w, B: CsvBuf + ?Sized> Serializer for &'a mut SeRecord<'w, B> {
     type Ok = ();
     type Error = Error;
     type SerializeSeq = Self;
@@ -35,72 +39,68 @@ impl<'a, 'w> Serializer for &'a mut SeRecord<'w> {
 
     fn serialize_bool(self, v: bool) -> Result<Self::Ok, Self::Error> {
         if v {
-            self.wtr.write_field(self.buf, "true")
+            self.wtr.write_scalar(self.buf, "true")
         } else {
-            self.wtr.write_field(self.buf, "false")
+            self.wtr.write_scalar(self.buf, "false")
         }
     }
 
     fn serialize_i8(self, v: i8) -> Result<Self::Ok, Self::Error> {
         let mut buffer = itoa::Buffer::new();
-        self.wtr.write_field(self.buf, buffer.format(v))
+        self.wtr.write_scalar(self.buf, buffer.format(v))
     }
 
     fn serialize_i16(self, v: i16) -> Result<Self::Ok, Self::Error> {
         let mut buffer = itoa::Buffer::new();
-        self.wtr.write_field(self.buf, buffer.format(v))
+        self.wtr.write_scalar(self.buf, buffer.format(v))
     }
 
     fn serialize_i32(self, v: i32) -> Result<Self::Ok, Self::Error> {
         let mut buffer = itoa::Buffer::new();
-        self.wtr.write_field(self.buf, buffer.format(v))
+        self.wtr.write_scalar(self.buf, buffer.format(v))
     }
 
     fn serialize_i64(self, v: i64) -> Result<Self::Ok, Self::Error> {
         let mut buffer = itoa::Buffer::new();
-        self.wtr.write_field(self.buf, buffer.format(v))
+        self.wtr.write_scalar(self.buf, buffer.format(v))
     }
 
-    serde_if_integer128! {
-        fn serialize_i128(self, v: i128) -> Result<Self::Ok, Self::Error> {
-            self.collect_str(&v)
-        }
+    fn serialize_i128(self, v: i128) -> Result<Self::Ok, Self::Error> {
+        self.wtr.write_scalar(self.buf, v.to_string())
     }
 
     fn serialize_u8(self, v: u8) -> Result<Self::Ok, Self::Error> {
         let mut buffer = itoa::Buffer::new();
-        self.wtr.write_field(self.buf, buffer.format(v))
+        self.wtr.write_scalar(self.buf, buffer.format(v))
     }
 
     fn serialize_u16(self, v: u16) -> Result<Self::Ok, Self::Error> {
         let mut buffer = itoa::Buffer::new();
-        self.wtr.write_field(self.buf, buffer.format(v))
+        self.wtr.write_scalar(self.buf, buffer.format(v))
     }
 
     fn serialize_u32(self, v: u32) -> Result<Self::Ok, Self::Error> {
         let mut buffer = itoa::Buffer::new();
-        self.wtr.write_field(self.buf, buffer.format(v))
+        self.wtr.write_scalar(self.buf, buffer.format(v))
     }
 
     fn serialize_u64(self, v: u64) -> Result<Self::Ok, Self::Error> {
         let mut buffer = itoa::Buffer::new();
-        self.wtr.write_field(self.buf, buffer.format(v))
+        self.wtr.write_scalar(self.buf, buffer.format(v))
     }
 
-    serde_if_integer128! {
-        fn serialize_u128(self, v: u128) -> Result<Self::Ok, Self::Error> {
-            self.collect_str(&v)
-        }
+    fn serialize_u128(self, v: u128) -> Result<Self::Ok, Self::Error> {
+        self.wtr.write_scalar(self.buf, v.to_string())
     }
 
     fn serialize_f32(self, v: f32) -> Result<Self::Ok, Self::Error> {
         let mut buffer = ryu::Buffer::new();
-        self.wtr.write_field(self.buf, buffer.format(v))
+        self.wtr.write_scalar(self.buf, buffer.format(v))
     }
 
     fn serialize_f64(self, v: f64) -> Result<Self::Ok, Self::Error> {
         let mut buffer = ryu::Buffer::new();
-        self.wtr.write_field(self.buf, buffer.format(v))
+        self.wtr.write_scalar(self.buf, buffer.format(v))
     }
 
     fn serialize_char(self, v: char) -> Result<Self::Ok, Self::Error> {
@@ -112,11 +112,11 @@ impl<'a, 'w> Serializer for &'a mut SeRecord<'w> {
     }
 
     fn serialize_bytes(self, value: &[u8]) -> Result<Self::Ok, Self::Error> {
-        self.wtr.write_field(self.buf, value)
+        self.wtr.write_bytes(self.buf, value)
     }
 
     fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
-        self.wtr.write_field(self.buf, &[])
+        self.wtr.write_scalar(self.buf, [])
     }
 
     fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Self::Ok, Self::Error> {
@@ -216,7 +216,7 @@ impl<'a, 'w> Serializer for &'a mut SeRecord<'w> {
     }
 }
 
-impl<'a, 'w> SerializeSeq for &'a mut SeRecord<'w> {
+impl<'a, 'w, B: CsvBuf + ?Sized> SerializeSeq for &'a mut SeRecord<'w, B> {
     type Ok = ();
     type Error = Error;
 
@@ -229,7 +229,7 @@ impl<'a, 'w> SerializeSeq for &'a mut SeRecord<'w> {
     }
 }
 
-impl<'a, 'w> SerializeTuple for &'a mut SeRecord<'w> {
+impl<'a, 'w, B: CsvBuf + ?Sized> SerializeTuple for &'a mut SeRecord<'w, B> {
     type Ok = ();
     type Error = Error;
 
@@ -242,7 +242,7 @@ impl<'a, 'w> SerializeTuple for &'a mut SeRecord<'w> {
     }
 }
 
-impl<'a, 'w> SerializeTupleStruct for &'a mut SeRecord<'w> {
+impl<'a, 'w, B: CsvBuf + ?Sized> SerializeTupleStruct for &'a mut SeRecord<'w, B> {
     type Ok = ();
     type Error = Error;
 
@@ -255,7 +255,7 @@ impl<'a, 'w> SerializeTupleStruct for &'a mut SeRecord<'w> {
     }
 }
 
-impl<'a, 'w> SerializeTupleVariant for &'a mut SeRecord<'w> {
+impl<'a, 'w, B: CsvBuf + ?Sized> SerializeTupleVariant for &'a mut SeRecord<'w, B> {
     type Ok = ();
     type Error = Error;
 
@@ -268,7 +268,7 @@ impl<'a, 'w> SerializeTupleVariant for &'a mut SeRecord<'w> {
     }
 }
 
-impl<'a, 'w> SerializeMap for &'a mut SeRecord<'w> {
+impl<'a, 'w, B: CsvBuf + ?Sized> SerializeMap for &'a mut SeRecord<'w, B> {
     type Ok = ();
     type Error = Error;
 
@@ -285,16 +285,19 @@ impl<'a, 'w> SerializeMap for &'a mut SeRecord<'w> {
     }
 }
 
-impl<'a, 'w> SerializeStruct for &'a mut SeRecord<'w> {
+impl<'a, 'w, B: CsvBuf + ?Sized> SerializeStruct for &'a mut SeRecord<'w, B> {
     type Ok = ();
     type Error = Error;
 
     fn serialize_field<T: ?Sized + Serialize>(
         &mut self,
-        _key: &'static str,
+        key: &'static str,
         value: &T,
     ) -> Result<(), Self::Error> {
-        value.serialize(&mut **self)
+        let index = self.wtr.fields_written();
+        value
+            .serialize(&mut **self)
+            .map_err(|err| err.or_field(index, Some(key)))
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
@@ -302,7 +305,7 @@ impl<'a, 'w> SerializeStruct for &'a mut SeRecord<'w> {
     }
 }
 
-impl<'a, 'w> SerializeStructVariant for &'a mut SeRecord<'w> {
+impl<'a, 'w, B: CsvBuf + ?Sized> SerializeStructVariant for &'a mut SeRecord<'w, B> {
     type Ok = ();
     type Error = Error;
 
@@ -349,11 +352,11 @@ fn error_container_inside_struct<T: fmt::Display>(name: T) -> Error {
 ///
 /// If the type to be serialized doesn't have field names, then nothing is
 /// written, and the `Ok` return value is `false`.
-pub fn serialize_header<S: Serialize>(
-    wtr: &mut Writer,
-    buf: &mut Vec<u8>,
-    value: S,
-) -> Result<bool, Error> {
+pub fn serialize_header<B, S>(wtr: &mut Writer, buf: &mut B, value: S) -> Result<bool, Error>
+where
+    B: CsvBuf + ?Sized,
+    S: Serialize,
+{
     let mut ser = SeHeader::new(wtr, buf);
     value.serialize(&mut ser).map(|_| ser.wrote_header())
 }
@@ -412,14 +415,14 @@ enum HeaderState {
     InStructField,
 }
 
-struct SeHeader<'w> {
+struct SeHeader<'w, B: ?Sized> {
     wtr: &'w mut Writer,
-    buf: &'w mut Vec<u8>,
+    buf: &'w mut B,
     state: HeaderState,
 }
 
-impl<'w> SeHeader<'w> {
-    fn new(wtr: &'w mut Writer, buf: &'w mut Vec<u8>) -> Self {
+impl<'w, B: CsvBuf + ?Sized> SeHeader<'w, B> {
+    fn new(wtr: &'w mut Writer, buf: &'w mut B) -> Self {
         SeHeader {
             wtr,
             buf,
@@ -457,7 +460,7 @@ impl<'w> SeHeader<'w> {
     }
 }
 
-impl<'a, 'w> Serializer for &'a mut SeHeader<'w> {
+impl<'a, 'w, B: CsvBuf + ?Sized> Serializer for &'a mut SeHeader<'w, B> {
     type Ok = ();
     type Error = Error;
     type SerializeSeq = Self;
@@ -488,10 +491,8 @@ impl<'a, 'w> Serializer for &'a mut SeHeader<'w> {
         self.handle_scalar(v)
     }
 
-    serde_if_integer128! {
-        fn serialize_i128(self, v: i128) -> Result<Self::Ok, Self::Error> {
-            self.handle_scalar(v)
-        }
+    fn serialize_i128(self, v: i128) -> Result<Self::Ok, Self::Error> {
+        self.handle_scalar(v)
     }
 
     fn serialize_u8(self, v: u8) -> Result<Self::Ok, Self::Error> {
@@ -510,10 +511,8 @@ impl<'a, 'w> Serializer for &'a mut SeHeader<'w> {
         self.handle_scalar(v)
     }
 
-    serde_if_integer128! {
-        fn serialize_u128(self, v: u128) -> Result<Self::Ok, Self::Error> {
-            self.handle_scalar(v)
-        }
+    fn serialize_u128(self, v: u128) -> Result<Self::Ok, Self::Error> {
+        self.handle_scalar(v)
     }
 
     fn serialize_f32(self, v: f32) -> Result<Self::Ok, Self::Error> {
@@ -637,7 +636,7 @@ impl<'a, 'w> Serializer for &'a mut SeHeader<'w> {
     }
 }
 
-impl<'a, 'w> SerializeSeq for &'a mut SeHeader<'w> {
+impl<'a, 'w, B: CsvBuf + ?Sized> SerializeSeq for &'a mut SeHeader<'w, B> {
     type Ok = ();
     type Error = Error;
 
@@ -650,7 +649,7 @@ impl<'a, 'w> SerializeSeq for &'a mut SeHeader<'w> {
     }
 }
 
-impl<'a, 'w> SerializeTuple for &'a mut SeHeader<'w> {
+impl<'a, 'w, B: CsvBuf + ?Sized> SerializeTuple for &'a mut SeHeader<'w, B> {
     type Ok = ();
     type Error = Error;
 
@@ -663,7 +662,7 @@ impl<'a, 'w> SerializeTuple for &'a mut SeHeader<'w> {
     }
 }
 
-impl<'a, 'w> SerializeTupleStruct for &'a mut SeHeader<'w> {
+impl<'a, 'w, B: CsvBuf + ?Sized> SerializeTupleStruct for &'a mut SeHeader<'w, B> {
     type Ok = ();
     type Error = Error;
 
@@ -676,7 +675,7 @@ impl<'a, 'w> SerializeTupleStruct for &'a mut SeHeader<'w> {
     }
 }
 
-impl<'a, 'w> SerializeTupleVariant for &'a mut SeHeader<'w> {
+impl<'a, 'w, B: CsvBuf + ?Sized> SerializeTupleVariant for &'a mut SeHeader<'w, B> {
     type Ok = ();
     type Error = Error;
 
@@ -689,7 +688,7 @@ impl<'a, 'w> SerializeTupleVariant for &'a mut SeHeader<'w> {
     }
 }
 
-impl<'a, 'w> SerializeMap for &'a mut SeHeader<'w> {
+impl<'a, 'w, B: CsvBuf + ?Sized> SerializeMap for &'a mut SeHeader<'w, B> {
     type Ok = ();
     type Error = Error;
 
@@ -706,7 +705,7 @@ impl<'a, 'w> SerializeMap for &'a mut SeHeader<'w> {
     }
 }
 
-impl<'a, 'w> SerializeStruct for &'a mut SeHeader<'w> {
+impl<'a, 'w, B: CsvBuf + ?Sized> SerializeStruct for &'a mut SeHeader<'w, B> {
     type Ok = ();
     type Error = Error;
 
@@ -720,11 +719,14 @@ impl<'a, 'w> SerializeStruct for &'a mut SeHeader<'w> {
         if let HeaderState::ErrorIfWrite(err) = old_state {
             return Err(err);
         }
+        let index = self.wtr.fields_written();
         self.wtr.write_field(self.buf, key)?;
 
         // Check that there aren't any containers in the value.
         self.state = HeaderState::InStructField;
-        value.serialize(&mut **self)?;
+        value
+            .serialize(&mut **self)
+            .map_err(|err| err.or_field(index, Some(key)))?;
         self.state = HeaderState::EncounteredStructField;
 
         Ok(())
@@ -735,7 +737,7 @@ impl<'a, 'w> SerializeStruct for &'a mut SeHeader<'w> {
     }
 }
 
-impl<'a, 'w> SerializeStructVariant for &'a mut SeHeader<'w> {
+impl<'a, 'w, B: CsvBuf + ?Sized> SerializeStructVariant for &'a mut SeHeader<'w, B> {
     type Ok = ();
     type Error = Error;
 
@@ -752,12 +754,495 @@ impl<'a, 'w> SerializeStructVariant for &'a mut SeHeader<'w> {
     }
 }
 
+/// The fields of a map or struct record, by name, captured so that they can
+/// be written in the order of an inferred header row.
+pub type Fields = Vec<(String, Scalar)>;
+
+/// A single field value, captured along with its type so that writing it
+/// later is no different from serializing it directly.
+#[derive(Clone, Debug)]
+pub enum Scalar {
+    None,
+    Bool(bool),
+    I64(i64),
+    I128(i128),
+    U64(u64),
+    U128(u128),
+    F32(f32),
+    F64(f64),
+    Str(String),
+    Bytes(Vec<u8>),
+}
+
+impl Serialize for Scalar {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Scalar::None => serializer.serialize_none(),
+            Scalar::Bool(v) => serializer.serialize_bool(v),
+            Scalar::I64(v) => serializer.serialize_i64(v),
+            Scalar::I128(v) => serializer.serialize_i128(v),
+            Scalar::U64(v) => serializer.serialize_u64(v),
+            Scalar::U128(v) => serializer.serialize_u128(v),
+            Scalar::F32(v) => serializer.serialize_f32(v),
+            Scalar::F64(v) => serializer.serialize_f64(v),
+            Scalar::Str(ref v) => serializer.serialize_str(v),
+            Scalar::Bytes(ref v) => serializer.serialize_bytes(v),
+        }
+    }
+}
+
+/// Capture the fields of a record that is a map or a struct.
+pub fn capture_fields<S: Serialize>(value: S) -> Result<Fields, Error> {
+    value.serialize(SeFields)
+}
+
+fn error_not_map<T: fmt::Display>(name: T) -> Error {
+    Error::custom(format!(
+        "cannot infer headers from {}, records must be maps or structs",
+        name
+    ))
+}
+
+fn error_container_in_map<T: fmt::Display>(name: T) -> Error {
+    Error::custom(format!(
+        "cannot serialize {} container as a field \
+         when inferring headers",
+        name
+    ))
+}
+
+/// Serializer for a whole record when inferring headers.
+struct SeFields;
+
+impl Serializer for SeFields {
+    type Ok = Fields;
+    type Error = Error;
+    type SerializeSeq = Impossible<Fields, Error>;
+    type SerializeTuple = Impossible<Fields, Error>;
+    type SerializeTupleStruct = Impossible<Fields, Error>;
+    type SerializeTupleVariant = Impossible<Fields, Error>;
+    type SerializeMap = SeMapFields;
+    type SerializeStruct = SeMapFields;
+    type SerializeStructVariant = Impossible<Fields, Error>;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, Self::Error> {
+        Err(error_not_map(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, Self::Error> {
+        Err(error_not_map(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, Self::Error> {
+        Err(error_not_map(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, Self::Error> {
+        Err(error_not_map(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, Self::Error> {
+        Err(error_not_map(v))
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<Self::Ok, Self::Error> {
+        Err(error_not_map(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, Self::Error> {
+        Err(error_not_map(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, Self::Error> {
+        Err(error_not_map(v))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, Self::Error> {
+        Err(error_not_map(v))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, Self::Error> {
+        Err(error_not_map(v))
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Self::Ok, Self::Error> {
+        Err(error_not_map(v))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Self::Ok, Self::Error> {
+        Err(error_not_map(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Self::Ok, Self::Error> {
+        Err(error_not_map(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, Self::Error> {
+        Err(error_not_map(v))
+    }
+
+    fn serialize_str(self, value: &str) -> Result<Self::Ok, Self::Error> {
+        Err(error_not_map(value))
+    }
+
+    fn serialize_bytes(self, _value: &[u8]) -> Result<Self::Ok, Self::Error> {
+        Err(error_not_map("&[u8]"))
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
+        Err(error_not_map("None"))
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Self::Ok, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
+        Err(error_not_map("()"))
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<Self::Ok, Self::Error> {
+        Err(error_not_map(name))
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Self::Error> {
+        Err(error_not_map(variant))
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        Err(error_not_map("sequence"))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        Err(error_not_map("tuple"))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        Err(error_not_map(name))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        Err(Error::custom(
+            "serializing enum tuple variants is not supported",
+        ))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        Ok(SeMapFields {
+            fields: Vec::with_capacity(len.unwrap_or(0)),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        Ok(SeMapFields {
+            fields: Vec::with_capacity(len),
+            key: None,
+        })
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        Err(Error::custom(
+            "serializing enum struct variants is not supported",
+        ))
+    }
+}
+
+struct SeMapFields {
+    fields: Fields,
+    /// The key of the entry currently being serialized.
+    key: Option<String>,
+}
+
+impl SeMapFields {
+    fn push<T: ?Sized + Serialize>(&mut self, key: String, value: &T) -> Result<(), Error> {
+        let index = self.fields.len() as u64;
+        match value.serialize(SeScalar) {
+            Ok(value) => {
+                self.fields.push((key, value));
+                Ok(())
+            }
+            Err(err) => Err(err.or_field(index, Some(&key))),
+        }
+    }
+}
+
+impl SerializeMap for SeMapFields {
+    type Ok = Fields;
+    type Error = Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Self::Error> {
+        let key = match key.serialize(SeScalar)? {
+            Scalar::Str(key) => key,
+            Scalar::Bytes(key) => {
+                String::from_utf8(key).map_err(|_| Error::custom("map keys must be valid UTF-8"))?
+            }
+            Scalar::Bool(key) => key.to_string(),
+            Scalar::I64(key) => key.to_string(),
+            Scalar::I128(key) => key.to_string(),
+            Scalar::U64(key) => key.to_string(),
+            Scalar::U128(key) => key.to_string(),
+            Scalar::F32(key) => key.to_string(),
+            Scalar::F64(key) => key.to_string(),
+            Scalar::None => return Err(Error::custom("map keys must not be empty")),
+        };
+        self.key = Some(key);
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        let key = self
+            .key
+            .take()
+            .expect("serialize_value called before serialize_key");
+        self.push(key, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Ok(self.fields)
+    }
+}
+
+impl SerializeStruct for SeMapFields {
+    type Ok = Fields;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        self.push(key.to_owned(), value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Ok(self.fields)
+    }
+}
+
+/// Serializer for a single field when inferring headers.
+struct SeScalar;
+
+impl Serializer for SeScalar {
+    type Ok = Scalar;
+    type Error = Error;
+    type SerializeSeq = Impossible<Scalar, Error>;
+    type SerializeTuple = Impossible<Scalar, Error>;
+    type SerializeTupleStruct = Impossible<Scalar, Error>;
+    type SerializeTupleVariant = Impossible<Scalar, Error>;
+    type SerializeMap = Impossible<Scalar, Error>;
+    type SerializeStruct = Impossible<Scalar, Error>;
+    type SerializeStructVariant = Impossible<Scalar, Error>;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, Self::Error> {
+        Ok(Scalar::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, Self::Error> {
+        Ok(Scalar::I64(v.into()))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, Self::Error> {
+        Ok(Scalar::I64(v.into()))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, Self::Error> {
+        Ok(Scalar::I64(v.into()))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, Self::Error> {
+        Ok(Scalar::I64(v))
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<Self::Ok, Self::Error> {
+        Ok(Scalar::I128(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, Self::Error> {
+        Ok(Scalar::U64(v.into()))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, Self::Error> {
+        Ok(Scalar::U64(v.into()))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, Self::Error> {
+        Ok(Scalar::U64(v.into()))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, Self::Error> {
+        Ok(Scalar::U64(v))
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Self::Ok, Self::Error> {
+        Ok(Scalar::U128(v))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Self::Ok, Self::Error> {
+        Ok(Scalar::F32(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Self::Ok, Self::Error> {
+        Ok(Scalar::F64(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, Self::Error> {
+        Ok(Scalar::Str(v.to_string()))
+    }
+
+    fn serialize_str(self, value: &str) -> Result<Self::Ok, Self::Error> {
+        Ok(Scalar::Str(value.to_owned()))
+    }
+
+    fn serialize_bytes(self, value: &[u8]) -> Result<Self::Ok, Self::Error> {
+        Ok(Scalar::Bytes(value.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
+        Ok(Scalar::None)
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Self::Ok, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
+        Ok(Scalar::None)
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<Self::Ok, Self::Error> {
+        Ok(Scalar::Str(name.to_owned()))
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Self::Error> {
+        Ok(Scalar::Str(variant.to_owned()))
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        Err(error_container_in_map("sequence"))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        Err(error_container_in_map("tuple"))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        Err(error_container_in_map(name))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        Err(Error::custom(
+            "serializing enum tuple variants is not supported",
+        ))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        Err(error_container_in_map("map"))
+    }
+
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        Err(error_container_in_map(name))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        Err(Error::custom(
+            "serializing enum struct variants is not supported",
+        ))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #![allow(clippy::approx_constant)]
 
     use bstr::ByteSlice;
-    use serde::{serde_if_integer128, Serialize};
+    use serde::Serialize;
 
     use crate::error::{Error, ErrorKind};
     use crate::writer::WriterBuilder;
@@ -815,24 +1300,22 @@ mod tests {
         assert_eq!(got, "");
     }
 
-    serde_if_integer128! {
-        #[test]
-        fn integer_u128() {
-            let got = serialize(i128::max_value() as u128 + 1);
-            assert_eq!(got, "170141183460469231731687303715884105728\n");
-            let (wrote, got) = serialize_header(12345);
-            assert!(!wrote);
-            assert_eq!(got, "");
-        }
+    #[test]
+    fn integer_u128() {
+        let got = serialize(i128::MAX as u128 + 1);
+        assert_eq!(got, "170141183460469231731687303715884105728\n");
+        let (wrote, got) = serialize_header(12345);
+        assert!(!wrote);
+        assert_eq!(got, "");
+    }
 
-        #[test]
-        fn integer_i128() {
-            let got = serialize(i128::max_value());
-            assert_eq!(got, "170141183460469231731687303715884105727\n");
-            let (wrote, got) = serialize_header(12345);
-            assert!(!wrote);
-            assert_eq!(got, "");
-        }
+    #[test]
+    fn integer_i128() {
+        let got = serialize(i128::MAX);
+        assert_eq!(got, "170141183460469231731687303715884105727\n");
+        let (wrote, got) = serialize_header(12345);
+        assert!(!wrote);
+        assert_eq!(got, "");
     }
 
     #[test]
@@ -846,9 +1329,9 @@ mod tests {
 
     #[test]
     fn float_nan() {
-        let got = serialize(::std::f64::NAN);
+        let got = serialize(f64::NAN);
         assert_eq!(got, "NaN\n");
-        let (wrote, got) = serialize_header(::std::f64::NAN);
+        let (wrote, got) = serialize_header(f64::NAN);
         assert!(!wrote);
         assert_eq!(got, "");
     }
@@ -1090,23 +1573,23 @@ mod tests {
         assert_eq!(got, "true,5,hi\n");
     }
 
-    serde_if_integer128! {
-        #[test]
-        fn struct_no_headers_128() {
-            #[derive(Serialize)]
-            struct Foo {
-                x: i128,
-                y: u128,
-            }
-
-            let got =
-                serialize(Foo { x: i128::max_value(), y: u128::max_value() });
-            assert_eq!(
-                got,
-                "170141183460469231731687303715884105727,\
-                 340282366920938463463374607431768211455\n"
-            );
+    #[test]
+    fn struct_no_headers_128() {
+        #[derive(Serialize)]
+        struct Foo {
+            x: i128,
+            y: u128,
         }
+
+        let got = serialize(Foo {
+            x: i128::MAX,
+            y: u128::MAX,
+        });
+        assert_eq!(
+            got,
+            "170141183460469231731687303715884105727,\
+             340282366920938463463374607431768211455\n"
+        );
     }
 
     #[test]
@@ -1336,4 +1819,27 @@ mod tests {
         assert!(wrote);
         assert_eq!(got, "label,num,label2,value,empty,label,num");
     }
+
+    #[test]
+    fn struct_field_err() {
+        use std::collections::BTreeMap;
+
+        #[derive(Clone, Serialize)]
+        struct Foo {
+            label: String,
+            values: BTreeMap<String, i32>,
+        }
+        let row = Foo {
+            label: "foo".into(),
+            values: BTreeMap::new(),
+        };
+
+        let err = serialize_err(row.clone());
+        assert_eq!(err.field(), Some(1));
+        assert_eq!(err.header(), Some("values"));
+
+        let err = serialize_header_err(row);
+        assert_eq!(err.field(), Some(1));
+        assert_eq!(err.header(), Some("values"));
+    }
 }
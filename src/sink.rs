@@ -0,0 +1,231 @@
+use std::io;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::io::AsyncWrite;
+use pin_project::pin_project;
+use serde::Serialize;
+
+use crate::{Error, Result, Stats, Writer};
+
+/// A Sink that writes records as CSV into an [`AsyncWrite`]
+///
+/// Records sent into the sink are serialized into an internal buffer. Once
+/// the buffer holds at least
+/// [`buffer_capacity`](crate::WriterBuilder::buffer_capacity) bytes, the sink
+/// stops accepting records until the buffer has been written out to the
+/// underlying writer. Closing the sink writes any remaining output and closes
+/// the underlying writer.
+///
+/// If a record fails to serialize, nothing of it is written and the sink
+/// can carry on accepting records.
+///
+/// # Example
+///
+/// ```
+/// use std::error::Error;
+/// use csv_stream::WriterBuilder;
+/// use serde::Serialize;
+/// use futures::StreamExt;
+///
+/// # #[tokio::main]
+/// # async fn main() { example().await.unwrap(); }
+/// async fn example() -> Result<(), Box<dyn Error>> {
+///     #[derive(Serialize)]
+///     struct Row { foo: usize, bar: usize }
+///     let rows = [
+///         Row{ foo: 1, bar: 2 },
+///         Row{ foo: 3, bar: 4 },
+///     ];
+///
+///     let mut sink = WriterBuilder::default().build_sink(vec![]);
+///     futures::stream::iter(rows).map(Ok).forward(&mut sink).await?;
+///
+///     let data = String::from_utf8(sink.into_inner())?;
+///     assert_eq!(data, "foo,bar\n1,2\n3,4\n");
+///     Ok(())
+/// }
+/// ```
+#[pin_project]
+#[derive(Debug)]
+pub struct CsvSink<W, T> {
+    #[pin]
+    inner: W,
+
+    writer: Writer,
+    buf: Vec<u8>,
+    /// The number of bytes at the start of `buf` that have already been
+    /// written to `inner`.
+    written: usize,
+    capacity: usize,
+    _record: PhantomData<fn(T)>,
+}
+
+impl<W, T> CsvSink<W, T> {
+    pub fn new(inner: W, writer: Writer, capacity: usize) -> Self {
+        Self {
+            inner,
+            writer,
+            buf: Vec::with_capacity(capacity),
+            written: 0,
+            capacity,
+            _record: PhantomData,
+        }
+    }
+
+    /// Returns statistics about the data written so far, including data that
+    /// is still buffered.
+    pub fn stats(&self) -> Stats {
+        self.writer.stats()
+    }
+
+    /// Returns a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Returns the underlying writer.
+    ///
+    /// Any output that is still buffered is lost, so the sink should be
+    /// flushed or closed first.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: AsyncWrite, T> CsvSink<W, T> {
+    /// Write all buffered output to the underlying writer.
+    fn poll_write_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let mut p = self.project();
+        while *p.written < p.buf.len() {
+            match p.inner.as_mut().poll_write(cx, &p.buf[*p.written..]) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(0)) => {
+                    let err = io::Error::new(io::ErrorKind::WriteZero, "failed to write CSV data");
+                    return Poll::Ready(Err(Error::from(err)));
+                }
+                Poll::Ready(Ok(n)) => *p.written += n,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(Error::from(err))),
+            }
+        }
+        p.buf.clear();
+        *p.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite, T: Serialize> futures::Sink<T> for CsvSink<W, T> {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if self.buf.len() < self.capacity {
+            return Poll::Ready(Ok(()));
+        }
+        self.poll_write_buf(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<()> {
+        let p = self.project();
+        p.writer.serialize(p.buf, item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        futures::ready!(self.as_mut().poll_write_buf(cx))?;
+        self.project().inner.poll_flush(cx).map_err(Error::from)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        futures::ready!(self.as_mut().poll_write_buf(cx))?;
+        self.project().inner.poll_close(cx).map_err(Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures::io::AsyncWrite;
+    use futures::SinkExt;
+    use serde::Serialize;
+
+    use crate::WriterBuilder;
+
+    #[derive(Serialize)]
+    struct Row {
+        foo: u32,
+        bar: &'static str,
+    }
+
+    /// A writer that accepts at most one byte per call, and is only ready
+    /// every other call.
+    #[derive(Default)]
+    struct Slow {
+        data: Vec<u8>,
+        ready: bool,
+        closed: bool,
+    }
+
+    impl AsyncWrite for Slow {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.data.push(buf[0]);
+            Poll::Ready(Ok(1))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            self.closed = true;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn send() {
+        let mut sink = WriterBuilder::default().build_sink(vec![]);
+        sink.send(Row { foo: 1, bar: "a" }).await.unwrap();
+        sink.send(Row { foo: 2, bar: "b,c" }).await.unwrap();
+
+        assert_eq!(sink.stats().records(), 2);
+        assert_eq!(sink.into_inner(), b"foo,bar\n1,a\n2,\"b,c\"\n");
+    }
+
+    #[tokio::test]
+    async fn backpressure() {
+        let mut sink = WriterBuilder::default()
+            .buffer_capacity(8)
+            .build_sink(Slow::default());
+        sink.feed(Row { foo: 1, bar: "a" }).await.unwrap();
+        assert!(sink.get_ref().data.is_empty());
+
+        // The buffer is full, so the next record waits for it to be written.
+        sink.feed(Row { foo: 2, bar: "b" }).await.unwrap();
+        assert_eq!(sink.get_ref().data, b"foo,bar\n1,a\n");
+
+        sink.close().await.unwrap();
+        assert_eq!(sink.get_ref().data, b"foo,bar\n1,a\n2,b\n");
+        assert!(sink.get_ref().closed);
+    }
+
+    #[tokio::test]
+    async fn serialize_error() {
+        let mut sink = WriterBuilder::default().build_sink(vec![]);
+        sink.send(vec![1, 2]).await.unwrap();
+        assert!(sink.send(vec![1, 2, 3]).await.is_err());
+        sink.send(vec![3, 4]).await.unwrap();
+
+        assert_eq!(sink.into_inner(), b"1,2\n3,4\n");
+    }
+}
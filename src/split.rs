@@ -0,0 +1,662 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use serde::Serialize;
+
+use crate::writer::Recovery;
+use crate::{Error, Result, Stats, Writer};
+
+/// Limits on the size of each part of a split CSV output.
+///
+/// A part is ended before the record that would take it over either limit,
+/// so parts are only ever split on record boundaries. Every part contains at
+/// least one record, even if that record on its own is larger than the byte
+/// limit.
+///
+/// Placeholder rows and comments written by the
+/// [`ErrorPolicy`](crate::ErrorPolicy) are kept in the part in which the
+/// error occurred, and are not moved to the next part.
+///
+/// # Example
+///
+/// ```
+/// use csv_stream::Split;
+///
+/// // At most 10,000 records or 5MiB per part, whichever comes first.
+/// let split = Split::default()
+///     .max_records(10_000)
+///     .max_bytes(5 * 1024 * 1024);
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Split {
+    records: Option<u64>,
+    bytes: Option<u64>,
+}
+
+impl Split {
+    /// The maximum number of records in each part, not including the header
+    /// row.
+    pub fn max_records(mut self, records: u64) -> Split {
+        self.records = Some(records);
+        self
+    }
+
+    /// The maximum number of bytes in each part, including the header row
+    /// and the preamble.
+    pub fn max_bytes(mut self, bytes: u64) -> Split {
+        self.bytes = Some(bytes);
+        self
+    }
+
+    /// Whether a part with these stats cannot take any more records.
+    fn is_full(&self, stats: &Stats) -> bool {
+        stats.records() > 0 && matches!(self.records, Some(max) if stats.records() >= max)
+    }
+
+    /// Whether the last record written went over the byte limit, given the
+    /// stats from before and after it was written.
+    fn overflows(&self, before: &Stats, after: &Stats) -> bool {
+        before.records() > 0 && matches!(self.bytes, Some(max) if after.bytes() > max)
+    }
+}
+
+/// The writer for a single part, shared between `Part` and `StreamPart`.
+struct PartWriter {
+    writer: Writer,
+    split: Split,
+    index: usize,
+    /// Set once this part is full, or the input has ended.
+    done: bool,
+}
+
+/// The outcome of writing a single record to a part.
+enum Step<T> {
+    /// Yield this item from the part.
+    Yield(Result<Vec<u8>>),
+    /// The record was dropped by the error policy.
+    Skip,
+    /// The record did not fit into the part, and starts the next part.
+    Full(T),
+    /// The error policy has ended the output. Yield this error and stop.
+    Abort(Error),
+    /// A limit has ended the output. Yield the truncation comment, if any,
+    /// and stop.
+    Stop(Vec<u8>),
+}
+
+impl PartWriter {
+    fn new(writer: &Writer, split: Split, index: usize, field_count: Option<u64>) -> PartWriter {
+        let mut writer = writer.clone();
+        writer.set_first_field_count(field_count);
+        PartWriter {
+            writer,
+            split,
+            index,
+            done: false,
+        }
+    }
+
+    fn write<T: Serialize>(&mut self, record: T) -> Step<T> {
+        let mut buf = vec![];
+        let before = self.writer.stats();
+        let checkpoint = self.writer.checkpoint(&buf);
+        let err = match self.writer.serialize(&mut buf, &record) {
+            Ok(()) if self.split.overflows(&before, &self.writer.stats()) => {
+                self.writer.restore(&mut buf, checkpoint);
+                return Step::Full(record);
+            }
+            Ok(()) => return Step::Yield(Ok(buf)),
+            Err(err) => err,
+        };
+        self.recover(buf, err)
+    }
+
+    /// Check the limit on the number of records before another record is
+    /// read, so that no more input is read once it has ended the output.
+    fn check_record_limit<T>(&mut self) -> Option<Step<T>> {
+        let err = self.writer.check_record_limit().err()?;
+        Some(self.recover(vec![], err))
+    }
+
+    /// Decide what to do with an error from writing a record to `buf`.
+    fn recover<T>(&mut self, mut buf: Vec<u8>, err: Error) -> Step<T> {
+        match self.writer.recover(&mut buf, err) {
+            Recovery::Yield(err) => Step::Yield(Err(err)),
+            Recovery::Abort(err) => Step::Abort(err),
+            Recovery::Skip => Step::Skip,
+            Recovery::Placeholder => Step::Yield(Ok(buf)),
+            Recovery::Stop => Step::Stop(buf),
+        }
+    }
+}
+
+/// The input, shared between a split iterator or stream and its parts.
+struct Shared<I, T> {
+    input: I,
+    /// A record that was read, but has not been written to a part yet.
+    pending: Option<T>,
+    /// Set once the input is exhausted, or the error policy has decided that
+    /// no more records should be written.
+    done: bool,
+    /// The index of the part currently being written.
+    part: usize,
+    /// The number of fields per record, carried over from one part to the
+    /// next so that all records are checked against the same count.
+    field_count: Option<u64>,
+}
+
+/// An iterator over the parts of a split CSV output.
+///
+/// This is created by [`WriterBuilder::build_split_iter`](crate::WriterBuilder::build_split_iter).
+/// Each part is itself an iterator of chunks that make up a complete CSV
+/// file, starting with its own preamble and header row.
+///
+/// Parts are meant to be consumed in order. Moving on to the next part
+/// before the current one is exhausted ends the current part early, and the
+/// next part starts with the first record that was not written yet.
+pub struct SplitIter<I: Iterator> {
+    shared: Rc<RefCell<Shared<I, I::Item>>>,
+    writer: Writer,
+    split: Split,
+    next_index: usize,
+}
+
+impl<I: Iterator> SplitIter<I> {
+    pub fn new(iter: impl IntoIterator<IntoIter = I>, writer: Writer, split: Split) -> Self {
+        Self {
+            shared: Rc::new(RefCell::new(Shared {
+                input: iter.into_iter(),
+                pending: None,
+                done: false,
+                part: 0,
+                field_count: None,
+            })),
+            writer,
+            split,
+            next_index: 0,
+        }
+    }
+}
+
+impl<I: Iterator> Iterator for SplitIter<I>
+where
+    I::Item: Serialize,
+{
+    type Item = Part<I>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut shared = self.shared.borrow_mut();
+        if shared.done {
+            return None;
+        }
+        // Only start a part if there is at least one record to put in it.
+        if shared.pending.is_none() {
+            shared.pending = shared.input.next();
+            if shared.pending.is_none() {
+                shared.done = true;
+                return None;
+            }
+        }
+
+        let index = self.next_index;
+        self.next_index += 1;
+        shared.part = index;
+        Some(Part {
+            shared: self.shared.clone(),
+            part: PartWriter::new(&self.writer, self.split, index, shared.field_count),
+        })
+    }
+}
+
+/// A single part of a split CSV output.
+///
+/// This is an iterator of chunks, like [`Iter`](crate::Iter). See
+/// [`SplitIter`] for more details.
+pub struct Part<I: Iterator> {
+    shared: Rc<RefCell<Shared<I, I::Item>>>,
+    part: PartWriter,
+}
+
+impl<I: Iterator> Part<I> {
+    /// The index of this part, starting at `0`.
+    ///
+    /// This is useful for naming the file that the part is written to.
+    pub fn index(&self) -> usize {
+        self.part.index
+    }
+
+    /// Returns the number of records that have been dropped from this part so
+    /// far according to the configured [`ErrorPolicy`](crate::ErrorPolicy).
+    pub fn skipped(&self) -> u64 {
+        self.part.writer.skipped()
+    }
+
+    /// Returns statistics about the data written to this part so far.
+    pub fn stats(&self) -> Stats {
+        self.part.writer.stats()
+    }
+}
+
+impl<I: Iterator> Iterator for Part<I>
+where
+    I::Item: Serialize,
+{
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut shared = self.shared.borrow_mut();
+        while !self.part.done {
+            if shared.done
+                || shared.part != self.part.index
+                || self.part.split.is_full(&self.part.writer.stats())
+            {
+                self.part.done = true;
+                break;
+            }
+            let step = match shared.pending.take() {
+                Some(s) => self.part.write(s),
+                None => match self.part.check_record_limit() {
+                    Some(step) => step,
+                    None => match shared.input.next() {
+                        Some(s) => self.part.write(s),
+                        None => {
+                            shared.done = true;
+                            self.part.done = true;
+                            break;
+                        }
+                    },
+                },
+            };
+            shared.field_count = self.part.writer.first_field_count();
+            match step {
+                Step::Yield(res) => return Some(res),
+                Step::Skip => continue,
+                Step::Full(s) => {
+                    shared.pending = Some(s);
+                    self.part.done = true;
+                }
+                Step::Abort(err) => {
+                    shared.done = true;
+                    self.part.done = true;
+                    return Some(Err(err));
+                }
+                Step::Stop(buf) => {
+                    shared.done = true;
+                    self.part.done = true;
+                    if !buf.is_empty() {
+                        return Some(Ok(buf));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(feature = "stream")]
+pub use self::stream::{SplitStream, StreamPart};
+
+#[cfg(feature = "stream")]
+mod stream {
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex, MutexGuard};
+    use std::task::{Context, Poll};
+
+    use serde::Serialize;
+
+    use super::{PartWriter, Shared, Split, Step};
+    use crate::{Result, Stats, Writer};
+
+    type SharedStream<S> = Arc<Mutex<Shared<Pin<Box<S>>, <S as futures::Stream>::Item>>>;
+
+    fn lock<S: futures::Stream>(
+        shared: &SharedStream<S>,
+    ) -> MutexGuard<'_, Shared<Pin<Box<S>>, S::Item>> {
+        // A panic while serializing a record leaves the shared state
+        // consistent, so there's no need to propagate the poison.
+        shared.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// A stream of the parts of a split CSV output.
+    ///
+    /// This is created by [`WriterBuilder::build_split_stream`](crate::WriterBuilder::build_split_stream).
+    /// Each part is itself a stream of chunks that make up a complete CSV
+    /// file, starting with its own preamble and header row.
+    ///
+    /// Parts are meant to be consumed in order. Moving on to the next part
+    /// before the current one is exhausted ends the current part early, and
+    /// the next part starts with the first record that was not written yet.
+    pub struct SplitStream<S: futures::Stream> {
+        shared: SharedStream<S>,
+        writer: Writer,
+        split: Split,
+        next_index: usize,
+    }
+
+    impl<S: futures::Stream> SplitStream<S> {
+        pub fn new(stream: S, writer: Writer, split: Split) -> Self {
+            Self {
+                shared: Arc::new(Mutex::new(Shared {
+                    input: Box::pin(stream),
+                    pending: None,
+                    done: false,
+                    part: 0,
+                    field_count: None,
+                })),
+                writer,
+                split,
+                next_index: 0,
+            }
+        }
+    }
+
+    impl<S: futures::Stream> futures::Stream for SplitStream<S>
+    where
+        S::Item: Serialize,
+    {
+        type Item = StreamPart<S>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            let this = self.get_mut();
+            let mut shared = lock(&this.shared);
+            if shared.done {
+                return Poll::Ready(None);
+            }
+            // Only start a part if there is at least one record to put in it.
+            if shared.pending.is_none() {
+                match shared.input.as_mut().poll_next(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(None) => {
+                        shared.done = true;
+                        return Poll::Ready(None);
+                    }
+                    Poll::Ready(Some(s)) => shared.pending = Some(s),
+                }
+            }
+
+            let index = this.next_index;
+            this.next_index += 1;
+            shared.part = index;
+            Poll::Ready(Some(StreamPart {
+                shared: this.shared.clone(),
+                part: PartWriter::new(&this.writer, this.split, index, shared.field_count),
+            }))
+        }
+    }
+
+    /// A single part of a split CSV output.
+    ///
+    /// This is a stream of chunks, like [`Stream`](crate::Stream). See
+    /// [`SplitStream`] for more details.
+    pub struct StreamPart<S: futures::Stream> {
+        shared: SharedStream<S>,
+        part: PartWriter,
+    }
+
+    impl<S: futures::Stream> StreamPart<S> {
+        /// The index of this part, starting at `0`.
+        ///
+        /// This is useful for naming the file that the part is written to.
+        pub fn index(&self) -> usize {
+            self.part.index
+        }
+
+        /// Returns the number of records that have been dropped from this
+        /// part so far according to the configured
+        /// [`ErrorPolicy`](crate::ErrorPolicy).
+        pub fn skipped(&self) -> u64 {
+            self.part.writer.skipped()
+        }
+
+        /// Returns statistics about the data written to this part so far.
+        pub fn stats(&self) -> Stats {
+            self.part.writer.stats()
+        }
+    }
+
+    impl<S: futures::Stream> futures::Stream for StreamPart<S>
+    where
+        S::Item: Serialize,
+    {
+        type Item = Result<Vec<u8>>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            let this = self.get_mut();
+            let mut shared = lock(&this.shared);
+            while !this.part.done {
+                if shared.done
+                    || shared.part != this.part.index
+                    || this.part.split.is_full(&this.part.writer.stats())
+                {
+                    this.part.done = true;
+                    break;
+                }
+                let step = match shared.pending.take() {
+                    Some(s) => this.part.write(s),
+                    None => match this.part.check_record_limit() {
+                        Some(step) => step,
+                        None => match shared.input.as_mut().poll_next(cx) {
+                            Poll::Pending => return Poll::Pending,
+                            Poll::Ready(None) => {
+                                shared.done = true;
+                                this.part.done = true;
+                                break;
+                            }
+                            Poll::Ready(Some(s)) => this.part.write(s),
+                        },
+                    },
+                };
+                shared.field_count = this.part.writer.first_field_count();
+                match step {
+                    Step::Yield(res) => return Poll::Ready(Some(res)),
+                    Step::Skip => continue,
+                    Step::Full(s) => {
+                        shared.pending = Some(s);
+                        this.part.done = true;
+                    }
+                    Step::Abort(err) => {
+                        shared.done = true;
+                        this.part.done = true;
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                    Step::Stop(buf) => {
+                        shared.done = true;
+                        this.part.done = true;
+                        if !buf.is_empty() {
+                            return Poll::Ready(Some(Ok(buf)));
+                        }
+                    }
+                }
+            }
+            Poll::Ready(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use serde::Serialize;
+
+    use crate::{ErrorPolicy, LimitPolicy, Split, WriterBuilder};
+
+    #[derive(Serialize)]
+    struct Row {
+        foo: u32,
+        bar: &'static str,
+    }
+
+    fn rows(n: u32) -> impl Iterator<Item = Row> {
+        (1..=n).map(|foo| Row { foo, bar: "x" })
+    }
+
+    fn collect_parts<I>(parts: I) -> Vec<String>
+    where
+        I: Iterator,
+        I::Item: Iterator<Item = crate::Result<Vec<u8>>>,
+    {
+        parts
+            .map(|part| {
+                let buf: Vec<u8> = part.flat_map(Result::unwrap).collect();
+                String::from_utf8(buf).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn split_max_records() {
+        let read = Rc::new(Cell::new(0));
+        let counter = read.clone();
+        let rows = rows(10).inspect(move |_| counter.set(counter.get() + 1));
+        let split = Split::default().max_records(3);
+        let parts = WriterBuilder::default()
+            .max_records(2, LimitPolicy::Truncate)
+            .build_split_iter(rows, split);
+
+        assert_eq!(collect_parts(parts), ["foo,bar\n1,x\n2,x\n"]);
+        assert_eq!(read.get(), 2);
+    }
+
+    #[test]
+    fn split_records() {
+        let split = Split::default().max_records(2);
+        let parts = WriterBuilder::default().build_split_iter(rows(5), split);
+
+        assert_eq!(
+            collect_parts(parts),
+            [
+                "foo,bar\n1,x\n2,x\n",
+                "foo,bar\n3,x\n4,x\n",
+                "foo,bar\n5,x\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn split_records_exact() {
+        let split = Split::default().max_records(2);
+        let parts = WriterBuilder::default().build_split_iter(rows(4), split);
+
+        assert_eq!(collect_parts(parts).len(), 2);
+    }
+
+    #[test]
+    fn split_bytes() {
+        // The header takes 8 bytes, and every record 4 bytes.
+        let split = Split::default().max_bytes(16);
+        let parts = WriterBuilder::default().build_split_iter(rows(5), split);
+
+        assert_eq!(
+            collect_parts(parts),
+            [
+                "foo,bar\n1,x\n2,x\n",
+                "foo,bar\n3,x\n4,x\n",
+                "foo,bar\n5,x\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn split_bytes_oversized_record() {
+        let split = Split::default().max_bytes(4);
+        let parts = WriterBuilder::default().build_split_iter(rows(2), split);
+
+        assert_eq!(collect_parts(parts), ["foo,bar\n1,x\n", "foo,bar\n2,x\n"]);
+    }
+
+    #[test]
+    fn split_preamble() {
+        let split = Split::default().max_records(1);
+        let parts = WriterBuilder::default()
+            .comment(b'#')
+            .preamble(["exported"])
+            .build_split_iter(rows(2), split);
+
+        assert_eq!(
+            collect_parts(parts),
+            ["#exported\nfoo,bar\n1,x\n", "#exported\nfoo,bar\n2,x\n"]
+        );
+    }
+
+    #[test]
+    fn split_index_and_stats() {
+        let split = Split::default().max_records(2);
+        let mut parts = WriterBuilder::default().build_split_iter(rows(3), split);
+
+        let mut first = parts.next().unwrap();
+        assert_eq!(first.index(), 0);
+        assert!(first.by_ref().all(|r| r.is_ok()));
+        assert_eq!(first.stats().records(), 2);
+
+        let mut second = parts.next().unwrap();
+        assert_eq!(second.index(), 1);
+        assert!(second.by_ref().all(|r| r.is_ok()));
+        assert_eq!(second.stats().records(), 1);
+
+        assert!(parts.next().is_none());
+    }
+
+    #[test]
+    fn split_abandoned_part() {
+        let split = Split::default().max_records(3);
+        let mut parts = WriterBuilder::default().build_split_iter(rows(4), split);
+
+        let mut first = parts.next().unwrap();
+        first.next().unwrap().unwrap();
+        first.next().unwrap().unwrap();
+
+        // Starting the next part ends the first one.
+        let second = parts.next().unwrap();
+        assert!(first.next().is_none());
+
+        assert_eq!(
+            collect_parts(std::iter::once(second)),
+            ["foo,bar\n3,x\n4,x\n"]
+        );
+        assert!(parts.next().is_none());
+    }
+
+    #[test]
+    fn split_abort() {
+        let split = Split::default().max_records(1);
+        let mut parts = WriterBuilder::default()
+            .error_policy(ErrorPolicy::Abort)
+            .build_split_iter(vec![vec![1, 2], vec![1, 2, 3], vec![1, 2]], split);
+
+        let first = parts.next().unwrap();
+        assert_eq!(collect_parts(std::iter::once(first)), ["1,2\n"]);
+
+        // A new part starts with the second record, which is then rejected.
+        let mut second = parts.next().unwrap();
+        assert!(second.next().unwrap().is_err());
+        assert!(second.next().is_none());
+        assert!(parts.next().is_none());
+    }
+
+    #[cfg(feature = "stream")]
+    #[tokio::test]
+    async fn split_stream() {
+        use futures::StreamExt;
+
+        let split = Split::default().max_records(2);
+        let stream = futures::stream::iter(rows(3));
+        let mut parts = WriterBuilder::default().build_split_stream(stream, split);
+
+        let mut data = vec![];
+        while let Some(part) = parts.next().await {
+            let index = part.index();
+            let buf: Vec<u8> = part.map(Result::unwrap).concat().await;
+            data.push((index, String::from_utf8(buf).unwrap()));
+        }
+
+        assert_eq!(
+            data,
+            [
+                (0, "foo,bar\n1,x\n2,x\n".to_owned()),
+                (1, "foo,bar\n3,x\n".to_owned())
+            ]
+        );
+    }
+}
@@ -0,0 +1,403 @@
+use pin_project::pin_project;
+use serde::{Serialize, Serializer};
+use sqlx::error::BoxDynError;
+#[cfg(feature = "sqlx-postgres")]
+use sqlx::postgres::{PgRow, PgTypeKind, PgValueFormat, Postgres};
+#[cfg(feature = "sqlx-sqlite")]
+use sqlx::sqlite::{Sqlite, SqliteRow};
+use sqlx::{Column, Decode, Row as _, TypeInfo, ValueRef};
+
+use crate::error::{Error, ErrorKind};
+use crate::writer::Recovery;
+use crate::{Result, Stats, Writer};
+
+/// A Streamable CSV creator for database rows
+///
+/// This requires the feature for the database the rows come from: enable
+/// `sqlx-sqlite` for SQLite rows, `sqlx-postgres` for Postgres rows, or both.
+///
+/// This writes the rows of any query, such as an ad-hoc `SELECT` that has no
+/// Rust struct to deserialize into. The header row is taken from the column
+/// names of the first row, so nothing is written for a query that returns no
+/// rows. Each value is converted according to its SQL type:
+///
+/// * `NULL` is written as an empty field.
+/// * Booleans are written as `true` or `false`.
+/// * Integers and floating point numbers are written as numbers.
+/// * Text is written as is, and binary data is written as raw bytes.
+///
+/// For SQLite, values are converted by their storage class, except that
+/// integers in a column declared as `BOOLEAN` are written as booleans. For
+/// Postgres, text-like types, `BYTEA`, `JSON`, `JSONB` and enums are
+/// supported as well, and any value sent in the text format is written as
+/// is. `NUMERIC`, `UUID`, date and time types, `INTERVAL` and arrays of
+/// supported types are written as Postgres prints them, such as
+/// `2024-01-02 03:04:05+00` or `{1,NULL,3}`, with times in `UTC`. Any other
+/// value is an error for that row, which is handled according to the
+/// configured [`ErrorPolicy`](crate::ErrorPolicy).
+///
+/// Errors from the database are yielded as
+/// [`ErrorKind::Database`](crate::ErrorKind::Database).
+///
+/// # Example
+///
+#[cfg_attr(feature = "sqlx-sqlite", doc = "```")]
+#[cfg_attr(not(feature = "sqlx-sqlite"), doc = "```ignore")]
+/// use std::error::Error;
+/// use csv_stream::WriterBuilder;
+/// use futures::TryStreamExt;
+///
+/// # #[tokio::main]
+/// # async fn main() { example().await.unwrap(); }
+/// async fn example() -> Result<(), Box<dyn Error>> {
+///     let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+///     let rows = sqlx::query("SELECT 1 AS foo, 'a,b' AS bar").fetch(&pool);
+///
+///     let csv_stream = WriterBuilder::default().build_row_stream(rows);
+///     let buf: Vec<Vec<u8>> = csv_stream.try_collect().await?;
+///
+///     let data = String::from_utf8(buf.concat())?;
+///     assert_eq!(data, "foo,bar\n1,\"a,b\"\n");
+///     Ok(())
+/// }
+/// ```
+#[pin_project]
+pub struct RowStream<S> {
+    #[pin]
+    stream: S,
+
+    writer: Writer,
+    /// Set once the input is exhausted, or the error policy or a limit has
+    /// decided that no more rows should be written.
+    done: bool,
+}
+
+impl<S> RowStream<S> {
+    pub fn new(stream: S, writer: Writer) -> Self {
+        Self {
+            stream,
+            writer,
+            done: false,
+        }
+    }
+
+    /// Returns the number of rows that have been dropped so far according
+    /// to the configured [`ErrorPolicy`](crate::ErrorPolicy).
+    pub fn skipped(&self) -> u64 {
+        self.writer.skipped()
+    }
+
+    /// Returns statistics about the data written so far.
+    pub fn stats(&self) -> Stats {
+        self.writer.stats()
+    }
+
+    /// Returns the writer used to write records, to inspect its
+    /// configuration.
+    pub fn writer(&self) -> &Writer {
+        &self.writer
+    }
+}
+
+impl<S, R> futures::Stream for RowStream<S>
+where
+    S: futures::Stream<Item = std::result::Result<R, sqlx::Error>>,
+    R: private::Row,
+{
+    type Item = Result<Vec<u8>>;
+
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        let mut p = self.project();
+        while !*p.done {
+            let mut buf = vec![];
+            // Don't fetch another row once a limit has ended the output.
+            let err = if let Err(err) = p.writer.check_record_limit() {
+                err
+            } else {
+                let row = match futures::ready!(p.stream.as_mut().poll_next(cx)) {
+                    Some(Ok(row)) => row,
+                    Some(Err(err)) => {
+                        let err = Error::new(ErrorKind::Database(err));
+                        let err = err.or_position(p.writer.position());
+                        return std::task::Poll::Ready(Some(Err(err)));
+                    }
+                    None => {
+                        *p.done = true;
+                        return std::task::Poll::Ready(None);
+                    }
+                };
+
+                let checkpoint = p.writer.checkpoint(&buf);
+                match write_row(p.writer, &mut buf, &row) {
+                    Ok(()) => return std::task::Poll::Ready(Some(Ok(buf))),
+                    Err(err) => {
+                        p.writer.restore(&mut buf, checkpoint);
+                        err
+                    }
+                }
+            };
+            match p.writer.recover(&mut buf, err) {
+                Recovery::Yield(err) => return std::task::Poll::Ready(Some(Err(err))),
+                Recovery::Abort(err) => {
+                    *p.done = true;
+                    return std::task::Poll::Ready(Some(Err(err)));
+                }
+                Recovery::Skip => continue,
+                Recovery::Placeholder => return std::task::Poll::Ready(Some(Ok(buf))),
+                Recovery::Stop => {
+                    *p.done = true;
+                    if !buf.is_empty() {
+                        return std::task::Poll::Ready(Some(Ok(buf)));
+                    }
+                }
+            }
+        }
+        std::task::Poll::Ready(None)
+    }
+}
+
+/// Write a row, along with the header row if it has not been written yet.
+fn write_row<R: private::Row>(writer: &mut Writer, buf: &mut Vec<u8>, row: &R) -> Result<()> {
+    let values = (0..row.len())
+        .map(|i| {
+            row.value(i).map_err(|err| {
+                let err = Error::new(ErrorKind::Serialize(err.to_string()));
+                err.or_field(i as u64, Some(row.columns()[i].name()))
+                    .or_position(writer.position())
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    writer.write_header(buf, row.columns().iter().map(|column| column.name()))?;
+    writer.serialize(buf, values)
+}
+
+mod private {
+    use sqlx::error::BoxDynError;
+
+    /// A database row that can be written as a CSV record.
+    pub trait Row: sqlx::Row {
+        /// Convert the value of the column at `index` by its SQL type.
+        fn value(&self, index: usize) -> Result<Value<'_>, BoxDynError>;
+    }
+
+    /// A database value, borrowed from its row.
+    pub enum Value<'r> {
+        Null,
+        Bool(bool),
+        I64(i64),
+        F32(f32),
+        F64(f64),
+        Str(&'r str),
+        String(String),
+        Bytes(&'r [u8]),
+    }
+}
+
+use self::private::Value;
+
+impl Serialize for Value<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match *self {
+            Value::Null => serializer.serialize_none(),
+            Value::Bool(v) => serializer.serialize_bool(v),
+            Value::I64(v) => serializer.serialize_i64(v),
+            Value::F32(v) => serializer.serialize_f32(v),
+            Value::F64(v) => serializer.serialize_f64(v),
+            Value::Str(v) => serializer.serialize_str(v),
+            Value::String(ref v) => serializer.serialize_str(v),
+            Value::Bytes(v) => serializer.serialize_bytes(v),
+        }
+    }
+}
+
+#[cfg(any(feature = "sqlx-sqlite", feature = "sqlx-postgres"))]
+fn unsupported(name: &str) -> BoxDynError {
+    format!("unsupported SQL type {}", name).into()
+}
+
+#[cfg(feature = "sqlx-sqlite")]
+impl private::Row for SqliteRow {
+    fn value(&self, index: usize) -> std::result::Result<Value<'_>, BoxDynError> {
+        let value = self.try_get_raw(index)?;
+        if value.is_null() {
+            return Ok(Value::Null);
+        }
+        let is_bool = self.column(index).type_info().name() == "BOOLEAN";
+        let storage = value.type_info().name().to_owned();
+        Ok(match storage.as_str() {
+            "INTEGER" if is_bool => Value::Bool(<_ as Decode<Sqlite>>::decode(value)?),
+            "INTEGER" => Value::I64(<_ as Decode<Sqlite>>::decode(value)?),
+            "REAL" => Value::F64(<_ as Decode<Sqlite>>::decode(value)?),
+            "TEXT" => Value::Str(<_ as Decode<Sqlite>>::decode(value)?),
+            "BLOB" => Value::Bytes(<_ as Decode<Sqlite>>::decode(value)?),
+            name => return Err(unsupported(name)),
+        })
+    }
+}
+
+#[cfg(feature = "sqlx-postgres")]
+impl private::Row for PgRow {
+    fn value(&self, index: usize) -> std::result::Result<Value<'_>, BoxDynError> {
+        let value = self.try_get_raw(index)?;
+        if value.is_null() {
+            return Ok(Value::Null);
+        }
+        let ty = value.type_info().into_owned();
+        Ok(match ty.name() {
+            "BOOL" => Value::Bool(<_ as Decode<Postgres>>::decode(value)?),
+            "INT2" => Value::I64(<i16 as Decode<Postgres>>::decode(value)?.into()),
+            "INT4" => Value::I64(<i32 as Decode<Postgres>>::decode(value)?.into()),
+            "INT8" => Value::I64(<_ as Decode<Postgres>>::decode(value)?),
+            "FLOAT4" => Value::F32(<_ as Decode<Postgres>>::decode(value)?),
+            "FLOAT8" => Value::F64(<_ as Decode<Postgres>>::decode(value)?),
+            "TEXT" | "VARCHAR" | "CHAR" | "NAME" | "UNKNOWN" | "CITEXT" => {
+                Value::Str(<_ as Decode<Postgres>>::decode(value)?)
+            }
+            "BYTEA" => Value::Bytes(<_ as Decode<Postgres>>::decode(value)?),
+            // Binary `JSONB` starts with a version number before the JSON
+            // text.
+            "JSONB" if value.format() == PgValueFormat::Binary => match value.as_bytes()? {
+                [1, json @ ..] => Value::Str(std::str::from_utf8(json)?),
+                _ => return Err("unsupported JSONB version".into()),
+            },
+            _ if value.format() == PgValueFormat::Text => Value::Str(value.as_str()?),
+            "JSON" | "JSONB" => Value::Str(value.as_str()?),
+            _ if matches!(ty.kind(), PgTypeKind::Enum(_)) => Value::Str(value.as_str()?),
+            name => {
+                let oid = ty.oid().map_or(0, |oid| oid.0);
+                let is_array = matches!(ty.kind(), PgTypeKind::Array(_));
+                match crate::postgres::to_text(oid, is_array, value.as_bytes()?)? {
+                    Some(text) => Value::String(text),
+                    None => return Err(unsupported(name)),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(all(test, feature = "sqlx-sqlite"))]
+mod tests {
+    use futures::TryStreamExt;
+    use sqlx::{Connection, SqliteConnection};
+
+    use crate::{ErrorKind, ErrorPolicy, LimitPolicy, WriterBuilder};
+
+    async fn connect() -> SqliteConnection {
+        let mut conn = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        sqlx::raw_sql(
+            "CREATE TABLE cities (
+                name TEXT NOT NULL,
+                population INTEGER,
+                area REAL,
+                capital BOOLEAN,
+                flag BLOB
+            );
+            INSERT INTO cities VALUES
+                ('Boston', 4628910, 232.1, 1, x'6869'),
+                ('Concord, NH', NULL, 66.3, 0, NULL),
+                ('Say \"hi\"', -1, NULL, NULL, x'');",
+        )
+        .execute(&mut conn)
+        .await
+        .unwrap();
+        conn
+    }
+
+    #[tokio::test]
+    async fn sql_types() {
+        let mut conn = connect().await;
+        let rows = sqlx::query("SELECT * FROM cities").fetch(&mut conn);
+        let csv_stream = WriterBuilder::default().build_row_stream(rows);
+        let buf: Vec<Vec<u8>> = csv_stream.try_collect().await.unwrap();
+
+        assert_eq!(
+            String::from_utf8(buf.concat()).unwrap(),
+            "name,population,area,capital,flag\n\
+             Boston,4628910,232.1,true,hi\n\
+             \"Concord, NH\",,66.3,false,\n\
+             \"Say \"\"hi\"\"\",-1,,,\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn expressions() {
+        let mut conn = connect().await;
+        let rows = sqlx::query(
+            "SELECT count(*) AS count, avg(area) AS area, group_concat(name, ';') AS names \
+             FROM cities",
+        )
+        .fetch(&mut conn);
+        let mut csv_stream = WriterBuilder::default()
+            .delimiter(b';')
+            .build_row_stream(rows);
+
+        let mut buf = vec![];
+        while let Some(row) = csv_stream.try_next().await.unwrap() {
+            buf.extend_from_slice(&row);
+        }
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "count;area;names\n3;149.2;\"Boston;Concord, NH;Say \"\"hi\"\"\"\n"
+        );
+        assert_eq!(csv_stream.stats().records(), 1);
+    }
+
+    #[tokio::test]
+    async fn no_rows() {
+        let mut conn = connect().await;
+        let rows = sqlx::query("SELECT * FROM cities WHERE population > 1e9").fetch(&mut conn);
+        let csv_stream = WriterBuilder::default()
+            .error_policy(ErrorPolicy::Abort)
+            .build_row_stream(rows);
+        let buf: Vec<Vec<u8>> = csv_stream.try_collect().await.unwrap();
+        assert!(buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn error_policy_abort() {
+        use futures::StreamExt;
+
+        let mut conn = connect().await;
+        let rows = sqlx::query("SELECT name FROM cities").fetch(&mut conn);
+        // Every row after the first has a name that is too long.
+        let csv_stream = WriterBuilder::default()
+            .max_field_len(6, LimitPolicy::Error)
+            .error_policy(ErrorPolicy::Abort)
+            .build_row_stream(rows);
+
+        let rows: Vec<_> = csv_stream.collect().await;
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].as_ref().unwrap(), b"name\nBoston\n");
+        assert!(rows[1].is_err());
+    }
+
+    #[tokio::test]
+    async fn limit_stop() {
+        let mut conn = connect().await;
+        let rows = sqlx::query("SELECT name FROM cities").fetch(&mut conn);
+        let csv_stream = WriterBuilder::default()
+            .comment(b'#')
+            .max_records(1, LimitPolicy::Stop)
+            .build_row_stream(rows);
+        let buf: Vec<Vec<u8>> = csv_stream.try_collect().await.unwrap();
+        assert_eq!(
+            String::from_utf8(buf.concat()).unwrap(),
+            "name\nBoston\n#truncated: more than the maximum of 1 records\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn database_error() {
+        let mut conn = connect().await;
+        let rows = sqlx::query("SELECT * FROM towns").fetch(&mut conn);
+        let mut csv_stream = WriterBuilder::default().build_row_stream(rows);
+
+        let err = csv_stream.try_next().await.unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Database(_)));
+        assert!(err.to_string().contains("no such table: towns"), "{}", err);
+    }
+}
@@ -1,7 +1,11 @@
+use std::collections::VecDeque;
+
 use pin_project::pin_project;
 use serde::Serialize;
 
-use crate::{Result, Writer};
+use crate::serializer::{capture_fields, Fields};
+use crate::writer::{Recovery, Trailer};
+use crate::{Result, Stats, Writer};
 
 /// A Streamable CSV creator
 ///
@@ -44,10 +48,82 @@ pub struct Stream<S> {
     stream: S,
 
     writer: Writer,
+    /// Set once the input is exhausted, or the error policy has decided that
+    /// no more records should be written.
+    done: bool,
+    trailer: Option<Trailer>,
+    /// Records read ahead of time to infer the header row.
+    captured: VecDeque<Result<Fields>>,
+    /// Set once the input stream has ended while reading ahead.
+    exhausted: bool,
+    #[cfg(feature = "http-body")]
+    body: crate::body::BodyState,
 }
 impl<S> Stream<S> {
     pub fn new(stream: S, writer: Writer) -> Self {
-        Self { stream, writer }
+        Self {
+            stream,
+            writer,
+            done: false,
+            trailer: None,
+            captured: VecDeque::new(),
+            exhausted: false,
+            #[cfg(feature = "http-body")]
+            body: Default::default(),
+        }
+    }
+
+    /// Set a hook to be called once the input stream is exhausted, to write
+    /// any final records such as a trailer or summary record.
+    ///
+    /// The hook receives the statistics of the data written so far and the
+    /// writer, with a buffer to write into. Whatever it writes is yielded as
+    /// the final item of the stream. Use [`Writer::write_flexible_record`]
+    /// to write records that do not have the same number of fields as the
+    /// data.
+    ///
+    /// The hook is not called if the stream was ended early by
+    /// [`ErrorPolicy::Abort`](crate::ErrorPolicy::Abort).
+    pub fn trailer<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&Stats, &mut Writer, &mut Vec<u8>) -> Result<()> + Send + 'static,
+    {
+        self.trailer = Some(Box::new(f));
+        self
+    }
+
+    /// Returns the number of records that have been dropped so far according
+    /// to the configured [`ErrorPolicy`](crate::ErrorPolicy).
+    pub fn skipped(&self) -> u64 {
+        self.writer.skipped()
+    }
+
+    /// Returns statistics about the data written so far.
+    ///
+    /// This can be called while streaming, or once the stream is finished.
+    pub fn stats(&self) -> Stats {
+        self.writer.stats()
+    }
+
+    /// Returns the writer used to write records, to inspect its
+    /// configuration.
+    pub fn writer(&self) -> &Writer {
+        &self.writer
+    }
+
+    /// Set a hook to be called once all records have been written, to
+    /// produce the HTTP trailers sent at the end of the body, such as a row
+    /// count or checksum.
+    ///
+    /// See [`Iter::http_trailers`](crate::Iter::http_trailers) for more
+    /// details.
+    #[cfg(feature = "http-body")]
+    pub fn http_trailers<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&Stats) -> http::HeaderMap + Send + 'static,
+    {
+        self.body.trailers = Some(Box::new(f));
+        self
     }
 }
 
@@ -61,26 +137,101 @@ where
         self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
+        let mut p = self.project();
+        if let Some(window) = p.writer.infer_window() {
+            while p.captured.len() < window && !*p.exhausted {
+                match p.stream.as_mut().poll_next(cx) {
+                    std::task::Poll::Pending => return std::task::Poll::Pending,
+                    std::task::Poll::Ready(Some(s)) => p.captured.push_back(capture_fields(s)),
+                    std::task::Poll::Ready(None) => *p.exhausted = true,
+                }
+            }
+            p.writer.infer_columns(&*p.captured);
+        }
+        while !*p.done {
+            let mut buf = vec![];
+            let res = if let Some(fields) = p.captured.pop_front() {
+                p.writer.write_fields(&mut buf, fields)
+            } else if let Err(err) = p.writer.check_record_limit() {
+                Err(err)
+            } else {
+                let s = if *p.exhausted {
+                    None
+                } else {
+                    futures::ready!(p.stream.as_mut().poll_next(cx))
+                };
+                match s {
+                    Some(s) => p.writer.serialize(&mut buf, s),
+                    None => {
+                        *p.done = true;
+                        return std::task::Poll::Ready(p.writer.finish(vec![], p.trailer.take()));
+                    }
+                }
+            };
+            let err = match res {
+                Ok(()) => return std::task::Poll::Ready(Some(Ok(buf))),
+                Err(err) => err,
+            };
+            match p.writer.recover(&mut buf, err) {
+                Recovery::Yield(err) => return std::task::Poll::Ready(Some(Err(err))),
+                Recovery::Abort(err) => {
+                    *p.done = true;
+                    return std::task::Poll::Ready(Some(Err(err)));
+                }
+                Recovery::Skip => continue,
+                Recovery::Placeholder => return std::task::Poll::Ready(Some(Ok(buf))),
+                Recovery::Stop => {
+                    *p.done = true;
+                    return std::task::Poll::Ready(p.writer.finish(buf, p.trailer.take()));
+                }
+            }
+        }
+        std::task::Poll::Ready(None)
+    }
+}
+
+#[cfg(feature = "http-body")]
+impl<S: futures::Stream> http_body::Body for Stream<S>
+where
+    S::Item: Serialize,
+{
+    type Data = bytes::Bytes;
+    type Error = crate::Error;
+
+    fn poll_frame(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<http_body::Frame<bytes::Bytes>>>> {
+        let item = futures::ready!(futures::Stream::poll_next(self.as_mut(), cx));
         let p = self.project();
-        let s = match p.stream.poll_next(cx) {
-            std::task::Poll::Pending => return std::task::Poll::Pending,
-            std::task::Poll::Ready(None) => return std::task::Poll::Ready(None),
-            std::task::Poll::Ready(Some(s)) => s,
-        };
+        let writer = &*p.writer;
+        std::task::Poll::Ready(p.body.frame(item, || writer.stats()))
+    }
 
-        let mut buf = vec![];
-        p.writer.serialize(&mut buf, s)?;
-        std::task::Poll::Ready(Some(Ok(buf)))
+    fn is_end_stream(&self) -> bool {
+        self.done && self.body.trailers.is_none()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        if self.is_end_stream() {
+            http_body::SizeHint::with_exact(0)
+        } else {
+            http_body::SizeHint::new()
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{Terminator, WriterBuilder};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::{ErrorPolicy, LimitPolicy, Terminator, WriterBuilder};
     use serde::Serialize;
 
     use super::Stream;
-    use futures::StreamExt;
+    use crate::test_util::Fallible;
+    use futures::{StreamExt, TryStreamExt};
 
     #[derive(Serialize)]
     struct Row<'a> {
@@ -158,4 +309,110 @@ Concord;United States;42695
 "#.replace("\n", "\r\n")
         )
     }
+
+    #[tokio::test]
+    async fn error_policy_abort() {
+        let rows = [Fallible(Some(1)), Fallible(None), Fallible(Some(3))];
+        let row_stream = futures::stream::iter(rows);
+        let csv_stream = WriterBuilder::default()
+            .error_policy(ErrorPolicy::Abort)
+            .build_stream(row_stream);
+
+        let rows: Vec<_> = csv_stream.collect().await;
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].as_ref().unwrap(), b"1,2\n");
+        assert!(rows[1].is_err());
+    }
+
+    #[tokio::test]
+    async fn trailer() {
+        let row_stream = futures::stream::iter(ROWS);
+        let csv_stream = WriterBuilder::default().build_stream(row_stream);
+        let csv_stream = csv_stream.trailer(|stats, wtr, buf| {
+            let count = stats.records().to_string();
+            wtr.write_flexible_record(buf, ["TRAILER", &count])
+        });
+
+        let buf = csv_stream
+            .map(Result::unwrap)
+            .map(futures::stream::iter)
+            .flatten()
+            .collect()
+            .await;
+
+        let buf = String::from_utf8(buf).unwrap();
+
+        assert_eq!(
+            buf,
+            r#"city,country,popcount
+Boston,United States,4628910
+Concord,United States,42695
+TRAILER,2
+"#
+        )
+    }
+
+    #[tokio::test]
+    async fn max_records() {
+        let read = Arc::new(AtomicUsize::new(0));
+        let counter = read.clone();
+        let rows = futures::stream::iter(1..).inspect(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let csv_stream = WriterBuilder::default()
+            .comment(b'#')
+            .max_records(2, LimitPolicy::Stop)
+            .build_stream(rows);
+
+        let buf: Vec<Vec<u8>> = csv_stream.try_collect().await.unwrap();
+        assert_eq!(
+            String::from_utf8(buf.concat()).unwrap(),
+            "1\n2\n#truncated: more than the maximum of 2 records\n"
+        );
+        assert_eq!(read.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn infer_headers() {
+        let rows = [
+            serde_json::json!({ "b": 1 }),
+            serde_json::json!({ "a": 2, "b": 3 }),
+            serde_json::json!({ "a": 4 }),
+        ];
+        let csv_stream = WriterBuilder::default()
+            .infer_headers(10)
+            .build_stream(futures::stream::iter(rows));
+
+        let buf = csv_stream
+            .map(Result::unwrap)
+            .map(futures::stream::iter)
+            .flatten()
+            .collect()
+            .await;
+
+        let buf = String::from_utf8(buf).unwrap();
+        assert_eq!(buf, "b,a\n1,\n3,2\n,4\n");
+    }
+
+    #[cfg(feature = "http-body")]
+    #[tokio::test]
+    async fn http_body() {
+        use http_body_util::BodyExt;
+
+        let row_stream = futures::stream::iter(ROWS);
+        let body = WriterBuilder::default()
+            .build_stream(row_stream)
+            .http_trailers(|stats| {
+                let mut trailers = http::HeaderMap::new();
+                trailers.insert("x-row-count", stats.records().into());
+                trailers
+            });
+
+        let collected = BodyExt::collect(body).await.unwrap();
+        assert_eq!(collected.trailers().unwrap()["x-row-count"], "2");
+        assert_eq!(
+            &collected.to_bytes()[..],
+            b"city,country,popcount\nBoston,United States,4628910\nConcord,United States,42695\n"
+        );
+    }
 }
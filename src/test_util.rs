@@ -0,0 +1,51 @@
+//! Fixtures shared by the tests of the iterators and streams.
+//!
+//! Not every fixture is used with every set of features.
+#![allow(dead_code)]
+
+use serde::{ser, Serialize, Serializer};
+
+#[derive(Serialize)]
+pub(crate) struct Row {
+    pub(crate) foo: usize,
+    pub(crate) bar: String,
+}
+
+/// `n` rows, each with a field that needs quoting.
+pub(crate) fn rows(n: usize) -> impl Iterator<Item = Row> {
+    (0..n).map(|foo| Row {
+        foo,
+        bar: format!("x,{}", foo),
+    })
+}
+
+/// A row that fails to serialize when it has no value.
+pub(crate) struct Fallible(pub(crate) Option<u32>);
+
+impl Serialize for Fallible {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            Some(x) => (x, x * 2).serialize(s),
+            None => Err(ser::Error::custom("missing value")),
+        }
+    }
+}
+
+/// Concatenate the output of an iterator into a string, panicking on any
+/// error.
+pub(crate) fn collect<I: Iterator<Item = crate::Result<Vec<u8>>>>(iter: I) -> String {
+    let buf: Vec<u8> = iter.flat_map(Result::unwrap).collect();
+    String::from_utf8(buf).unwrap()
+}
+
+/// Concatenate the output of a stream into a string, panicking on any error.
+#[cfg(feature = "stream")]
+pub(crate) async fn collect_stream<S>(stream: S) -> String
+where
+    S: futures::Stream<Item = crate::Result<Vec<u8>>>,
+{
+    use futures::StreamExt;
+
+    let buf: Vec<u8> = stream.map(Result::unwrap).concat().await;
+    String::from_utf8(buf).unwrap()
+}
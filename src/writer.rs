@@ -1,9 +1,19 @@
-use csv_core::{self, WriteResult, Writer as CoreWriter, WriterBuilder as CoreWriterBuilder};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use bstr::ByteSlice;
+use csv_core::{self, Writer as CoreWriter, WriterBuilder as CoreWriterBuilder};
 use serde::Serialize;
 
-use crate::error::{Error, ErrorKind, Result};
-use crate::serializer::{serialize, serialize_header};
-use crate::{QuoteStyle, Terminator};
+use crate::error::{Error, ErrorKind, Limit, Position, Result};
+use crate::serializer::{capture_fields, serialize, serialize_header, Fields, Scalar};
+use crate::{
+    BytesEncoding, CsvBuf, ErrorPolicy, HeaderOrder, LimitPolicy, QuoteStyle, Sanitize, Split,
+    Terminator,
+};
 
 /// Builds a CSV writer with various configuration knobs.
 ///
@@ -13,18 +23,137 @@ use crate::{QuoteStyle, Terminator};
 #[derive(Debug)]
 pub struct WriterBuilder {
     builder: CoreWriterBuilder,
+    quote_style: QuoteStyle,
     capacity: usize,
     flexible: bool,
     has_headers: bool,
+    comment: Option<u8>,
+    preamble: Vec<String>,
+    error_policy: ErrorPolicy,
+    on_skip: Option<SkipHook>,
+    infer_headers: usize,
+    header_order: HeaderOrder,
+    bytes_encoding: BytesEncoding,
+    limits: Limits,
+    sanitize: Sanitize,
+    replacement: Arc<str>,
+    column_quotes: ColumnQuotes,
+}
+
+/// A callback invoked with the error for every record that is skipped.
+#[derive(Clone)]
+struct SkipHook(Arc<dyn Fn(&Error) + Send + Sync>);
+
+impl fmt::Debug for SkipHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SkipHook")
+    }
+}
+
+/// The quoting styles that override the global quoting style for some
+/// columns.
+#[derive(Clone, Debug, Default)]
+struct ColumnQuotes {
+    by_name: HashMap<String, QuoteStyle>,
+    by_index: HashMap<usize, QuoteStyle>,
+}
+
+impl ColumnQuotes {
+    fn is_empty(&self) -> bool {
+        self.by_name.is_empty() && self.by_index.is_empty()
+    }
+}
+
+/// The limits on the size of the output, each with the policy to apply when
+/// it is exceeded.
+#[derive(Clone, Debug)]
+struct Limits {
+    field_len: Option<(u64, LimitPolicy)>,
+    record_len: Option<(u64, LimitPolicy)>,
+    records: Option<(u64, LimitPolicy)>,
+    bytes: Option<(u64, LimitPolicy)>,
+    /// The marker that truncated fields end with.
+    ellipsis: Arc<str>,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            field_len: None,
+            record_len: None,
+            records: None,
+            bytes: None,
+            ellipsis: "...".into(),
+        }
+    }
+}
+
+impl Limits {
+    fn is_unlimited(&self) -> bool {
+        self.field_len.is_none()
+            && self.record_len.is_none()
+            && self.records.is_none()
+            && self.bytes.is_none()
+    }
+
+    /// Cut `field` down to at most `len` bytes, including the ellipsis.
+    ///
+    /// Fields are not cut in the middle of a UTF-8 encoded character. If
+    /// there is no room for the ellipsis, it is cut down instead.
+    fn truncate(&self, field: &[u8], len: u64) -> Vec<u8> {
+        let len = len as usize;
+        let ellipsis = self.ellipsis.as_bytes();
+        if ellipsis.len() >= len {
+            return floor_char_boundary(ellipsis, len).to_vec();
+        }
+        let mut truncated = floor_char_boundary(field, len - ellipsis.len()).to_vec();
+        truncated.extend_from_slice(ellipsis);
+        truncated
+    }
+}
+
+/// Returns the longest prefix of `field` of at most `len` bytes that does
+/// not end in the middle of a UTF-8 encoded character.
+fn floor_char_boundary(field: &[u8], len: usize) -> &[u8] {
+    if len >= field.len() {
+        return field;
+    }
+    let mut end = len;
+    while end > 0 && field[end] & 0xC0 == 0x80 {
+        end -= 1;
+    }
+    &field[..end]
+}
+
+/// Encode `bytes` as hexadecimal, with `digits` as the sixteen digits.
+fn encode_hex(bytes: &[u8], digits: &[u8; 16]) -> Vec<u8> {
+    let mut hex = Vec::with_capacity(bytes.len() * 2);
+    for &b in bytes {
+        hex.push(digits[usize::from(b >> 4)]);
+        hex.push(digits[usize::from(b & 0xf)]);
+    }
+    hex
 }
 
 impl Default for WriterBuilder {
     fn default() -> WriterBuilder {
         WriterBuilder {
             builder: CoreWriterBuilder::default(),
+            quote_style: QuoteStyle::default(),
             capacity: 8 * (1 << 10),
             flexible: false,
             has_headers: true,
+            comment: None,
+            preamble: Vec::new(),
+            error_policy: ErrorPolicy::default(),
+            on_skip: None,
+            infer_headers: 0,
+            header_order: HeaderOrder::default(),
+            bytes_encoding: BytesEncoding::default(),
+            limits: Limits::default(),
+            sanitize: Sanitize::default(),
+            replacement: " ".into(),
+            column_quotes: ColumnQuotes::default(),
         }
     }
 }
@@ -311,6 +440,60 @@ impl WriterBuilder {
     /// ```
     pub fn quote_style(&mut self, style: QuoteStyle) -> &mut WriterBuilder {
         self.builder.quote_style(style.to_core());
+        self.quote_style = style;
+        self
+    }
+
+    /// The quoting style to use for the column with the given name,
+    /// instead of the global [`quote_style`](WriterBuilder::quote_style).
+    ///
+    /// Names refer to the header row, so they only apply once a header row
+    /// has been written, including to the header row itself. Use
+    /// [`column_index_quote_style`](WriterBuilder::column_index_quote_style)
+    /// when there is no header row. If a column has a quoting style set both
+    /// by name and by index, the one set by name is used.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::error::Error;
+    /// use csv_stream::{QuoteStyle, WriterBuilder};
+    /// use serde::Serialize;
+    ///
+    /// # fn main() { example().unwrap(); }
+    /// fn example() -> Result<(), Box<dyn Error>> {
+    ///     #[derive(Serialize)]
+    ///     struct Payment<'a> { payee: &'a str, reference: &'a str, amount: &'a str }
+    ///
+    ///     let mut wtr = WriterBuilder::default()
+    ///         .quote_style(QuoteStyle::Always)
+    ///         .column_quote_style("amount", QuoteStyle::Never)
+    ///         .build();
+    ///
+    ///     let mut buf = vec![];
+    ///     wtr.serialize(&mut buf, Payment { payee: "ACME", reference: "123", amount: "9.99" })?;
+    ///
+    ///     let data = String::from_utf8(buf)?;
+    ///     assert_eq!(data, "\"payee\",\"reference\",amount\n\"ACME\",\"123\",9.99\n");
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn column_quote_style(&mut self, name: &str, style: QuoteStyle) -> &mut WriterBuilder {
+        self.column_quotes.by_name.insert(name.to_owned(), style);
+        self
+    }
+
+    /// The quoting style to use for the column at the given index, starting
+    /// from `0`, instead of the global
+    /// [`quote_style`](WriterBuilder::quote_style).
+    ///
+    /// This also applies to the header row.
+    pub fn column_index_quote_style(
+        &mut self,
+        index: usize,
+        style: QuoteStyle,
+    ) -> &mut WriterBuilder {
+        self.column_quotes.by_index.insert(index, style);
         self
     }
 
@@ -407,42 +590,49 @@ impl WriterBuilder {
         self
     }
 
-    /// Create a new iterator for creating CSVs from the given iterator of rows
+    /// The comment character that readers of the CSV data will use.
+    ///
+    /// Setting this enables [`Writer::write_comment`] and the
+    /// [`preamble`](WriterBuilder::preamble) option. It also ensures that
+    /// data fields containing the comment character are quoted, so that a
+    /// record starting with one is not mistaken for a comment when the data
+    /// is read back.
+    ///
+    /// By default, no comment character is set.
     ///
     /// # Example
     ///
     /// ```
     /// use std::error::Error;
     /// use csv_stream::WriterBuilder;
-    /// use serde::Serialize;
     ///
     /// # fn main() { example().unwrap(); }
     /// fn example() -> Result<(), Box<dyn Error>> {
-    ///     #[derive(Serialize)]
-    ///     struct Row { foo: usize, bar: usize }
-    ///     let rows = [
-    ///         Row{ foo: 1, bar: 2 },
-    ///         Row{ foo: 3, bar: 4 },
-    ///     ];
-    ///
-    ///     let mut csv_iter = WriterBuilder::default().build_iter(rows);
-    ///
+    ///     let mut wtr = WriterBuilder::default()
+    ///         .comment(b'#')
+    ///         .build();
     ///     let mut buf = vec![];
-    ///     for row in csv_iter {
-    ///         let row = row.unwrap();
-    ///         buf.extend_from_slice(&row);
-    ///     }
+    ///     wtr.write_comment(&mut buf, " schema version 2")?;
+    ///     wtr.write_record(&mut buf, &["#a", "b", "c"])?;
     ///
     ///     let data = String::from_utf8(buf)?;
-    ///     assert_eq!(data, "foo,bar\n1,2\n3,4\n");
+    ///     assert_eq!(data, "# schema version 2\n\"#a\",b,c\n");
     ///     Ok(())
     /// }
     /// ```
-    pub fn build_iter<I: IntoIterator>(&self, iter: I) -> crate::Iter<I::IntoIter> {
-        crate::Iter::new(iter, self.build())
+    pub fn comment(&mut self, comment: u8) -> &mut WriterBuilder {
+        self.builder.comment(Some(comment));
+        self.comment = Some(comment);
+        self
     }
 
-    /// Create a new stream for creating CSVs from the given stream of rows
+    /// Comment lines to write before any other output, such as export
+    /// metadata.
+    ///
+    /// The preamble is written before the header row (or the first record, if
+    /// there is no header row). Each line is written as with
+    /// [`Writer::write_comment`], so this requires a comment character to be
+    /// set with [`comment`](WriterBuilder::comment).
     ///
     /// # Example
     ///
@@ -450,489 +640,2114 @@ impl WriterBuilder {
     /// use std::error::Error;
     /// use csv_stream::WriterBuilder;
     /// use serde::Serialize;
-    /// use futures::StreamExt;
     ///
-    /// # #[tokio::main]
-    /// # async fn main() { example().await.unwrap(); }
-    /// async fn example() -> Result<(), Box<dyn Error>> {
+    /// # fn main() { example().unwrap(); }
+    /// fn example() -> Result<(), Box<dyn Error>> {
     ///     #[derive(Serialize)]
     ///     struct Row { foo: usize, bar: usize }
     ///     let rows = [
     ///         Row{ foo: 1, bar: 2 },
     ///         Row{ foo: 3, bar: 4 },
     ///     ];
-    ///     // a Stream over rows
-    ///     let stream = futures::stream::iter(rows);
     ///
-    ///     let mut csv_stream = WriterBuilder::default().build_stream(stream);
+    ///     let csv_iter = WriterBuilder::default()
+    ///         .comment(b'#')
+    ///         .preamble(["generated: 2021-10-01", "query: all rows"])
+    ///         .build_iter(rows);
     ///
     ///     let mut buf = vec![];
-    ///     while let Some(row) = csv_stream.next().await {
-    ///         let row = row.unwrap();
-    ///         buf.extend_from_slice(&row);
+    ///     for row in csv_iter {
+    ///         buf.extend_from_slice(&row?);
     ///     }
     ///
     ///     let data = String::from_utf8(buf)?;
-    ///     assert_eq!(data, "foo,bar\n1,2\n3,4\n");
+    ///     assert_eq!(data, "\
+    /// #generated: 2021-10-01
+    /// #query: all rows
+    /// foo,bar
+    /// 1,2
+    /// 3,4
+    /// ");
     ///     Ok(())
     /// }
     /// ```
-    #[cfg(feature = "stream")]
-    pub fn build_stream<S>(&self, stream: S) -> crate::Stream<S> {
-        crate::Stream::new(stream, self.build())
-    }
-}
-
-/// A already configured CSV writer.
-///
-/// A CSV writer takes as input Rust values and writes those values in a valid
-/// CSV format as output.
-///
-/// While CSV writing is considerably easier than parsing CSV, a proper writer
-/// will do a number of things for you:
-///
-/// 1. Quote fields when necessary.
-/// 2. Check that all records have the same number of fields.
-/// 3. Write records with a single empty field correctly.
-/// 4. Automatically serialize normal Rust types to CSV records. When that
-///    type is a struct, a header row is automatically written corresponding
-///    to the fields of that struct.
-/// 5. Use buffering intelligently and otherwise avoid allocation. (This means
-///    that callers should not do their own buffering.)
-///
-/// All of the above can be configured using a
-/// [`WriterBuilder`](struct.WriterBuilder.html).
-///
-/// Note that the default configuration of a `Writer` uses `\n` for record
-/// terminators instead of `\r\n` as specified by RFC 4180. Use the
-/// `terminator` method on `WriterBuilder` to set the terminator to `\r\n` if
-/// it's desired.
-#[derive(Debug)]
-pub struct Writer {
-    core: CoreWriter,
-    state: WriterState,
-}
-
-#[derive(Debug)]
-struct WriterState {
-    /// Whether the Serde serializer should attempt to write a header row.
-    header: HeaderState,
-    /// Whether inconsistent record lengths are allowed.
-    flexible: bool,
-    /// The number of fields writtein in the first record. This is compared
-    /// with `fields_written` on all subsequent records to check for
-    /// inconsistent record lengths.
-    first_field_count: Option<u64>,
-    /// The number of fields written in this record. This is used to report
-    /// errors for inconsistent record lengths if `flexible` is disabled.
-    fields_written: u64,
-    /// This is set immediately before flushing the buffer and then unset
-    /// immediately after flushing the buffer. This avoids flushing the buffer
-    /// twice if the inner writer panics.
-    panicked: bool,
-}
-
-/// HeaderState encodes a small state machine for handling header writes.
-#[derive(Debug)]
-enum HeaderState {
-    /// Indicates that we should attempt to write a header.
-    Write,
-    /// Indicates that writing a header was attempt, and a header was written.
-    DidWrite,
-    /// Indicates that writing a header was attempted, but no headers were
-    /// written or the attempt failed.
-    DidNotWrite,
-    /// This state is used when headers are disabled. It cannot transition
-    /// to any other state.
-    None,
-}
-
-impl Default for Writer {
-    fn default() -> Self {
-        WriterBuilder::default().build()
-    }
-}
-
-impl Writer {
-    fn new(builder: &WriterBuilder) -> Writer {
-        let header_state = if builder.has_headers {
-            HeaderState::Write
-        } else {
-            HeaderState::None
-        };
-        Writer {
-            core: builder.builder.build(),
-            state: WriterState {
-                header: header_state,
-                flexible: builder.flexible,
-                first_field_count: None,
-                fields_written: 0,
-                panicked: false,
-            },
-        }
+    pub fn preamble<I, T>(&mut self, lines: I) -> &mut WriterBuilder
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.preamble = lines.into_iter().map(Into::into).collect();
+        self
     }
 
-    /// Serialize a single record using Serde.
+    /// What `Iter` and `Stream` should do when a record fails to be written.
     ///
-    /// # Example
+    /// By default, the error is yielded in place of the record and the next
+    /// record is written as normal. See [`ErrorPolicy`] for the alternatives.
     ///
-    /// This shows how to serialize normal Rust structs as CSV records. The
-    /// fields of the struct are used to write a header row automatically.
-    /// (Writing the header row automatically can be disabled by building the
-    /// CSV writer with a [`WriterBuilder`](struct.WriterBuilder.html) and
-    /// calling the `has_headers` method.)
+    /// This option has no effect when using a `Writer` directly.
+    ///
+    /// # Example
     ///
     /// ```
     /// use std::error::Error;
+    /// use csv_stream::{ErrorPolicy, WriterBuilder};
+    /// use serde::{ser, Serialize, Serializer};
     ///
-    /// use csv_stream::WriterBuilder;
-    /// use serde::Serialize;
+    /// struct Row(Option<u32>);
     ///
-    /// #[derive(Serialize)]
-    /// struct Row<'a> {
-    ///     city: &'a str,
-    ///     country: &'a str,
-    ///     // Serde allows us to name our headers exactly,
-    ///     // even if they don't match our struct field names.
-    ///     #[serde(rename = "popcount")]
-    ///     population: u64,
+    /// impl Serialize for Row {
+    ///     fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+    ///         match self.0 {
+    ///             Some(x) => (x,).serialize(s),
+    ///             None => Err(ser::Error::custom("missing value")),
+    ///         }
+    ///     }
     /// }
     ///
     /// # fn main() { example().unwrap(); }
     /// fn example() -> Result<(), Box<dyn Error>> {
-    ///     let mut wtr = WriterBuilder::default().build();
+    ///     let rows = [Row(Some(1)), Row(None), Row(Some(3))];
+    ///
+    ///     let mut csv_iter = WriterBuilder::default()
+    ///         .error_policy(ErrorPolicy::Skip)
+    ///         .build_iter(rows);
+    ///
     ///     let mut buf = vec![];
-    ///     wtr.serialize(
-    ///         &mut buf,
-    ///         Row {
-    ///             city: "Boston",
-    ///             country: "United States",
-    ///             population: 4628910,
-    ///         },
-    ///     )?;
-    ///     wtr.serialize(
-    ///         &mut buf,
-    ///         Row {
-    ///             city: "Concord",
-    ///             country: "United States",
-    ///             population: 42695,
-    ///         },
-    ///     )?;
+    ///     for row in &mut csv_iter {
+    ///         buf.extend_from_slice(&row?);
+    ///     }
+    ///     assert_eq!(csv_iter.skipped(), 1);
     ///
     ///     let data = String::from_utf8(buf)?;
-    ///     assert_eq!(data, "\
-    /// city,country,popcount
-    /// Boston,United States,4628910
-    /// Concord,United States,42695
-    /// ");
+    ///     assert_eq!(data, "1\n3\n");
     ///     Ok(())
     /// }
     /// ```
+    pub fn error_policy(&mut self, policy: ErrorPolicy) -> &mut WriterBuilder {
+        self.error_policy = policy;
+        self
+    }
+
+    /// Set a callback to be invoked with the error for every record that
+    /// `Iter` or `Stream` drops, either because the error policy is
+    /// `ErrorPolicy::Skip` or because the record was replaced with a
+    /// placeholder or comment.
+    ///
+    /// This is useful for logging records that would otherwise be lost
+    /// silently.
+    pub fn on_skip<F>(&mut self, f: F) -> &mut WriterBuilder
+    where
+        F: Fn(&Error) + Send + Sync + 'static,
+    {
+        self.on_skip = Some(SkipHook(Arc::new(f)));
+        self
+    }
+
+    /// Infer the header row from the names of the fields of the first
+    /// `window` records, for records that are maps, such as a `HashMap` or a
+    /// `serde_json::Value` object, whose keys vary from record to record.
     ///
-    /// # Rules
-    ///
-    /// The behavior of `serialize` is fairly simple:
+    /// `Iter` and `Stream` read the first `window` records ahead of time,
+    /// and take the union of their field names, in the order set by
+    /// [`header_order`](WriterBuilder::header_order), as the header row.
+    /// Every record is then written with its fields aligned to the header
+    /// row, with an empty field for each missing name. Other ways of writing
+    /// records infer the header row from the first record alone.
     ///
-    /// 1. Nested containers (tuples, `Vec`s, structs, etc.) are always
-    ///    flattened (depth-first order).
+    /// A field name that is first seen after the window is an error for
+    /// that record, unless the `flexible` option is enabled, in which case
+    /// it is added as a new column at the end of every following record.
     ///
-    /// 2. If `has_headers` is `true` and the type contains field names, then
-    ///    a header row is automatically generated.
+    /// Records must be maps or structs, and their fields must be scalars.
+    /// Inference is disabled by default, and when `window` is `0`.
     ///
-    /// However, some container types cannot be serialized, and if
-    /// `has_headers` is `true`, there are some additional restrictions on the
-    /// types that can be serialized. See below for details.
+    /// # Example
     ///
-    /// For the purpose of this section, Rust types can be divided into three
-    /// categories: scalars, non-struct containers, and structs.
+    /// ```
+    /// use std::collections::BTreeMap;
+    /// use std::error::Error;
+    /// use csv_stream::WriterBuilder;
     ///
-    /// ## Scalars
+    /// # fn main() { example().unwrap(); }
+    /// fn example() -> Result<(), Box<dyn Error>> {
+    ///     let rows = [
+    ///         BTreeMap::from([("city", "Boston")]),
+    ///         BTreeMap::from([("city", "Concord"), ("state", "NH")]),
+    ///     ];
     ///
-    /// Single values with no field names are written like the following. Note
-    /// that some of the outputs may be quoted, according to the selected
-    /// quoting style.
+    ///     let csv_iter = WriterBuilder::default()
+    ///         .infer_headers(2)
+    ///         .build_iter(rows);
     ///
-    /// | Name | Example Type | Example Value | Output |
-    /// | ---- | ---- | ---- | ---- |
-    /// | boolean | `bool` | `true` | `true` |
-    /// | integers | `i8`, `i16`, `i32`, `i64`, `i128`, `u8`, `u16`, `u32`, `u64`, `u128` | `5` | `5` |
-    /// | floats | `f32`, `f64` | `3.14` | `3.14` |
-    /// | character | `char` | `'☃'` | `☃` |
-    /// | string | `&str` | `"hi"` | `hi` |
-    /// | bytes | `&[u8]` | `b"hi"[..]` | `hi` |
-    /// | option | `Option` | `None` | *empty* |
-    /// | option |          | `Some(5)` | `5` |
-    /// | unit | `()` | `()` | *empty* |
-    /// | unit struct | `struct Foo;` | `Foo` | `Foo` |
-    /// | unit enum variant | `enum E { A, B }` | `E::A` | `A` |
-    /// | newtype struct | `struct Foo(u8);` | `Foo(5)` | `5` |
-    /// | newtype enum variant | `enum E { A(u8) }` | `E::A(5)` | `5` |
+    ///     let mut buf = vec![];
+    ///     for row in csv_iter {
+    ///         buf.extend_from_slice(&row?);
+    ///     }
     ///
-    /// Note that this table includes simple structs and enums. For example, to
-    /// serialize a field from either an integer or a float type, one can do
-    /// this:
+    ///     let data = String::from_utf8(buf)?;
+    ///     assert_eq!(data, "city,state\nBoston,\nConcord,NH\n");
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn infer_headers(&mut self, window: usize) -> &mut WriterBuilder {
+        self.infer_headers = window;
+        self
+    }
+
+    /// The order of the columns of a header row inferred with
+    /// [`infer_headers`](WriterBuilder::infer_headers).
+    ///
+    /// The default is `HeaderOrder::FirstSeen`.
+    pub fn header_order(&mut self, order: HeaderOrder) -> &mut WriterBuilder {
+        self.header_order = order;
+        self
+    }
+
+    /// The encoding of fields serialized from bytes, such as a
+    /// `serde_bytes::ByteBuf` or a `BLOB` column of a database row.
+    ///
+    /// By default, bytes are written as they are, which produces invalid
+    /// UTF-8 CSV data for arbitrary binary data such as hashes. Encoding them
+    /// as hexadecimal or base64 keeps such data intact through text based
+    /// tooling. This does not change how strings are written.
+    ///
+    /// # Example
     ///
     /// ```
     /// use std::error::Error;
+    /// use csv_stream::{BytesEncoding, WriterBuilder};
+    /// use serde::{Serialize, Serializer};
     ///
-    /// use csv_stream::WriterBuilder;
-    /// use serde::Serialize;
-    ///
-    /// #[derive(Serialize)]
-    /// struct Row {
-    ///     label: String,
-    ///     value: Value,
-    /// }
+    /// struct Digest([u8; 4]);
     ///
-    /// #[derive(Serialize)]
-    /// enum Value {
-    ///     Integer(i64),
-    ///     Float(f64),
+    /// impl Serialize for Digest {
+    ///     fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+    ///         s.serialize_bytes(&self.0)
+    ///     }
     /// }
     ///
     /// # fn main() { example().unwrap(); }
     /// fn example() -> Result<(), Box<dyn Error>> {
-    ///     let mut wtr = WriterBuilder::default().build();
+    ///     let mut wtr = WriterBuilder::default()
+    ///         .bytes_encoding(BytesEncoding::Hex)
+    ///         .build();
+    ///
     ///     let mut buf = vec![];
-    ///     wtr.serialize(
-    ///         &mut buf,
-    ///         Row {
-    ///             label: "foo".to_string(),
-    ///             value: Value::Integer(3),
-    ///         },
-    ///     )?;
-    ///     wtr.serialize(
-    ///         &mut buf,
-    ///         Row {
-    ///             label: "bar".to_string(),
-    ///             value: Value::Float(3.14),
-    ///         },
-    ///     )?;
+    ///     wtr.serialize(&mut buf, ("file.txt", Digest([0xde, 0xad, 0xbe, 0xef])))?;
     ///
     ///     let data = String::from_utf8(buf)?;
-    ///     assert_eq!(data, "\
-    /// label,value
-    /// foo,3
-    /// bar,3.14
-    /// ");
+    ///     assert_eq!(data, "file.txt,deadbeef\n");
     ///     Ok(())
     /// }
     /// ```
+    pub fn bytes_encoding(&mut self, encoding: BytesEncoding) -> &mut WriterBuilder {
+        self.bytes_encoding = encoding;
+        self
+    }
+
+    /// Sanitize the line breaks, NUL bytes and other C0 control characters in
+    /// fields, for consumers that read CSV data one line at a time.
     ///
-    /// ## Non-Struct Containers
-    ///
-    /// Nested containers are flattened to their scalar components, with the
-    /// exception of a few types that are not allowed:
+    /// Control characters are either replaced, or escaped with backslashes.
+    /// This also applies to the record terminator, if it is set to a byte
+    /// that is not a control character, but not to the preamble or
+    /// comments. Fields are sanitized before they are quoted, and before the
+    /// limits on their length are applied.
     ///
-    /// | Name | Example Type | Example Value | Output |
-    /// | ---- | ---- | ---- | ---- |
-    /// | sequence | `Vec<u8>` | `vec![1, 2, 3]` | `1,2,3` |
-    /// | tuple | `(u8, bool)` | `(5, true)` | `5,true` |
-    /// | tuple struct | `Foo(u8, bool)` | `Foo(5, true)` | `5,true` |
-    /// | tuple enum variant | `enum E { A(u8, bool) }` | `E::A(5, true)` | *error* |
-    /// | struct enum variant | `enum E { V { a: u8, b: bool } }` | `E::V { a: 5, b: true }` | *error* |
-    /// | map | `BTreeMap<K, V>` | `BTreeMap::new()` | *error* |
+    /// The default is `Sanitize::None`.
     ///
-    /// ## Structs
+    /// # Example
     ///
-    /// Like the other containers, structs are flattened to their scalar
-    /// components:
+    /// ```
+    /// use std::error::Error;
+    /// use csv_stream::{Sanitize, WriterBuilder};
     ///
-    /// | Name | Example Type | Example Value | Output |
-    /// | ---- | ---- | ---- | ---- |
-    /// | struct | `struct Foo { a: u8, b: bool }` | `Foo { a: 5, b: true }` | `5,true` |
+    /// # fn main() { example().unwrap(); }
+    /// fn example() -> Result<(), Box<dyn Error>> {
+    ///     let mut wtr = WriterBuilder::default()
+    ///         .sanitize(Sanitize::Escape)
+    ///         .build();
     ///
-    /// If `has_headers` is `false`, then there are no additional restrictions;
-    /// types can be nested arbitrarily. For example:
+    ///     let mut buf = vec![];
+    ///     wtr.write_record(&mut buf, &["first line\r\nsecond line", "C:\\"])?;
     ///
+    ///     let data = String::from_utf8(buf)?;
+    ///     assert_eq!(data, "first line\\r\\nsecond line,C:\\\\\n");
+    ///     Ok(())
+    /// }
     /// ```
-    /// use std::error::Error;
+    pub fn sanitize(&mut self, mode: Sanitize) -> &mut WriterBuilder {
+        self.sanitize = mode;
+        self
+    }
+
+    /// The replacement for control characters with `Sanitize::Replace`.
     ///
-    /// use csv_stream::WriterBuilder;
-    /// use serde::Serialize;
+    /// The replacement may be empty, to remove control characters. The
+    /// default is `" "`.
+    pub fn sanitize_replacement(&mut self, replacement: &str) -> &mut WriterBuilder {
+        self.replacement = replacement.into();
+        self
+    }
+
+    /// The maximum length of a field in bytes, not counting the quotes and
+    /// escapes around and within it, and what to do with a field that is
+    /// longer.
     ///
-    /// #[derive(Serialize)]
-    /// struct Row {
-    ///     label: String,
-    ///     values: Vec<f64>,
-    /// }
+    /// Longer fields are rejected before they are written, so a single huge
+    /// field does not end up in memory twice.
+    ///
+    /// There is no limit by default.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::error::Error;
+    /// use csv_stream::{LimitPolicy, WriterBuilder};
     ///
     /// # fn main() { example().unwrap(); }
     /// fn example() -> Result<(), Box<dyn Error>> {
     ///     let mut wtr = WriterBuilder::default()
-    ///         .has_headers(false)
+    ///         .max_field_len(8, LimitPolicy::Truncate)
     ///         .build();
     ///
     ///     let mut buf = vec![];
-    ///     wtr.serialize(
-    ///         &mut buf,
-    ///         Row {
-    ///             label: "foo".to_string(),
-    ///             values: vec![1.1234, 2.5678, 3.14],
-    ///         },
-    ///     )?;
+    ///     wtr.write_record(&mut buf, &["short", "a very long field"])?;
     ///
     ///     let data = String::from_utf8(buf)?;
-    ///     assert_eq!(data, "\
-    /// foo,1.1234,2.5678,3.14
-    /// ");
+    ///     assert_eq!(data, "short,a ver...\n");
     ///     Ok(())
     /// }
     /// ```
+    pub fn max_field_len(&mut self, len: u64, policy: LimitPolicy) -> &mut WriterBuilder {
+        self.limits.field_len = Some((len, policy));
+        self
+    }
+
+    /// The maximum length of a record in bytes, including delimiters and
+    /// quotes but not the record terminator, and what to do with a record
+    /// that is longer.
+    ///
+    /// With `LimitPolicy::Truncate`, the field that goes over the limit is
+    /// truncated, and any fields after it are written empty, so that the
+    /// record keeps the same number of fields. Their delimiters still go
+    /// over the limit.
+    ///
+    /// There is no limit by default.
+    pub fn max_record_len(&mut self, len: u64, policy: LimitPolicy) -> &mut WriterBuilder {
+        self.limits.record_len = Some((len, policy));
+        self
+    }
+
+    /// The maximum number of records to write, not including the header
+    /// row, and what to do with the records after that.
     ///
-    /// However, if `has_headers` were enabled in the above example, then
-    /// serialization would return an error. Specifically, when `has_headers` is
-    /// `true`, there are two restrictions:
+    /// With `LimitPolicy::Truncate` or `LimitPolicy::Stop`, the iterators
+    /// and streams built by this builder end once the limit is reached,
+    /// without reading another record from their input.
     ///
-    /// 1. Named field values in structs must be scalars.
+    /// There is no limit by default.
     ///
-    /// 2. All scalars must be named field values in structs.
+    /// # Example
     ///
-    /// Other than these two restrictions, types can be nested arbitrarily.
-    /// Here are a few examples:
+    /// ```
+    /// use std::error::Error;
+    /// use csv_stream::{LimitPolicy, WriterBuilder};
     ///
-    /// | Value | Header | Record |
-    /// | ---- | ---- | ---- |
-    /// | `(Foo { x: 5, y: 6 }, Bar { z: true })` | `x,y,z` | `5,6,true` |
-    /// | `vec![Foo { x: 5, y: 6 }, Foo { x: 7, y: 8 }]` | `x,y,x,y` | `5,6,7,8` |
-    /// | `(Foo { x: 5, y: 6 }, vec![Bar { z: Baz(true) }])` | `x,y,z` | `5,6,true` |
-    /// | `Foo { x: 5, y: (6, 7) }` | *error: restriction 1* | `5,6,7` |
-    /// | `(5, Foo { x: 6, y: 7 }` | *error: restriction 2* | `5,6,7` |
-    /// | `(Foo { x: 5, y: 6 }, true)` | *error: restriction 2* | `5,6,true` |
-    pub fn serialize<S: Serialize>(&mut self, buf: &mut Vec<u8>, record: S) -> Result<()> {
-        if let HeaderState::Write = self.state.header {
-            let wrote_header = serialize_header(self, buf, &record)?;
-            if wrote_header {
-                self.write_terminator(buf)?;
-                self.state.header = HeaderState::DidWrite;
-            } else {
-                self.state.header = HeaderState::DidNotWrite;
-            };
-        }
-        serialize(self, buf, &record)?;
-        self.write_terminator(buf)?;
-        Ok(())
+    /// # fn main() { example().unwrap(); }
+    /// fn example() -> Result<(), Box<dyn Error>> {
+    ///     let csv_iter = WriterBuilder::default()
+    ///         .comment(b'#')
+    ///         .max_records(2, LimitPolicy::Stop)
+    ///         .build_iter(1..);
+    ///
+    ///     let mut buf = vec![];
+    ///     for row in csv_iter {
+    ///         buf.extend_from_slice(&row?);
+    ///     }
+    ///
+    ///     let data = String::from_utf8(buf)?;
+    ///     assert_eq!(data, "1\n2\n#truncated: more than the maximum of 2 records\n");
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn max_records(&mut self, records: u64, policy: LimitPolicy) -> &mut WriterBuilder {
+        self.limits.records = Some((records, policy));
+        self
     }
 
-    /// Write a single record.
+    /// The maximum number of bytes to write, including the preamble and the
+    /// header row, and what to do with the record that would go over it.
     ///
-    /// This method accepts something that can be turned into an iterator that
-    /// yields elements that can be represented by a `&[u8]`.
+    /// The output ends before the record that goes over the limit with
+    /// `LimitPolicy::Truncate` or `LimitPolicy::Stop`. A truncation comment
+    /// or a trailer may still go over it.
     ///
-    /// This may be called with an empty iterator, which will cause a record
-    /// terminator to be written. If no fields had been written, then a single
-    /// empty field is written before the terminator.
+    /// There is no limit by default.
+    pub fn max_bytes(&mut self, bytes: u64, policy: LimitPolicy) -> &mut WriterBuilder {
+        self.limits.bytes = Some((bytes, policy));
+        self
+    }
+
+    /// The marker that fields truncated by `LimitPolicy::Truncate` end with.
+    ///
+    /// The marker counts towards the limit. The default is `"..."`.
+    pub fn ellipsis(&mut self, marker: &str) -> &mut WriterBuilder {
+        self.limits.ellipsis = marker.into();
+        self
+    }
+
+    /// Set the capacity (in bytes) of the internal buffer used by
+    /// [`build_io`](WriterBuilder::build_io).
+    ///
+    /// Once this much output is buffered, it is written to the underlying
+    /// writer. The default is 8KiB.
+    pub fn buffer_capacity(&mut self, capacity: usize) -> &mut WriterBuilder {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Create a new CSV writer that writes to the given `io::Write`.
     ///
     /// # Example
     ///
     /// ```
     /// use std::error::Error;
     /// use csv_stream::WriterBuilder;
+    /// use serde::Serialize;
     ///
     /// # fn main() { example().unwrap(); }
     /// fn example() -> Result<(), Box<dyn Error>> {
-    ///     let mut wtr = WriterBuilder::default().build();
-    ///     let mut buf = vec![];
-    ///     wtr.write_record(&mut buf, &["a", "b", "c"])?;
-    ///     wtr.write_record(&mut buf, &["x", "y", "z"])?;
+    ///     #[derive(Serialize)]
+    ///     struct Row { foo: usize, bar: usize }
     ///
-    ///     let data = String::from_utf8(buf)?;
-    ///     assert_eq!(data, "a,b,c\nx,y,z\n");
+    ///     let mut wtr = WriterBuilder::default().build_io(vec![]);
+    ///     wtr.serialize(Row { foo: 1, bar: 2 })?;
+    ///     wtr.serialize(Row { foo: 3, bar: 4 })?;
+    ///
+    ///     let data = String::from_utf8(wtr.into_inner()?)?;
+    ///     assert_eq!(data, "foo,bar\n1,2\n3,4\n");
     ///     Ok(())
     /// }
     /// ```
-    pub fn write_record<I, T>(&mut self, buf: &mut Vec<u8>, record: I) -> Result<()>
+    pub fn build_io<W: io::Write>(&self, w: W) -> crate::IoWriter<W> {
+        crate::IoWriter::new(w, self.build(), self.capacity)
+    }
+
+    /// Create a new sink that writes the records sent into it as CSV to the
+    /// given `AsyncWrite`.
+    ///
+    /// Output is buffered up to the
+    /// [`buffer_capacity`](WriterBuilder::buffer_capacity). See
+    /// [`CsvSink`](crate::CsvSink) for more details.
+    #[cfg(feature = "stream")]
+    pub fn build_sink<W, T>(&self, w: W) -> crate::CsvSink<W, T>
     where
-        I: IntoIterator<Item = T>,
-        T: AsRef<[u8]>,
+        W: futures::io::AsyncWrite,
+        T: Serialize,
     {
-        for field in record.into_iter() {
-            self.write_field_impl(buf, field)?;
-        }
-        self.write_terminator(buf)
+        crate::CsvSink::new(w, self.build(), self.capacity)
     }
 
-    /// Write a single field.
+    /// Create a new encoder for writing records into a
+    /// [`FramedWrite`](tokio_util::codec::FramedWrite).
     ///
-    /// One should prefer using `write_record` over this method. It is provided
-    /// for cases where writing a field at a time is more convenient than
-    /// writing a record at a time.
+    /// See [`CsvEncoder`](crate::CsvEncoder) for more details.
+    #[cfg(feature = "tokio-util")]
+    pub fn build_encoder<T>(&self) -> crate::CsvEncoder<T> {
+        crate::CsvEncoder::new(self.build())
+    }
+
+    /// Create a new iterator for creating CSVs from the given iterator of
+    /// Arrow record batches
+    ///
+    /// See [`RecordBatchIter`](crate::RecordBatchIter) for more details.
+    #[cfg(feature = "arrow")]
+    pub fn build_record_batch_iter<I: IntoIterator>(
+        &self,
+        iter: I,
+    ) -> crate::RecordBatchIter<I::IntoIter> {
+        crate::RecordBatchIter::new(iter, self.build())
+    }
+
+    /// Create a new stream for creating CSVs from the given stream of Arrow
+    /// record batches
     ///
-    /// Note that if this API is used, `write_record` should be called with an
-    /// empty iterator to write a record terminator.
+    /// See [`RecordBatchIter`](crate::RecordBatchIter) for more details.
+    #[cfg(all(feature = "arrow", feature = "stream"))]
+    pub fn build_record_batch_stream<S>(&self, stream: S) -> crate::RecordBatchStream<S> {
+        crate::RecordBatchStream::new(stream, self.build())
+    }
+
+    /// Create a new stream for creating CSVs from the given stream of
+    /// database rows, such as the output of an `sqlx` query's `fetch`
+    ///
+    /// This requires the `sqlx-sqlite` or `sqlx-postgres` feature. See
+    /// [`RowStream`](crate::RowStream) for more details.
+    #[cfg(any(feature = "sqlx-sqlite", feature = "sqlx-postgres"))]
+    pub fn build_row_stream<S>(&self, stream: S) -> crate::RowStream<S> {
+        crate::RowStream::new(stream, self.build())
+    }
+
+    /// Create a new iterator for creating CSVs from the given iterator of rows
     ///
     /// # Example
     ///
     /// ```
     /// use std::error::Error;
     /// use csv_stream::WriterBuilder;
+    /// use serde::Serialize;
     ///
     /// # fn main() { example().unwrap(); }
     /// fn example() -> Result<(), Box<dyn Error>> {
-    ///     let mut wtr = WriterBuilder::default().build();
-    ///     let mut buf = vec![];
-    ///     wtr.write_field(&mut buf, "a")?;
-    ///     wtr.write_field(&mut buf, "b")?;
-    ///     wtr.write_field(&mut buf, "c")?;
-    ///     wtr.write_record(&mut buf, None::<&[u8]>)?;
+    ///     #[derive(Serialize)]
+    ///     struct Row { foo: usize, bar: usize }
+    ///     let rows = [
+    ///         Row{ foo: 1, bar: 2 },
+    ///         Row{ foo: 3, bar: 4 },
+    ///     ];
+    ///
+    ///     let mut csv_iter = WriterBuilder::default().build_iter(rows);
+    ///
+    ///     let mut buf = vec![];
+    ///     for row in csv_iter {
+    ///         let row = row.unwrap();
+    ///         buf.extend_from_slice(&row);
+    ///     }
+    ///
+    ///     let data = String::from_utf8(buf)?;
+    ///     assert_eq!(data, "foo,bar\n1,2\n3,4\n");
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn build_iter<I: IntoIterator>(&self, iter: I) -> crate::Iter<I::IntoIter> {
+        crate::Iter::new(iter, self.build())
+    }
+
+    /// Create a new stream for creating CSVs from the given stream of rows
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::error::Error;
+    /// use csv_stream::WriterBuilder;
+    /// use serde::Serialize;
+    /// use futures::StreamExt;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() { example().await.unwrap(); }
+    /// async fn example() -> Result<(), Box<dyn Error>> {
+    ///     #[derive(Serialize)]
+    ///     struct Row { foo: usize, bar: usize }
+    ///     let rows = [
+    ///         Row{ foo: 1, bar: 2 },
+    ///         Row{ foo: 3, bar: 4 },
+    ///     ];
+    ///     // a Stream over rows
+    ///     let stream = futures::stream::iter(rows);
+    ///
+    ///     let mut csv_stream = WriterBuilder::default().build_stream(stream);
+    ///
+    ///     let mut buf = vec![];
+    ///     while let Some(row) = csv_stream.next().await {
+    ///         let row = row.unwrap();
+    ///         buf.extend_from_slice(&row);
+    ///     }
+    ///
+    ///     let data = String::from_utf8(buf)?;
+    ///     assert_eq!(data, "foo,bar\n1,2\n3,4\n");
+    ///     Ok(())
+    /// }
+    /// ```
+    #[cfg(feature = "stream")]
+    pub fn build_stream<S>(&self, stream: S) -> crate::Stream<S> {
+        crate::Stream::new(stream, self.build())
+    }
+
+    /// Create a new iterator for creating CSVs from the given rows, serializing
+    /// the rows in parallel on the rayon thread pool.
+    ///
+    /// The output is identical to that of
+    /// [`build_iter`](WriterBuilder::build_iter). See
+    /// [`ParIter`](crate::ParIter) for more details.
+    #[cfg(feature = "rayon")]
+    pub fn build_par_iter<I: IntoIterator>(&self, iter: I) -> crate::ParIter<I::IntoIter> {
+        crate::ParIter::new(iter, self.build())
+    }
+
+    /// Create a new stream for creating CSVs from the given stream of rows,
+    /// serializing the rows on tokio's blocking thread pool with up to
+    /// `max_in_flight` batches in flight at once.
+    ///
+    /// The output is identical to that of
+    /// [`build_stream`](WriterBuilder::build_stream). See
+    /// [`ConcurrentStream`](crate::ConcurrentStream) for more details.
+    #[cfg(feature = "tokio")]
+    pub fn build_concurrent_stream<S: futures::Stream>(
+        &self,
+        stream: S,
+        max_in_flight: usize,
+    ) -> crate::ConcurrentStream<S> {
+        crate::ConcurrentStream::new(stream, self.build(), max_in_flight)
+    }
+
+    /// Create a new iterator that splits the CSV created from the given rows
+    /// into multiple parts, according to the limits set by `split`.
+    ///
+    /// Each part is an iterator of chunks, like the one returned by
+    /// [`build_iter`](WriterBuilder::build_iter), and makes up a complete CSV
+    /// file with its own preamble and header row. All parts are checked
+    /// against the same number of fields per record, unless `flexible` is
+    /// enabled.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::error::Error;
+    /// use csv_stream::{Split, WriterBuilder};
+    /// use serde::Serialize;
+    ///
+    /// # fn main() { example().unwrap(); }
+    /// fn example() -> Result<(), Box<dyn Error>> {
+    ///     #[derive(Serialize)]
+    ///     struct Row { foo: usize, bar: usize }
+    ///     let rows = [
+    ///         Row{ foo: 1, bar: 2 },
+    ///         Row{ foo: 3, bar: 4 },
+    ///         Row{ foo: 5, bar: 6 },
+    ///     ];
+    ///
+    ///     let split = Split::default().max_records(2);
+    ///     let parts = WriterBuilder::default().build_split_iter(rows, split);
+    ///
+    ///     let mut files = vec![];
+    ///     for part in parts {
+    ///         let name = format!("part-{}.csv", part.index());
+    ///         let mut buf = vec![];
+    ///         for row in part {
+    ///             buf.extend_from_slice(&row?);
+    ///         }
+    ///         files.push((name, String::from_utf8(buf)?));
+    ///     }
+    ///
+    ///     assert_eq!(files[0].0, "part-0.csv");
+    ///     assert_eq!(files[0].1, "foo,bar\n1,2\n3,4\n");
+    ///     assert_eq!(files[1].0, "part-1.csv");
+    ///     assert_eq!(files[1].1, "foo,bar\n5,6\n");
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn build_split_iter<I: IntoIterator>(
+        &self,
+        iter: I,
+        split: Split,
+    ) -> crate::SplitIter<I::IntoIter> {
+        crate::SplitIter::new(iter, self.build(), split)
+    }
+
+    /// Create a new stream that splits the CSV created from the given stream
+    /// of rows into multiple parts, according to the limits set by `split`.
+    ///
+    /// Each part is a stream of chunks, like the one returned by
+    /// [`build_stream`](WriterBuilder::build_stream). See
+    /// [`build_split_iter`](WriterBuilder::build_split_iter) for more
+    /// details.
+    #[cfg(feature = "stream")]
+    pub fn build_split_stream<S: futures::Stream>(
+        &self,
+        stream: S,
+        split: Split,
+    ) -> crate::SplitStream<S> {
+        crate::SplitStream::new(stream, self.build(), split)
+    }
+}
+
+/// A already configured CSV writer.
+///
+/// A CSV writer takes as input Rust values and writes those values in a valid
+/// CSV format as output.
+///
+/// While CSV writing is considerably easier than parsing CSV, a proper writer
+/// will do a number of things for you:
+///
+/// 1. Quote fields when necessary.
+/// 2. Check that all records have the same number of fields.
+/// 3. Write records with a single empty field correctly.
+/// 4. Automatically serialize normal Rust types to CSV records. When that
+///    type is a struct, a header row is automatically written corresponding
+///    to the fields of that struct.
+/// 5. Use buffering intelligently and otherwise avoid allocation. (This means
+///    that callers should not do their own buffering.)
+///
+/// All of the above can be configured using a
+/// [`WriterBuilder`](struct.WriterBuilder.html).
+///
+/// Note that the default configuration of a `Writer` uses `\n` for record
+/// terminators instead of `\r\n` as specified by RFC 4180. Use the
+/// `terminator` method on `WriterBuilder` to set the terminator to `\r\n` if
+/// it's desired.
+///
+/// A `Writer` does not own its output. Every method takes the buffer to
+/// write to, which can be any [`CsvBuf`], such as a `Vec<u8>` or a `String`.
+#[derive(Clone, Debug)]
+pub struct Writer {
+    core: CoreWriter,
+    quote_style: QuoteStyle,
+    state: WriterState,
+    comment: Option<u8>,
+    preamble: Arc<[String]>,
+    error_policy: ErrorPolicy,
+    on_skip: Option<SkipHook>,
+    infer_headers: usize,
+    header_order: HeaderOrder,
+    bytes_encoding: BytesEncoding,
+    limits: Limits,
+    sanitize: Sanitize,
+    replacement: Arc<str>,
+    column_quotes: Arc<ColumnQuotes>,
+    /// The limit that ended the output, if any. This is not part of the
+    /// state, so that it is not undone along with the record that went over
+    /// the limit.
+    stopped: Option<(Limit, LimitPolicy)>,
+}
+
+#[derive(Clone, Debug)]
+struct WriterState {
+    /// Whether the Serde serializer should attempt to write a header row.
+    header: HeaderState,
+    /// Whether inconsistent record lengths are allowed.
+    flexible: bool,
+    /// The number of fields writtein in the first record. This is compared
+    /// with `fields_written` on all subsequent records to check for
+    /// inconsistent record lengths.
+    first_field_count: Option<u64>,
+    /// The number of fields written in this record. This is used to report
+    /// errors for inconsistent record lengths if `flexible` is disabled.
+    fields_written: u64,
+    /// The number of records written so far, including the header row. This
+    /// is the index of the record currently being written.
+    records: u64,
+    /// Statistics about the data written so far, including the total number
+    /// of bytes written.
+    stats: Stats,
+    /// The byte offset at which the record currently being written starts.
+    record_start: u64,
+    /// The number of records dropped by `Iter` or `Stream` according to the
+    /// error policy.
+    skipped: u64,
+    /// Whether the preamble has been written yet.
+    wrote_preamble: bool,
+    /// The columns that records are aligned to, once they have been
+    /// inferred.
+    columns: Option<Arc<Columns>>,
+    /// Whether the header row is being written.
+    in_header: bool,
+    /// Whether the field being written was serialized from a number, a
+    /// boolean or a missing value, rather than a string.
+    in_scalar: bool,
+    /// The quoting styles of the columns of the header row that have one set
+    /// by name, once the header row has been written.
+    header_quotes: Option<Arc<Vec<Option<QuoteStyle>>>>,
+}
+
+/// The columns of a header row inferred from the field names of records.
+#[derive(Clone, Debug, Default)]
+struct Columns {
+    names: Vec<String>,
+    index: HashMap<String, usize>,
+}
+
+impl Columns {
+    fn push(&mut self, name: &str) {
+        if !self.index.contains_key(name) {
+            self.index.insert(name.to_owned(), self.names.len());
+            self.names.push(name.to_owned());
+        }
+    }
+}
+
+/// Statistics about the data a `Writer` has written so far.
+///
+/// These are returned by [`Writer::stats`], as well as by `Iter::stats` and
+/// `Stream::stats`.
+///
+/// The header row only counts towards the number of bytes written, and not
+/// towards any of the other statistics. Comment lines are counted likewise.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    records: u64,
+    fields: u64,
+    bytes: u64,
+    quoted_fields: u64,
+    max_record_width: u64,
+}
+
+impl Stats {
+    /// The number of records written, not including the header row.
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// The total number of fields written, across all records.
+    pub fn fields(&self) -> u64 {
+        self.fields
+    }
+
+    /// The total number of bytes written, including the header row and any
+    /// comments.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// The number of fields that were written in quotes.
+    pub fn quoted_fields(&self) -> u64 {
+        self.quoted_fields
+    }
+
+    /// The largest number of fields written in a single record.
+    pub fn max_record_width(&self) -> u64 {
+        self.max_record_width
+    }
+}
+
+/// HeaderState encodes a small state machine for handling header writes.
+#[derive(Clone, Copy, Debug)]
+enum HeaderState {
+    /// Indicates that we should attempt to write a header.
+    Write,
+    /// Indicates that writing a header was attempt, and a header was written.
+    DidWrite,
+    /// Indicates that writing a header was attempted, but no headers were
+    /// written or the attempt failed.
+    DidNotWrite,
+    /// This state is used when headers are disabled. It cannot transition
+    /// to any other state.
+    None,
+}
+
+impl Default for Writer {
+    fn default() -> Self {
+        WriterBuilder::default().build()
+    }
+}
+
+impl Writer {
+    fn new(builder: &WriterBuilder) -> Writer {
+        let header_state = if builder.has_headers {
+            HeaderState::Write
+        } else {
+            HeaderState::None
+        };
+        Writer {
+            core: builder.builder.build(),
+            quote_style: builder.quote_style,
+            state: WriterState {
+                header: header_state,
+                flexible: builder.flexible,
+                first_field_count: None,
+                fields_written: 0,
+                records: 0,
+                stats: Stats::default(),
+                record_start: 0,
+                skipped: 0,
+                wrote_preamble: builder.preamble.is_empty(),
+                columns: None,
+                in_header: false,
+                in_scalar: false,
+                header_quotes: None,
+            },
+            comment: builder.comment,
+            preamble: builder.preamble.clone().into(),
+            error_policy: builder.error_policy,
+            on_skip: builder.on_skip.clone(),
+            infer_headers: builder.infer_headers,
+            header_order: builder.header_order,
+            bytes_encoding: builder.bytes_encoding,
+            limits: builder.limits.clone(),
+            sanitize: builder.sanitize,
+            replacement: builder.replacement.clone(),
+            column_quotes: Arc::new(builder.column_quotes.clone()),
+            stopped: None,
+        }
+    }
+
+    /// Serialize a single record using Serde.
+    ///
+    /// # Example
+    ///
+    /// This shows how to serialize normal Rust structs as CSV records. The
+    /// fields of the struct are used to write a header row automatically.
+    /// (Writing the header row automatically can be disabled by building the
+    /// CSV writer with a [`WriterBuilder`](struct.WriterBuilder.html) and
+    /// calling the `has_headers` method.)
+    ///
+    /// ```
+    /// use std::error::Error;
+    ///
+    /// use csv_stream::WriterBuilder;
+    /// use serde::Serialize;
+    ///
+    /// #[derive(Serialize)]
+    /// struct Row<'a> {
+    ///     city: &'a str,
+    ///     country: &'a str,
+    ///     // Serde allows us to name our headers exactly,
+    ///     // even if they don't match our struct field names.
+    ///     #[serde(rename = "popcount")]
+    ///     population: u64,
+    /// }
+    ///
+    /// # fn main() { example().unwrap(); }
+    /// fn example() -> Result<(), Box<dyn Error>> {
+    ///     let mut wtr = WriterBuilder::default().build();
+    ///     let mut buf = vec![];
+    ///     wtr.serialize(
+    ///         &mut buf,
+    ///         Row {
+    ///             city: "Boston",
+    ///             country: "United States",
+    ///             population: 4628910,
+    ///         },
+    ///     )?;
+    ///     wtr.serialize(
+    ///         &mut buf,
+    ///         Row {
+    ///             city: "Concord",
+    ///             country: "United States",
+    ///             population: 42695,
+    ///         },
+    ///     )?;
+    ///
+    ///     let data = String::from_utf8(buf)?;
+    ///     assert_eq!(data, "\
+    /// city,country,popcount
+    /// Boston,United States,4628910
+    /// Concord,United States,42695
+    /// ");
+    ///     Ok(())
+    /// }
+    /// ```
+    ///
+    /// # Rules
+    ///
+    /// The behavior of `serialize` is fairly simple:
+    ///
+    /// 1. Nested containers (tuples, `Vec`s, structs, etc.) are always
+    ///    flattened (depth-first order).
+    ///
+    /// 2. If `has_headers` is `true` and the type contains field names, then
+    ///    a header row is automatically generated.
+    ///
+    /// However, some container types cannot be serialized, and if
+    /// `has_headers` is `true`, there are some additional restrictions on the
+    /// types that can be serialized. See below for details.
+    ///
+    /// For the purpose of this section, Rust types can be divided into three
+    /// categories: scalars, non-struct containers, and structs.
+    ///
+    /// ## Scalars
+    ///
+    /// Single values with no field names are written like the following. Note
+    /// that some of the outputs may be quoted, according to the selected
+    /// quoting style.
+    ///
+    /// | Name | Example Type | Example Value | Output |
+    /// | ---- | ---- | ---- | ---- |
+    /// | boolean | `bool` | `true` | `true` |
+    /// | integers | `i8`, `i16`, `i32`, `i64`, `i128`, `u8`, `u16`, `u32`, `u64`, `u128` | `5` | `5` |
+    /// | floats | `f32`, `f64` | `3.14` | `3.14` |
+    /// | character | `char` | `'☃'` | `☃` |
+    /// | string | `&str` | `"hi"` | `hi` |
+    /// | bytes | `&[u8]` | `b"hi"[..]` | `hi` |
+    /// | option | `Option` | `None` | *empty* |
+    /// | option |          | `Some(5)` | `5` |
+    /// | unit | `()` | `()` | *empty* |
+    /// | unit struct | `struct Foo;` | `Foo` | `Foo` |
+    /// | unit enum variant | `enum E { A, B }` | `E::A` | `A` |
+    /// | newtype struct | `struct Foo(u8);` | `Foo(5)` | `5` |
+    /// | newtype enum variant | `enum E { A(u8) }` | `E::A(5)` | `5` |
+    ///
+    /// Note that this table includes simple structs and enums. For example, to
+    /// serialize a field from either an integer or a float type, one can do
+    /// this:
+    ///
+    /// ```
+    /// use std::error::Error;
+    ///
+    /// use csv_stream::WriterBuilder;
+    /// use serde::Serialize;
+    ///
+    /// #[derive(Serialize)]
+    /// struct Row {
+    ///     label: String,
+    ///     value: Value,
+    /// }
+    ///
+    /// #[derive(Serialize)]
+    /// enum Value {
+    ///     Integer(i64),
+    ///     Float(f64),
+    /// }
+    ///
+    /// # fn main() { example().unwrap(); }
+    /// fn example() -> Result<(), Box<dyn Error>> {
+    ///     let mut wtr = WriterBuilder::default().build();
+    ///     let mut buf = vec![];
+    ///     wtr.serialize(
+    ///         &mut buf,
+    ///         Row {
+    ///             label: "foo".to_string(),
+    ///             value: Value::Integer(3),
+    ///         },
+    ///     )?;
+    ///     wtr.serialize(
+    ///         &mut buf,
+    ///         Row {
+    ///             label: "bar".to_string(),
+    ///             value: Value::Float(3.14),
+    ///         },
+    ///     )?;
+    ///
+    ///     let data = String::from_utf8(buf)?;
+    ///     assert_eq!(data, "\
+    /// label,value
+    /// foo,3
+    /// bar,3.14
+    /// ");
+    ///     Ok(())
+    /// }
+    /// ```
+    ///
+    /// ## Non-Struct Containers
+    ///
+    /// Nested containers are flattened to their scalar components, with the
+    /// exception of a few types that are not allowed:
+    ///
+    /// | Name | Example Type | Example Value | Output |
+    /// | ---- | ---- | ---- | ---- |
+    /// | sequence | `Vec<u8>` | `vec![1, 2, 3]` | `1,2,3` |
+    /// | tuple | `(u8, bool)` | `(5, true)` | `5,true` |
+    /// | tuple struct | `Foo(u8, bool)` | `Foo(5, true)` | `5,true` |
+    /// | tuple enum variant | `enum E { A(u8, bool) }` | `E::A(5, true)` | *error* |
+    /// | struct enum variant | `enum E { V { a: u8, b: bool } }` | `E::V { a: 5, b: true }` | *error* |
+    /// | map | `BTreeMap<K, V>` | `BTreeMap::new()` | *error* |
+    ///
+    /// ## Structs
+    ///
+    /// Like the other containers, structs are flattened to their scalar
+    /// components:
+    ///
+    /// | Name | Example Type | Example Value | Output |
+    /// | ---- | ---- | ---- | ---- |
+    /// | struct | `struct Foo { a: u8, b: bool }` | `Foo { a: 5, b: true }` | `5,true` |
+    ///
+    /// If `has_headers` is `false`, then there are no additional restrictions;
+    /// types can be nested arbitrarily. For example:
+    ///
+    /// ```
+    /// use std::error::Error;
+    ///
+    /// use csv_stream::WriterBuilder;
+    /// use serde::Serialize;
+    ///
+    /// #[derive(Serialize)]
+    /// struct Row {
+    ///     label: String,
+    ///     values: Vec<f64>,
+    /// }
+    ///
+    /// # fn main() { example().unwrap(); }
+    /// fn example() -> Result<(), Box<dyn Error>> {
+    ///     let mut wtr = WriterBuilder::default()
+    ///         .has_headers(false)
+    ///         .build();
+    ///
+    ///     let mut buf = vec![];
+    ///     wtr.serialize(
+    ///         &mut buf,
+    ///         Row {
+    ///             label: "foo".to_string(),
+    ///             values: vec![1.1234, 2.5678, 3.14],
+    ///         },
+    ///     )?;
+    ///
+    ///     let data = String::from_utf8(buf)?;
+    ///     assert_eq!(data, "\
+    /// foo,1.1234,2.5678,3.14
+    /// ");
+    ///     Ok(())
+    /// }
+    /// ```
+    ///
+    /// However, if `has_headers` were enabled in the above example, then
+    /// serialization would return an error. Specifically, when `has_headers` is
+    /// `true`, there are two restrictions:
+    ///
+    /// 1. Named field values in structs must be scalars.
+    ///
+    /// 2. All scalars must be named field values in structs.
+    ///
+    /// Other than these two restrictions, types can be nested arbitrarily.
+    /// Here are a few examples:
+    ///
+    /// | Value | Header | Record |
+    /// | ---- | ---- | ---- |
+    /// | `(Foo { x: 5, y: 6 }, Bar { z: true })` | `x,y,z` | `5,6,true` |
+    /// | `vec![Foo { x: 5, y: 6 }, Foo { x: 7, y: 8 }]` | `x,y,x,y` | `5,6,7,8` |
+    /// | `(Foo { x: 5, y: 6 }, vec![Bar { z: Baz(true) }])` | `x,y,z` | `5,6,true` |
+    /// | `Foo { x: 5, y: (6, 7) }` | *error: restriction 1* | `5,6,7` |
+    /// | `(5, Foo { x: 6, y: 7 }` | *error: restriction 2* | `5,6,7` |
+    /// | `(Foo { x: 5, y: 6 }, true)` | *error: restriction 2* | `5,6,true` |
+    ///
+    /// # Errors
+    ///
+    /// If the record fails to serialize, everything this call wrote to `buf`
+    /// (including a header row) is removed again and the writer is restored
+    /// to the state it was in before the call, so it can carry on writing
+    /// further records.
+    pub fn serialize<B, S>(&mut self, buf: &mut B, record: S) -> Result<()>
+    where
+        B: CsvBuf + ?Sized,
+        S: Serialize,
+    {
+        let checkpoint = self.checkpoint(buf);
+        self.serialize_impl(buf, record)
+            .map_err(|err| self.rollback(buf, checkpoint, err))
+    }
+
+    fn serialize_impl<B, S>(&mut self, buf: &mut B, record: S) -> Result<()>
+    where
+        B: CsvBuf + ?Sized,
+        S: Serialize,
+    {
+        if self.infer_headers > 0 {
+            let fields = capture_fields(&record)?;
+            if self.state.columns.is_none() {
+                self.infer_columns([&Ok(fields.clone())]);
+            }
+            return self.write_fields_impl(buf, fields);
+        }
+
+        self.check_records()?;
+        self.write_preamble(buf)?;
+        if let HeaderState::Write = self.state.header {
+            let stats = self.state.stats.clone();
+            self.state.in_header = true;
+            let wrote_header = serialize_header(self, buf, &record)?;
+            self.state.in_header = false;
+            if wrote_header {
+                self.write_terminator(buf)?;
+                self.state.header = HeaderState::DidWrite;
+                // The header row is not data, so it only counts towards the
+                // number of bytes written.
+                self.state.stats = Stats {
+                    bytes: self.state.stats.bytes,
+                    ..stats
+                };
+            } else {
+                self.state.header = HeaderState::DidNotWrite;
+            };
+        }
+        serialize(self, buf, &record)?;
+        self.write_terminator(buf)?;
+        Ok(())
+    }
+
+    /// Write a single record.
+    ///
+    /// This method accepts something that can be turned into an iterator that
+    /// yields elements that can be represented by a `&[u8]`.
+    ///
+    /// This may be called with an empty iterator, which will cause a record
+    /// terminator to be written. If no fields had been written, then a single
+    /// empty field is written before the terminator.
+    ///
+    /// If an error occurs, everything this call wrote to `buf` is removed
+    /// again and the writer is restored to the state it was in before the
+    /// call.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::error::Error;
+    /// use csv_stream::WriterBuilder;
+    ///
+    /// # fn main() { example().unwrap(); }
+    /// fn example() -> Result<(), Box<dyn Error>> {
+    ///     let mut wtr = WriterBuilder::default().build();
+    ///     let mut buf = vec![];
+    ///     wtr.write_record(&mut buf, &["a", "b", "c"])?;
+    ///     wtr.write_record(&mut buf, &["x", "y", "z"])?;
+    ///
+    ///     let data = String::from_utf8(buf)?;
+    ///     assert_eq!(data, "a,b,c\nx,y,z\n");
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn write_record<B, I, T>(&mut self, buf: &mut B, record: I) -> Result<()>
+    where
+        B: CsvBuf + ?Sized,
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        let checkpoint = self.checkpoint(buf);
+        self.write_record_impl(buf, record)
+            .map_err(|err| self.rollback(buf, checkpoint, err))
+    }
+
+    fn write_record_impl<B, I, T>(&mut self, buf: &mut B, record: I) -> Result<()>
+    where
+        B: CsvBuf + ?Sized,
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        self.check_records()?;
+        self.write_preamble(buf)?;
+        for field in record.into_iter() {
+            self.write_field_impl(buf, field)?;
+        }
+        self.write_terminator(buf)
+    }
+
+    /// Write a single field.
+    ///
+    /// One should prefer using `write_record` over this method. It is provided
+    /// for cases where writing a field at a time is more convenient than
+    /// writing a record at a time.
+    ///
+    /// Note that if this API is used, `write_record` should be called with an
+    /// empty iterator to write a record terminator.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::error::Error;
+    /// use csv_stream::WriterBuilder;
+    ///
+    /// # fn main() { example().unwrap(); }
+    /// fn example() -> Result<(), Box<dyn Error>> {
+    ///     let mut wtr = WriterBuilder::default().build();
+    ///     let mut buf = vec![];
+    ///     wtr.write_field(&mut buf, "a")?;
+    ///     wtr.write_field(&mut buf, "b")?;
+    ///     wtr.write_field(&mut buf, "c")?;
+    ///     wtr.write_record(&mut buf, None::<&[u8]>)?;
     ///     wtr.write_field(&mut buf, "x")?;
     ///     wtr.write_field(&mut buf, "y")?;
     ///     wtr.write_field(&mut buf, "z")?;
     ///     wtr.write_record(&mut buf, None::<&[u8]>)?;
     ///
     ///     let data = String::from_utf8(buf)?;
-    ///     assert_eq!(data, "a,b,c\nx,y,z\n");
+    ///     assert_eq!(data, "a,b,c\nx,y,z\n");
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn write_field<B, T>(&mut self, buf: &mut B, field: T) -> Result<()>
+    where
+        B: CsvBuf + ?Sized,
+        T: AsRef<[u8]>,
+    {
+        self.write_preamble(buf)
+            .and_then(|_| self.write_field_impl(buf, field))
+            .map_err(|err| self.annotate(err))
+    }
+
+    /// Write a single field from bytes, encoded according to the
+    /// configured [`BytesEncoding`].
+    pub(crate) fn write_bytes<B>(&mut self, buf: &mut B, field: &[u8]) -> Result<()>
+    where
+        B: CsvBuf + ?Sized,
+    {
+        #[cfg(feature = "base64")]
+        use base64::engine::general_purpose::{
+            STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD,
+        };
+        #[cfg(feature = "base64")]
+        use base64::Engine;
+
+        match self.bytes_encoding {
+            BytesEncoding::Raw => self.write_field(buf, field),
+            BytesEncoding::Utf8 => match std::str::from_utf8(field) {
+                Ok(field) => self.write_field(buf, field),
+                Err(err) => Err(self.annotate(Error::new(ErrorKind::Utf8(err)))),
+            },
+            BytesEncoding::Hex => self.write_field(buf, encode_hex(field, b"0123456789abcdef")),
+            BytesEncoding::HexUpper => {
+                self.write_field(buf, encode_hex(field, b"0123456789ABCDEF"))
+            }
+            #[cfg(feature = "base64")]
+            BytesEncoding::Base64 => self.write_field(buf, STANDARD.encode(field)),
+            #[cfg(feature = "base64")]
+            BytesEncoding::Base64NoPad => self.write_field(buf, STANDARD_NO_PAD.encode(field)),
+            #[cfg(feature = "base64")]
+            BytesEncoding::Base64UrlSafe => self.write_field(buf, URL_SAFE.encode(field)),
+            #[cfg(feature = "base64")]
+            BytesEncoding::Base64UrlSafeNoPad => {
+                self.write_field(buf, URL_SAFE_NO_PAD.encode(field))
+            }
+        }
+    }
+
+    /// Write a single field serialized from a number, a boolean or a missing
+    /// value, which is not quoted like a string by `QuoteStyle::Strings`.
+    pub(crate) fn write_scalar<B, T>(&mut self, buf: &mut B, field: T) -> Result<()>
+    where
+        B: CsvBuf + ?Sized,
+        T: AsRef<[u8]>,
+    {
+        self.state.in_scalar = true;
+        let res = self.write_field(buf, field);
+        self.state.in_scalar = false;
+        res
+    }
+
+    /// Write a comment line.
+    ///
+    /// The comment is written as the configured comment character followed
+    /// by `text` and a record terminator. If `text` contains line breaks (or
+    /// the record terminator), it is split into several comment lines so
+    /// that every line of it is still read back as a comment.
+    ///
+    /// Comments are not records: they are ignored by the field count check
+    /// and should only be written between records.
+    ///
+    /// This returns an error if no comment character was set with
+    /// [`WriterBuilder::comment`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::error::Error;
+    /// use csv_stream::WriterBuilder;
+    ///
+    /// # fn main() { example().unwrap(); }
+    /// fn example() -> Result<(), Box<dyn Error>> {
+    ///     let mut wtr = WriterBuilder::default()
+    ///         .comment(b'#')
+    ///         .build();
+    ///     let mut buf = vec![];
+    ///     wtr.write_record(&mut buf, &["a", "b", "c"])?;
+    ///     wtr.write_comment(&mut buf, "filters:\ncountry = US")?;
+    ///     wtr.write_record(&mut buf, &["x", "y", "z"])?;
+    ///
+    ///     let data = String::from_utf8(buf)?;
+    ///     assert_eq!(data, "a,b,c\n#filters:\n#country = US\nx,y,z\n");
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn write_comment<B, T>(&mut self, buf: &mut B, text: T) -> Result<()>
+    where
+        B: CsvBuf + ?Sized,
+        T: AsRef<[u8]>,
+    {
+        self.write_preamble(buf)
+            .and_then(|_| self.write_comment_impl(buf, text.as_ref()))
+            .map_err(|err| self.annotate(err))
+    }
+
+    fn write_comment_impl<B>(&mut self, buf: &mut B, mut text: &[u8]) -> Result<()>
+    where
+        B: CsvBuf + ?Sized,
+    {
+        let comment = match self.comment {
+            Some(comment) => comment,
+            None => return Err(Error::new(ErrorKind::CommentsDisabled)),
+        };
+        let term = self.core.get_terminator();
+        let is_break = |b: u8| match term {
+            csv_core::Terminator::Any(t) if t == b => true,
+            _ => b == b'\r' || b == b'\n',
+        };
+
+        let len = buf.len();
+        loop {
+            let end = text.iter().position(|&b| is_break(b));
+            buf.put(&[comment])?;
+            buf.put(&text[..end.unwrap_or(text.len())])?;
+            self.put_terminator(buf)?;
+            match end {
+                None => break,
+                Some(i) if text[i..].starts_with(b"\r\n") => text = &text[i + 2..],
+                Some(i) => text = &text[i + 1..],
+            }
+        }
+        self.state.stats.bytes += (buf.len() - len) as u64;
+        self.state.record_start = self.state.stats.bytes;
+
+        Ok(())
+    }
+
+    /// Write the preamble, if it has not been written yet.
+    fn write_preamble<B: CsvBuf + ?Sized>(&mut self, buf: &mut B) -> Result<()> {
+        if self.state.wrote_preamble {
+            return Ok(());
+        }
+        let preamble = self.preamble.clone();
+        for line in preamble.iter() {
+            self.write_comment_impl(buf, line.as_bytes())?;
+        }
+        self.state.wrote_preamble = true;
+        Ok(())
+    }
+
+    /// Write a header row, unless headers are disabled or a header row has
+    /// already been written.
+    ///
+    /// This is useful for writing records with `write_record` whose field
+    /// names are only known at runtime, and should be called before any
+    /// record is written. Like the header row written by
+    /// `serialize`, it only counts towards the number of bytes in
+    /// [`stats`](Writer::stats).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::error::Error;
+    /// use csv_stream::WriterBuilder;
+    ///
+    /// # fn main() { example().unwrap(); }
+    /// fn example() -> Result<(), Box<dyn Error>> {
+    ///     let mut wtr = WriterBuilder::default().build();
+    ///     let mut buf = vec![];
+    ///     wtr.write_header(&mut buf, &["a", "b"])?;
+    ///     wtr.write_record(&mut buf, &["x", "y"])?;
+    ///     wtr.write_header(&mut buf, &["a", "b"])?;
+    ///     wtr.write_record(&mut buf, &["z", "w"])?;
+    ///
+    ///     let data = String::from_utf8(buf)?;
+    ///     assert_eq!(data, "a,b\nx,y\nz,w\n");
+    ///     assert_eq!(wtr.stats().records(), 2);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn write_header<B, I, T>(&mut self, buf: &mut B, names: I) -> Result<()>
+    where
+        B: CsvBuf + ?Sized,
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        if !matches!(self.state.header, HeaderState::Write) {
+            return Ok(());
+        }
+        let stats = self.state.stats.clone();
+        self.state.in_header = true;
+        let res = self.write_record(buf, names);
+        self.state.in_header = false;
+        res?;
+        self.state.header = HeaderState::DidWrite;
+        self.state.stats = Stats {
+            bytes: self.state.stats.bytes,
+            ..stats
+        };
+        Ok(())
+    }
+
+    /// Write a single record, exempt from the check that all records have
+    /// the same number of fields.
+    ///
+    /// This is otherwise identical to `write_record`. It is useful for writing
+    /// records that are not part of the data, such as a trailer or summary
+    /// record, even when the `flexible` option is disabled. The record does
+    /// not set the expected number of fields for subsequent records either.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::error::Error;
+    /// use csv_stream::WriterBuilder;
+    ///
+    /// # fn main() { example().unwrap(); }
+    /// fn example() -> Result<(), Box<dyn Error>> {
+    ///     let mut wtr = WriterBuilder::default().build();
+    ///     let mut buf = vec![];
+    ///     wtr.write_record(&mut buf, &["a", "b", "c"])?;
+    ///     wtr.write_record(&mut buf, &["x", "y", "z"])?;
+    ///     wtr.write_flexible_record(&mut buf, &["TRAILER", "2"])?;
+    ///
+    ///     let data = String::from_utf8(buf)?;
+    ///     assert_eq!(data, "a,b,c\nx,y,z\nTRAILER,2\n");
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn write_flexible_record<B, I, T>(&mut self, buf: &mut B, record: I) -> Result<()>
+    where
+        B: CsvBuf + ?Sized,
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        let checkpoint = self.checkpoint(buf);
+        self.write_flexible_record_impl(buf, record)
+            .map_err(|err| self.rollback(buf, checkpoint, err))
+    }
+
+    fn write_flexible_record_impl<B, I, T>(&mut self, buf: &mut B, record: I) -> Result<()>
+    where
+        B: CsvBuf + ?Sized,
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        self.write_preamble(buf)?;
+        for field in record.into_iter() {
+            self.write_field_impl(buf, field)?;
+        }
+        self.end_record(buf)
+    }
+
+    /// Returns statistics about the data written so far.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::error::Error;
+    /// use csv_stream::WriterBuilder;
+    ///
+    /// # fn main() { example().unwrap(); }
+    /// fn example() -> Result<(), Box<dyn Error>> {
+    ///     let mut wtr = WriterBuilder::default().build();
+    ///     let mut buf = vec![];
+    ///     wtr.serialize(&mut buf, ("a", 1))?;
+    ///     wtr.serialize(&mut buf, ("b", 2))?;
+    ///
+    ///     let stats = wtr.stats();
+    ///     assert_eq!(stats.records(), 2);
+    ///     assert_eq!(stats.fields(), 4);
+    ///     assert_eq!(stats.bytes(), 8);
     ///     Ok(())
     /// }
     /// ```
-    pub fn write_field<T: AsRef<[u8]>>(&mut self, buf: &mut Vec<u8>, field: T) -> Result<()> {
-        self.write_field_impl(buf, field)
+    pub fn stats(&self) -> Stats {
+        self.state.stats.clone()
+    }
+
+    /// Returns the field delimiter this writer uses.
+    pub fn delimiter(&self) -> u8 {
+        self.core.get_delimiter()
+    }
+
+    /// Returns the record terminator this writer uses.
+    pub fn terminator(&self) -> Terminator {
+        Terminator::from_core(self.core.get_terminator())
+    }
+
+    /// Returns the quoting style this writer uses.
+    pub fn quote_style(&self) -> QuoteStyle {
+        self.quote_style
+    }
+
+    /// Returns the quote character this writer uses.
+    pub fn quote(&self) -> u8 {
+        self.core.get_quote()
+    }
+
+    /// Returns whether this writer writes a header row when serializing
+    /// structs or maps.
+    pub fn has_headers(&self) -> bool {
+        !matches!(self.state.header, HeaderState::None)
+    }
+
+    /// Returns the encoding this writer uses for fields serialized from
+    /// bytes.
+    pub fn bytes_encoding(&self) -> BytesEncoding {
+        self.bytes_encoding
+    }
+
+    /// Returns whether the delimiter, quote, escape, terminator and comment
+    /// characters are all ASCII, so that they keep UTF-8 data valid.
+    #[cfg(feature = "axum")]
+    pub(crate) fn is_ascii_dialect(&self) -> bool {
+        let term = match self.core.get_terminator() {
+            csv_core::Terminator::Any(t) => t,
+            _ => b'\n',
+        };
+        [
+            self.core.get_delimiter(),
+            self.core.get_quote(),
+            self.core.get_escape(),
+            term,
+        ]
+        .iter()
+        .chain(&self.comment)
+        .all(u8::is_ascii)
+    }
+
+    /// Returns whether this writer allows records of different lengths.
+    pub fn is_flexible(&self) -> bool {
+        self.state.flexible
+    }
+
+    /// Returns whether a limit with `LimitPolicy::Truncate` or
+    /// `LimitPolicy::Stop` has ended the output.
+    ///
+    /// Every record written after that fails with an
+    /// [`ErrorKind::LimitExceeded`] error.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.is_some()
+    }
+
+    /// Returns the position of the record currently being written.
+    pub(crate) fn position(&self) -> Position {
+        Position::new(self.state.record_start, self.state.records)
+    }
+
+    /// Returns the number of fields written in the current record.
+    pub(crate) fn fields_written(&self) -> u64 {
+        self.state.fields_written
+    }
+
+    /// Attach the current record position, and for serializer errors the
+    /// current field, to an error.
+    fn annotate(&self, err: Error) -> Error {
+        err.or_field(self.state.fields_written, None)
+            .or_position(self.position())
+    }
+
+    /// Take a snapshot of the writer before writing a record to `buf`.
+    pub(crate) fn checkpoint<B: CsvBuf + ?Sized>(&self, buf: &B) -> Checkpoint {
+        Checkpoint {
+            len: buf.len(),
+            state: self.state.clone(),
+        }
+    }
+
+    /// Undo everything written to `buf` and every change made to the writer
+    /// since `checkpoint` was taken, so that the writer remains usable after
+    /// a failed record.
+    ///
+    /// Returns `err` with the position at which it occurred attached.
+    fn rollback<B>(&mut self, buf: &mut B, checkpoint: Checkpoint, err: Error) -> Error
+    where
+        B: CsvBuf + ?Sized,
+    {
+        let err = self.annotate(err);
+        self.restore(buf, checkpoint);
+        err
+    }
+
+    /// Undo everything written to `buf` and every change made to the writer
+    /// since `checkpoint` was taken.
+    pub(crate) fn restore<B: CsvBuf + ?Sized>(&mut self, buf: &mut B, checkpoint: Checkpoint) {
+        buf.truncate(checkpoint.len);
+        self.state = checkpoint.state;
+    }
+
+    /// Implementation of write_field.
+    ///
+    /// This is a separate method so we can force the compiler to inline it
+    /// into write_record.
+    #[inline(always)]
+    fn write_field_impl<B, T>(&mut self, buf: &mut B, field: T) -> Result<()>
+    where
+        B: CsvBuf + ?Sized,
+        T: AsRef<[u8]>,
+    {
+        if self.state.fields_written > 0 {
+            self.write_delimiter(buf)?;
+        }
+        let field = self.sanitize_field(field.as_ref());
+        if self.state.in_header && !self.column_quotes.by_name.is_empty() {
+            let style = std::str::from_utf8(&field)
+                .ok()
+                .and_then(|name| self.column_quotes.by_name.get(name))
+                .copied();
+            let quotes = self
+                .state
+                .header_quotes
+                .get_or_insert_with(Default::default);
+            Arc::make_mut(quotes).push(style);
+        }
+
+        let len = buf.len();
+        let quoted = if self.limits.is_unlimited() {
+            self.put_field(buf, &field)?
+        } else {
+            self.put_limited_field(buf, &field)?
+        };
+        self.state.fields_written += 1;
+        self.state.stats.bytes += (buf.len() - len) as u64;
+        self.state.stats.fields += 1;
+        if quoted {
+            self.state.stats.quoted_fields += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Replace or escape the control characters in `field`, according to the
+    /// configured `Sanitize` mode.
+    fn sanitize_field<'f>(&self, field: &'f [u8]) -> Cow<'f, [u8]> {
+        let term = self.core.get_terminator();
+        let is_control = |b: u8| match term {
+            csv_core::Terminator::Any(t) if t == b => true,
+            _ => b < 0x20,
+        };
+        let sanitized = match self.sanitize {
+            Sanitize::None => return Cow::Borrowed(field),
+            Sanitize::Replace => !field.iter().any(|&b| is_control(b)),
+            Sanitize::Escape => !field.iter().any(|&b| is_control(b) || b == b'\\'),
+        };
+        if sanitized {
+            return Cow::Borrowed(field);
+        }
+
+        let mut out = Vec::with_capacity(field.len() + 8);
+        for &b in field {
+            match self.sanitize {
+                Sanitize::Replace if is_control(b) => {
+                    out.extend_from_slice(self.replacement.as_bytes())
+                }
+                Sanitize::Escape if is_control(b) || b == b'\\' => match b {
+                    b'\n' => out.extend_from_slice(b"\\n"),
+                    b'\r' => out.extend_from_slice(b"\\r"),
+                    b'\t' => out.extend_from_slice(b"\\t"),
+                    b'\0' => out.extend_from_slice(b"\\0"),
+                    b'\\' => out.extend_from_slice(b"\\\\"),
+                    _ => out.extend_from_slice(format!("\\x{:02x}", b).as_bytes()),
+                },
+                _ => out.push(b),
+            }
+        }
+        Cow::Owned(out)
+    }
+
+    /// Whether `field` should be quoted, according to the quoting style of
+    /// its column.
+    fn should_quote(&self, field: &[u8]) -> bool {
+        if self.column_quotes.is_empty() && !matches!(self.quote_style, QuoteStyle::Strings) {
+            return self.core.should_quote(field);
+        }
+        let index = self.state.fields_written as usize;
+        let style = self
+            .state
+            .header_quotes
+            .as_ref()
+            .and_then(|quotes| quotes.get(index).copied().flatten())
+            .or_else(|| self.column_quotes.by_index.get(&index).copied())
+            .unwrap_or(self.quote_style);
+        let necessary = || field.iter().any(|&b| self.core.is_special_byte(b));
+        match style {
+            QuoteStyle::Always => true,
+            QuoteStyle::Never => false,
+            QuoteStyle::NonNumeric => csv_core::is_non_numeric(field),
+            QuoteStyle::Necessary => necessary(),
+            QuoteStyle::Strings => !self.state.in_scalar || necessary(),
+        }
+    }
+
+    /// Write `field`, in quotes if necessary. Returns whether it was quoted.
+    fn put_field<B: CsvBuf + ?Sized>(&self, buf: &mut B, field: &[u8]) -> Result<bool> {
+        let quoted = self.should_quote(field);
+        if quoted {
+            self.put_quoted(buf, field)?;
+        } else {
+            buf.put(field)?;
+        }
+        Ok(quoted)
+    }
+
+    /// Write `field` like `put_field`, within the limits on the length of
+    /// fields and records.
+    fn put_limited_field<B>(&mut self, buf: &mut B, field: &[u8]) -> Result<bool>
+    where
+        B: CsvBuf + ?Sized,
+    {
+        let mut field = Cow::Borrowed(field);
+        if let Some((max, policy)) = self.limits.field_len {
+            if field.len() as u64 > max {
+                if policy != LimitPolicy::Truncate {
+                    return Err(self.exceed(Limit::FieldLen(max), policy));
+                }
+                field = Cow::Owned(self.limits.truncate(&field, max));
+            }
+        }
+
+        let (max, policy) = match self.limits.record_len {
+            Some(limit) => limit,
+            None => return self.put_field(buf, &field),
+        };
+        let written = self.state.stats.bytes - self.state.record_start;
+        let mut room = max.saturating_sub(written);
+        let len = buf.len();
+        loop {
+            // The field is checked before it is quoted, so that a huge field
+            // is never written.
+            if field.len() as u64 > room {
+                if policy != LimitPolicy::Truncate {
+                    return Err(self.exceed(Limit::RecordLen(max), policy));
+                }
+                field = Cow::Owned(self.limits.truncate(&field, room));
+            }
+            let quoted = self.put_field(buf, &field)?;
+            let overflow = ((buf.len() - len) as u64).saturating_sub(room);
+            if overflow == 0 || field.is_empty() {
+                return Ok(quoted);
+            }
+            if policy != LimitPolicy::Truncate {
+                return Err(self.exceed(Limit::RecordLen(max), policy));
+            }
+            // Quotes took the field over the limit, so make room for them.
+            buf.truncate(len);
+            room = room.saturating_sub(overflow);
+        }
+    }
+
+    /// Returns the error for going over `limit`, and ends the output if the
+    /// policy says so.
+    fn exceed(&mut self, limit: Limit, policy: LimitPolicy) -> Error {
+        if policy != LimitPolicy::Error {
+            self.stopped = Some((limit, policy));
+        }
+        Error::new(ErrorKind::LimitExceeded(limit))
+    }
+
+    /// Check that another record can be written.
+    fn check_records(&mut self) -> Result<()> {
+        if let Some((limit, _)) = self.stopped {
+            return Err(Error::new(ErrorKind::LimitExceeded(limit)));
+        }
+        match self.limits.records {
+            Some((max, policy)) if self.state.stats.records >= max => {
+                Err(self.exceed(Limit::Records(max), policy))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check, before the next record is read, whether the limit on the
+    /// number of records has ended the output, so that no more input is read
+    /// for it.
+    ///
+    /// With `LimitPolicy::Error` every record over the limit is an error of
+    /// its own, so they are still read.
+    pub(crate) fn check_record_limit(&mut self) -> Result<()> {
+        match self.limits.records {
+            Some((max, policy))
+                if policy != LimitPolicy::Error && self.state.stats.records >= max =>
+            {
+                Err(self.exceed(Limit::Records(max), policy))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Write `field` in quotes, escaping any quotes inside it.
+    ///
+    /// The field is written in pieces split around the quotes, so that a
+    /// buffer that checks its contents sees every piece of the field whole.
+    fn put_quoted<B: CsvBuf + ?Sized>(&self, buf: &mut B, mut field: &[u8]) -> Result<()> {
+        let quote = self.core.get_quote();
+        let escape = if self.core.get_double_quote() {
+            quote
+        } else {
+            self.core.get_escape()
+        };
+        buf.put(&[quote])?;
+        while let Some(i) = field.find_byte(quote) {
+            buf.put(&field[..i])?;
+            buf.put(&[escape, quote])?;
+            field = &field[i + 1..];
+        }
+        buf.put(field)?;
+        buf.put(&[quote])
+    }
+
+    /// Write the configured record terminator.
+    fn put_terminator<B: CsvBuf + ?Sized>(&self, buf: &mut B) -> Result<()> {
+        match self.core.get_terminator() {
+            csv_core::Terminator::Any(t) => buf.put(&[t]),
+            _ => buf.put(b"\r\n"),
+        }
+    }
+
+    /// Write a CSV delimiter.
+    fn write_delimiter<B: CsvBuf + ?Sized>(&mut self, buf: &mut B) -> Result<()> {
+        buf.put(&[self.core.get_delimiter()])?;
+        self.state.stats.bytes += 1;
+
+        Ok(())
+    }
+
+    /// Write a CSV terminator.
+    fn write_terminator<B: CsvBuf + ?Sized>(&mut self, buf: &mut B) -> Result<()> {
+        self.check_field_count()?;
+        let pos = self.position();
+        self.end_record(buf)?;
+        match self.limits.bytes {
+            Some((max, policy)) if self.state.stats.bytes > max => {
+                Err(self.exceed(Limit::Bytes(max), policy).or_position(pos))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Write a CSV terminator without checking the number of fields in the
+    /// record.
+    fn end_record<B: CsvBuf + ?Sized>(&mut self, buf: &mut B) -> Result<()> {
+        let width = self.state.fields_written;
+        let len = buf.len();
+        // An empty record is indistinguishable from no record at all, so it
+        // is written as a single empty field in quotes.
+        if self.state.stats.bytes == self.state.record_start {
+            let quote = self.core.get_quote();
+            buf.put(&[quote, quote])?;
+        }
+        self.put_terminator(buf)?;
+        self.state.fields_written = 0;
+        let stats = &mut self.state.stats;
+        stats.bytes += (buf.len() - len) as u64;
+        stats.records += 1;
+        stats.max_record_width = stats.max_record_width.max(width);
+        self.state.records += 1;
+        self.state.record_start = stats.bytes;
+
+        Ok(())
+    }
+
+    /// Apply the configured `ErrorPolicy` to a record that failed to be
+    /// written to `buf`.
+    pub(crate) fn recover<B: CsvBuf + ?Sized>(&mut self, buf: &mut B, err: Error) -> Recovery {
+        if let Some((limit, policy)) = self.stopped {
+            if policy == LimitPolicy::Stop && self.comment.is_some() {
+                if let Err(err) = self.write_comment(buf, format!("truncated: {}", limit)) {
+                    return Recovery::Abort(err);
+                }
+            }
+            return Recovery::Stop;
+        }
+        match self.error_policy {
+            ErrorPolicy::Continue => return Recovery::Yield(err),
+            ErrorPolicy::Comment if self.comment.is_none() => return Recovery::Yield(err),
+            ErrorPolicy::Abort => return Recovery::Abort(err),
+            ErrorPolicy::Skip | ErrorPolicy::Placeholder | ErrorPolicy::Comment => {}
+        }
+
+        self.state.skipped += 1;
+        if let Some(SkipHook(ref f)) = self.on_skip {
+            f(&err);
+        }
+
+        let res = match self.error_policy {
+            ErrorPolicy::Placeholder => self.write_placeholder(buf, &err),
+            ErrorPolicy::Comment => self.write_comment(buf, err.to_string()),
+            _ => return Recovery::Skip,
+        };
+        match res {
+            Ok(()) => Recovery::Placeholder,
+            Err(err) => Recovery::Yield(err),
+        }
+    }
+
+    /// Write a row describing `err`, padded with empty fields to the number
+    /// of fields expected per record.
+    ///
+    /// This row is exempt from the field count check, and does not set the
+    /// expected number of fields if it is not yet known.
+    fn write_placeholder<B>(&mut self, buf: &mut B, err: &Error) -> Result<()>
+    where
+        B: CsvBuf + ?Sized,
+    {
+        self.write_preamble(buf)?;
+        let width = self.state.first_field_count.unwrap_or(1);
+        self.write_field_impl(buf, err.to_string())?;
+        for _ in 1..width {
+            self.write_field_impl(buf, "")?;
+        }
+        self.end_record(buf)
+    }
+
+    /// Run the trailer hook, if any, once all input has been written, or a
+    /// limit has ended the output.
+    ///
+    /// Returns `buf` followed by the output of the hook, or `None` if there
+    /// is nothing to yield.
+    pub(crate) fn finish(
+        &mut self,
+        mut buf: Vec<u8>,
+        trailer: Option<Trailer>,
+    ) -> Option<Result<Vec<u8>>> {
+        if let Some(trailer) = trailer {
+            let stats = self.stats();
+            if let Err(err) = trailer(&stats, self, &mut buf) {
+                return Some(Err(err));
+            }
+        }
+        if buf.is_empty() {
+            None
+        } else {
+            Some(Ok(buf))
+        }
+    }
+
+    /// Returns the number of fields expected per record, if known.
+    pub(crate) fn first_field_count(&self) -> Option<u64> {
+        self.state.first_field_count
+    }
+
+    /// Set the number of fields expected per record, such as when carrying
+    /// on from the output of another writer.
+    pub(crate) fn set_first_field_count(&mut self, count: Option<u64>) {
+        self.state.first_field_count = count;
+    }
+
+    /// Whether the header row, preamble and number of fields per record are
+    /// all known, so that the remaining records can be serialized
+    /// independently of one another with [`Writer::serialize_detached`].
+    #[cfg(any(feature = "rayon", feature = "tokio"))]
+    pub(crate) fn is_settled(&self) -> bool {
+        !matches!(self.state.header, HeaderState::Write)
+            && self.state.wrote_preamble
+            // New columns can only be added to the inferred header row in
+            // order.
+            && !(self.infer_headers > 0 && self.state.flexible)
+            // Limits depend on the records written before.
+            && self.limits.is_unlimited()
+            && (self.state.flexible || self.state.first_field_count.is_some())
+    }
+
+    /// Serialize a single record on a copy of this writer, without changing
+    /// this writer. The record can then be added to the output with
+    /// [`Writer::merge`].
+    ///
+    /// This must only be used once the writer [is settled](Writer::is_settled).
+    #[cfg(any(feature = "rayon", feature = "tokio"))]
+    pub(crate) fn serialize_detached<S: Serialize>(&self, record: S) -> Detached {
+        let mut wtr = self.clone();
+        // The field count is checked when the record is merged, in order.
+        wtr.state.flexible = true;
+        let mut buf = vec![];
+        let result = wtr.serialize(&mut buf, record);
+        Detached {
+            buf,
+            result,
+            fields: wtr.state.stats.fields - self.state.stats.fields,
+            quoted_fields: wtr.state.stats.quoted_fields - self.state.stats.quoted_fields,
+        }
     }
 
-    /// Implementation of write_field.
+    /// Add a record serialized by [`Writer::serialize_detached`] to `buf`, as
+    /// if it had been serialized by this writer.
     ///
-    /// This is a separate method so we can force the compiler to inline it
-    /// into write_record.
-    #[inline(always)]
-    fn write_field_impl<T: AsRef<[u8]>>(&mut self, buf: &mut Vec<u8>, field: T) -> Result<()> {
-        if self.state.fields_written > 0 {
-            self.write_delimiter(buf)?;
+    /// Records must be merged in the same order as they would otherwise have
+    /// been serialized.
+    #[cfg(any(feature = "rayon", feature = "tokio"))]
+    pub(crate) fn merge<B>(&mut self, buf: &mut B, record: Detached) -> Result<()>
+    where
+        B: CsvBuf + ?Sized,
+    {
+        // The record was serialized in the position of the first record after
+        // the copy was made, which may no longer be the next record.
+        record
+            .result
+            .map_err(|err| err.at_position(self.position()))?;
+        if !self.state.flexible {
+            match self.state.first_field_count {
+                None => self.state.first_field_count = Some(record.fields),
+                Some(expected) if expected != record.fields => {
+                    let err = Error::new(ErrorKind::UnequalLengths {
+                        expected_len: expected,
+                        len: record.fields,
+                    });
+                    return Err(err.at_position(self.position()));
+                }
+                Some(_) => {}
+            }
         }
-        let field = field.as_ref();
-
-        extend(buf, 2 * field.len() + 2, |buf| {
-            let (res, nin, nout) = self.core.field(field, buf);
-            debug_assert_eq!(res, WriteResult::InputEmpty);
-            debug_assert_eq!(nin, field.len());
-            self.state.fields_written += 1;
-            nout
-        });
 
+        buf.put(&record.buf)
+            .map_err(|err| err.at_position(self.position()))?;
+        let stats = &mut self.state.stats;
+        stats.records += 1;
+        stats.fields += record.fields;
+        stats.bytes += record.buf.len() as u64;
+        stats.quoted_fields += record.quoted_fields;
+        stats.max_record_width = stats.max_record_width.max(record.fields);
+        self.state.records += 1;
+        self.state.record_start = stats.bytes;
         Ok(())
     }
 
-    /// Write a CSV delimiter.
-    fn write_delimiter(&mut self, buf: &mut Vec<u8>) -> Result<()> {
-        extend(buf, 2, |buf| {
-            let (res, nout) = self.core.delimiter(buf);
-            debug_assert_eq!(res, WriteResult::InputEmpty);
-            nout
-        });
+    /// Returns the number of records dropped according to the error policy.
+    pub(crate) fn skipped(&self) -> u64 {
+        self.state.skipped
+    }
 
-        Ok(())
+    /// Returns the number of records to read ahead of time to infer the
+    /// header row, if it is yet to be inferred.
+    pub(crate) fn infer_window(&self) -> Option<usize> {
+        if self.infer_headers > 0 && self.state.columns.is_none() {
+            Some(self.infer_headers)
+        } else {
+            None
+        }
     }
 
-    /// Write a CSV terminator.
-    fn write_terminator(&mut self, buf: &mut Vec<u8>) -> Result<()> {
-        self.check_field_count()?;
-        extend(buf, 4, |buf| {
-            let (res, nout) = self.core.terminator(buf);
-            debug_assert_eq!(res, WriteResult::InputEmpty);
-            self.state.fields_written = 0;
-            nout
-        });
+    /// Infer the header row from the fields of records read ahead of time.
+    ///
+    /// Records that could not be captured are ignored here, and fail once
+    /// they are written instead.
+    pub(crate) fn infer_columns<'a, I>(&mut self, records: I)
+    where
+        I: IntoIterator<Item = &'a Result<Fields>>,
+    {
+        let mut columns = Columns::default();
+        for (name, _) in records.into_iter().flatten().flatten() {
+            columns.push(name);
+        }
+        if let HeaderOrder::Sorted = self.header_order {
+            columns.names.sort();
+            for (i, name) in columns.names.iter().enumerate() {
+                columns.index.insert(name.clone(), i);
+            }
+        }
+        self.state.columns = Some(Arc::new(columns));
+    }
 
-        Ok(())
+    /// Write a record captured ahead of time to infer the header row.
+    ///
+    /// This is otherwise identical to `serialize`, including how errors are
+    /// handled.
+    pub(crate) fn write_fields<B>(&mut self, buf: &mut B, fields: Result<Fields>) -> Result<()>
+    where
+        B: CsvBuf + ?Sized,
+    {
+        let checkpoint = self.checkpoint(buf);
+        fields
+            .and_then(|fields| self.write_fields_impl(buf, fields))
+            .map_err(|err| self.rollback(buf, checkpoint, err))
+    }
+
+    /// Write the fields of a record in the order of the inferred header row,
+    /// along with the header row if it has not been written yet.
+    fn write_fields_impl<B>(&mut self, buf: &mut B, fields: Fields) -> Result<()>
+    where
+        B: CsvBuf + ?Sized,
+    {
+        self.check_records()?;
+        let mut columns = self.state.columns.clone().unwrap_or_default();
+        let mut row: Vec<Option<Scalar>> = vec![None; columns.names.len()];
+        for (name, value) in fields {
+            let i = match columns.index.get(&name) {
+                Some(&i) => i,
+                None if self.state.flexible => {
+                    Arc::make_mut(&mut columns).push(&name);
+                    row.push(None);
+                    row.len() - 1
+                }
+                None => {
+                    let err = Error::new(ErrorKind::Serialize(
+                        "field is not in the inferred header row".to_owned(),
+                    ));
+                    return Err(err.or_field(row.len() as u64, Some(&name)));
+                }
+            };
+            row[i] = Some(value);
+        }
+        self.state.columns = Some(Arc::clone(&columns));
+
+        self.write_preamble(buf)?;
+        self.write_header(buf, &columns.names)?;
+        serialize(self, buf, &row)?;
+        self.write_terminator(buf)
     }
 
     fn check_field_count(&mut self) -> Result<()> {
@@ -954,17 +2769,49 @@ impl Writer {
     }
 }
 
-fn extend(buf: &mut Vec<u8>, max: usize, f: impl FnOnce(&mut [u8]) -> usize) {
-    let len = buf.len();
-    buf.resize(len + max, 0);
-    let n = f(&mut buf[len..]);
-    buf.resize(len + n, 0);
+/// A hook that writes final records once all input has been written.
+pub(crate) type Trailer = Box<dyn FnOnce(&Stats, &mut Writer, &mut Vec<u8>) -> Result<()> + Send>;
+
+/// A snapshot of a writer taken before writing a record.
+pub(crate) struct Checkpoint {
+    /// The length of the output buffer.
+    len: usize,
+    state: WriterState,
+}
+
+/// A record serialized by [`Writer::serialize_detached`], waiting to be
+/// merged back into the output.
+#[cfg(any(feature = "rayon", feature = "tokio"))]
+pub(crate) struct Detached {
+    buf: Vec<u8>,
+    result: Result<()>,
+    /// The number of fields in the record.
+    fields: u64,
+    /// The number of those fields that were quoted.
+    quoted_fields: u64,
+}
+
+/// What an adapter should do after the configured `ErrorPolicy` has been
+/// applied to a record that failed to be written.
+pub(crate) enum Recovery {
+    /// Yield this error in place of the record.
+    Yield(Error),
+    /// Yield this error in place of the record and then stop.
+    Abort(Error),
+    /// Move on to the next record.
+    Skip,
+    /// Yield the placeholder row or comment that was written to the buffer.
+    Placeholder,
+    /// A limit has ended the output. Finish with the truncation comment, if
+    /// any, that was written to the buffer.
+    Stop,
 }
 
 #[cfg(test)]
 mod tests {
     use super::WriterBuilder;
-    use serde::{serde_if_integer128, Serialize};
+    use crate::{ErrorKind, Terminator};
+    use serde::Serialize;
 
     fn buf_as_string(buf: Vec<u8>) -> String {
         String::from_utf8(buf).unwrap()
@@ -974,7 +2821,7 @@ mod tests {
     fn one_record() {
         let mut wtr = WriterBuilder::default().build();
         let mut buf = vec![];
-        wtr.write_record(&mut buf, &["a", "b", "c"]).unwrap();
+        wtr.write_record(&mut buf, ["a", "b", "c"]).unwrap();
 
         assert_eq!(buf_as_string(buf), "a,b,c\n");
     }
@@ -983,7 +2830,7 @@ mod tests {
     fn one_empty_record() {
         let mut wtr = WriterBuilder::default().build();
         let mut buf = vec![];
-        wtr.write_record(&mut buf, &[""]).unwrap();
+        wtr.write_record(&mut buf, [""]).unwrap();
 
         assert_eq!(buf_as_string(buf), "\"\"\n");
     }
@@ -992,8 +2839,8 @@ mod tests {
     fn two_empty_records() {
         let mut wtr = WriterBuilder::default().build();
         let mut buf = vec![];
-        wtr.write_record(&mut buf, &[""]).unwrap();
-        wtr.write_record(&mut buf, &[""]).unwrap();
+        wtr.write_record(&mut buf, [""]).unwrap();
+        wtr.write_record(&mut buf, [""]).unwrap();
 
         assert_eq!(buf_as_string(buf), "\"\"\n\"\"\n");
     }
@@ -1044,26 +2891,27 @@ mod tests {
         assert_eq!(buf_as_string(buf), "42,42.5,true\n");
     }
 
-    serde_if_integer128! {
-        #[test]
-        fn serialize_no_headers_128() {
-            #[derive(Serialize)]
-            struct Row {
-                foo: i128,
-                bar: f64,
-                baz: bool,
-            }
+    #[test]
+    fn serialize_no_headers_128() {
+        #[derive(Serialize)]
+        struct Row {
+            foo: i128,
+            bar: f64,
+            baz: bool,
+        }
 
-            let mut wtr =
-                WriterBuilder::default().has_headers(false).build();
-            let mut buf = vec![];
-            wtr.serialize(&mut buf, Row {
+        let mut wtr = WriterBuilder::default().has_headers(false).build();
+        let mut buf = vec![];
+        wtr.serialize(
+            &mut buf,
+            Row {
                 foo: 9_223_372_036_854_775_808,
                 bar: 42.5,
                 baz: true,
-            }).unwrap();
-            assert_eq!(buf_as_string(buf), "9223372036854775808,42.5,true\n");
-        }
+            },
+        )
+        .unwrap();
+        assert_eq!(buf_as_string(buf), "9223372036854775808,42.5,true\n");
     }
 
     #[test]
@@ -1073,4 +2921,519 @@ mod tests {
         wtr.serialize(&mut buf, (true, 1.3, "hi")).unwrap();
         assert_eq!(buf_as_string(buf), "true,1.3,hi\n");
     }
+
+    #[test]
+    fn unequal_lengths_position() {
+        let mut wtr = WriterBuilder::default().build();
+        let mut buf = vec![];
+        wtr.write_record(&mut buf, ["a", "b"]).unwrap();
+        wtr.write_record(&mut buf, ["c", "d"]).unwrap();
+        let err = wtr.write_record(&mut buf, ["x", "y", "z"]).unwrap_err();
+
+        let pos = err.position().unwrap();
+        assert_eq!(pos.record(), 2);
+        assert_eq!(pos.byte(), 8);
+        assert_eq!(err.field(), None);
+        assert_eq!(
+            err.to_string(),
+            "CSV error: record 2 (byte: 8): found record with 3 fields, \
+             but the previous record has 2 fields"
+        );
+    }
+
+    #[test]
+    fn serialize_error_position() {
+        use std::collections::BTreeMap;
+
+        #[derive(Serialize)]
+        struct Row {
+            foo: i32,
+            bar: Option<BTreeMap<i32, i32>>,
+        }
+
+        let mut wtr = WriterBuilder::default().build();
+        let mut buf = vec![];
+        wtr.serialize(&mut buf, Row { foo: 1, bar: None }).unwrap();
+        let err = wtr
+            .serialize(
+                &mut buf,
+                Row {
+                    foo: 2,
+                    bar: Some(BTreeMap::new()),
+                },
+            )
+            .unwrap_err();
+
+        let pos = err.position().unwrap();
+        assert_eq!(pos.record(), 2);
+        assert_eq!(pos.byte(), 11);
+        assert_eq!(err.field(), Some(1));
+        assert_eq!(err.header(), Some("bar"));
+        assert!(err
+            .to_string()
+            .starts_with("CSV write error: record 2 (byte: 11), field 1 (\"bar\"): "));
+    }
+
+    #[test]
+    fn rollback_unequal_lengths() {
+        let mut wtr = WriterBuilder::default().build();
+        let mut buf = vec![];
+        wtr.write_record(&mut buf, ["a", "b"]).unwrap();
+        wtr.write_record(&mut buf, ["x", "y", "z"]).unwrap_err();
+        wtr.write_record(&mut buf, ["c", "d"]).unwrap();
+
+        assert_eq!(buf_as_string(buf), "a,b\nc,d\n");
+    }
+
+    #[test]
+    fn rollback_partial_record() {
+        use std::collections::BTreeMap;
+
+        #[derive(Serialize)]
+        struct Row {
+            foo: &'static str,
+            bar: (i32, Option<BTreeMap<i32, i32>>),
+        }
+
+        let mut wtr = WriterBuilder::default().has_headers(false).build();
+        let mut buf = vec![];
+        wtr.serialize(
+            &mut buf,
+            Row {
+                foo: "a\"b",
+                bar: (1, None),
+            },
+        )
+        .unwrap();
+        wtr.serialize(
+            &mut buf,
+            Row {
+                foo: "c\"d",
+                bar: (2, Some(BTreeMap::new())),
+            },
+        )
+        .unwrap_err();
+        wtr.serialize(
+            &mut buf,
+            Row {
+                foo: "e",
+                bar: (3, None),
+            },
+        )
+        .unwrap();
+
+        assert_eq!(buf_as_string(buf), "\"a\"\"b\",1,\ne,3,\n");
+    }
+
+    #[test]
+    fn rollback_header() {
+        use std::collections::BTreeMap;
+
+        #[derive(Serialize)]
+        struct Row {
+            foo: i32,
+            bar: Option<BTreeMap<i32, i32>>,
+        }
+
+        let mut wtr = WriterBuilder::default().build();
+        let mut buf = vec![];
+        wtr.serialize(
+            &mut buf,
+            Row {
+                foo: 1,
+                bar: Some(BTreeMap::new()),
+            },
+        )
+        .unwrap_err();
+        assert_eq!(buf_as_string(buf.clone()), "");
+
+        wtr.serialize(&mut buf, Row { foo: 2, bar: None }).unwrap();
+        assert_eq!(buf_as_string(buf), "foo,bar\n2,\n");
+    }
+
+    #[test]
+    fn rollback_after_write_field() {
+        let mut wtr = WriterBuilder::default().build();
+        let mut buf = vec![];
+        wtr.write_record(&mut buf, ["a", "b"]).unwrap();
+        wtr.write_field(&mut buf, "x\"").unwrap();
+        wtr.write_record(&mut buf, ["y", "z"]).unwrap_err();
+        wtr.write_record(&mut buf, ["y"]).unwrap();
+
+        assert_eq!(buf_as_string(buf), "a,b\n\"x\"\"\",y\n");
+    }
+
+    #[test]
+    fn comment_line_breaks() {
+        let mut wtr = WriterBuilder::default()
+            .comment(b'#')
+            .terminator(Terminator::CRLF)
+            .build();
+        let mut buf = vec![];
+        wtr.write_comment(&mut buf, "a\r\nb\nc\rd").unwrap();
+        wtr.write_record(&mut buf, ["#x", "y"]).unwrap();
+
+        assert_eq!(buf_as_string(buf), "#a\r\n#b\r\n#c\r\n#d\r\n\"#x\",y\r\n");
+    }
+
+    #[test]
+    fn comment_terminator() {
+        let mut wtr = WriterBuilder::default()
+            .comment(b'%')
+            .terminator(Terminator::Any(b';'))
+            .build();
+        let mut buf = vec![];
+        wtr.write_comment(&mut buf, "a;b").unwrap();
+
+        assert_eq!(buf_as_string(buf), "%a;%b;");
+    }
+
+    #[test]
+    fn comment_disabled() {
+        let mut wtr = WriterBuilder::default().build();
+        let mut buf = vec![];
+        let err = wtr.write_comment(&mut buf, "hello").unwrap_err();
+        match *err.kind() {
+            ErrorKind::CommentsDisabled => {}
+            ref x => panic!("expected ErrorKind::CommentsDisabled but got '{:?}'", x),
+        }
+    }
+
+    #[test]
+    fn preamble() {
+        let mut wtr = WriterBuilder::default()
+            .comment(b'#')
+            .preamble(["one", "two"])
+            .build();
+        let mut buf = vec![];
+        wtr.write_record(&mut buf, ["a", "b"]).unwrap();
+        wtr.write_record(&mut buf, ["c", "d"]).unwrap();
+
+        assert_eq!(buf_as_string(buf), "#one\n#two\na,b\nc,d\n");
+    }
+
+    #[test]
+    fn preamble_rollback() {
+        let mut wtr = WriterBuilder::default()
+            .comment(b'#')
+            .preamble(["one"])
+            .build();
+        let mut buf = vec![];
+        wtr.serialize(&mut buf, (1, std::collections::BTreeMap::<i32, i32>::new()))
+            .unwrap_err();
+        assert_eq!(buf_as_string(buf.clone()), "");
+        wtr.serialize(&mut buf, (1, 2)).unwrap();
+
+        assert_eq!(buf_as_string(buf), "#one\n1,2\n");
+    }
+
+    #[test]
+    fn stats() {
+        let mut wtr = WriterBuilder::default().build();
+        let mut buf = vec![];
+        wtr.serialize(&mut buf, ("a,b", 1)).unwrap();
+        wtr.write_flexible_record(&mut buf, ["x", "y", "\"z\""])
+            .unwrap();
+
+        let stats = wtr.stats();
+        assert_eq!(stats.records(), 2);
+        assert_eq!(stats.fields(), 5);
+        assert_eq!(stats.bytes(), buf.len() as u64);
+        assert_eq!(stats.quoted_fields(), 2);
+        assert_eq!(stats.max_record_width(), 3);
+    }
+
+    #[test]
+    fn stats_exclude_header() {
+        #[derive(Serialize)]
+        struct Row {
+            foo: &'static str,
+            bar: i32,
+        }
+
+        let mut wtr = WriterBuilder::default().build();
+        let mut buf = vec![];
+        wtr.serialize(&mut buf, Row { foo: "a b", bar: 1 }).unwrap();
+
+        assert_eq!(buf_as_string(buf), "foo,bar\na b,1\n");
+        let stats = wtr.stats();
+        assert_eq!(stats.records(), 1);
+        assert_eq!(stats.fields(), 2);
+        assert_eq!(stats.bytes(), 14);
+        assert_eq!(stats.quoted_fields(), 0);
+        assert_eq!(stats.max_record_width(), 2);
+    }
+
+    #[test]
+    fn bytes_encoding() {
+        use crate::BytesEncoding;
+
+        struct Bytes(&'static [u8]);
+
+        impl Serialize for Bytes {
+            fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+                s.serialize_bytes(self.0)
+            }
+        }
+
+        let record = (Bytes(b"\xfb\xff"), "caf\u{e9}");
+        let cases = [
+            (BytesEncoding::Hex, "fbff,caf\u{e9}\n"),
+            (BytesEncoding::HexUpper, "FBFF,caf\u{e9}\n"),
+            #[cfg(feature = "base64")]
+            (BytesEncoding::Base64, "+/8=,caf\u{e9}\n"),
+            #[cfg(feature = "base64")]
+            (BytesEncoding::Base64NoPad, "+/8,caf\u{e9}\n"),
+            #[cfg(feature = "base64")]
+            (BytesEncoding::Base64UrlSafe, "-_8=,caf\u{e9}\n"),
+            #[cfg(feature = "base64")]
+            (BytesEncoding::Base64UrlSafeNoPad, "-_8,caf\u{e9}\n"),
+        ];
+        for (encoding, expected) in cases {
+            let mut wtr = WriterBuilder::default().bytes_encoding(encoding).build();
+            let mut buf = vec![];
+            wtr.serialize(&mut buf, &record).unwrap();
+            assert_eq!(buf_as_string(buf), expected);
+        }
+
+        let mut wtr = WriterBuilder::default().build();
+        let mut buf = vec![];
+        wtr.serialize(&mut buf, &record).unwrap();
+        assert_eq!(buf, b"\xfb\xff,caf\xc3\xa9\n");
+
+        let mut wtr = WriterBuilder::default()
+            .bytes_encoding(BytesEncoding::Utf8)
+            .build();
+        let mut buf = vec![];
+        wtr.serialize(&mut buf, (Bytes(b"ok"),)).unwrap();
+        let err = wtr.serialize(&mut buf, (Bytes(b"\xfb\xff"),)).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Utf8(_)));
+        assert_eq!(buf_as_string(buf), "ok\n");
+    }
+
+    #[test]
+    fn max_field_len() {
+        use crate::{Limit, LimitPolicy};
+
+        let mut wtr = WriterBuilder::default()
+            .max_field_len(4, LimitPolicy::Error)
+            .build();
+        let mut buf = vec![];
+        wtr.write_record(&mut buf, ["a", "bcde"]).unwrap();
+        let err = wtr.write_record(&mut buf, ["a", "bcdef"]).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::LimitExceeded(Limit::FieldLen(4))
+        ));
+        assert!(!wtr.is_stopped());
+        wtr.write_record(&mut buf, ["f", "g"]).unwrap();
+        assert_eq!(buf_as_string(buf), "a,bcde\nf,g\n");
+
+        let mut wtr = WriterBuilder::default()
+            .max_field_len(6, LimitPolicy::Truncate)
+            .ellipsis("\u{2026}")
+            .build();
+        let mut buf = vec![];
+        wtr.write_record(&mut buf, ["abcdefgh", "\u{e9}\u{e9}\u{e9}\u{e9}"])
+            .unwrap();
+        assert_eq!(buf_as_string(buf), "abc\u{2026},\u{e9}\u{2026}\n");
+
+        let mut wtr = WriterBuilder::default()
+            .max_field_len(4, LimitPolicy::Stop)
+            .build();
+        let mut buf = vec![];
+        wtr.write_record(&mut buf, ["a", "bcdef"]).unwrap_err();
+        assert!(wtr.is_stopped());
+        let err = wtr.write_record(&mut buf, ["a"]).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::LimitExceeded(Limit::FieldLen(4))
+        ));
+        assert_eq!(buf_as_string(buf), "");
+    }
+
+    #[test]
+    fn max_record_len() {
+        use crate::{Limit, LimitPolicy};
+
+        let mut wtr = WriterBuilder::default()
+            .max_record_len(8, LimitPolicy::Truncate)
+            .build();
+        let mut buf = vec![];
+        wtr.write_record(&mut buf, ["abc", "defg"]).unwrap();
+        wtr.write_record(&mut buf, ["abc", "defghi"]).unwrap();
+        // The quotes are made room for.
+        wtr.write_record(&mut buf, ["a", "b\"cdefgh"]).unwrap();
+        assert_eq!(buf_as_string(buf), "abc,defg\nabc,d...\na,...\n");
+
+        let mut wtr = WriterBuilder::default()
+            .max_record_len(8, LimitPolicy::Truncate)
+            .flexible(true)
+            .build();
+        let mut buf = vec![];
+        wtr.write_record(&mut buf, ["abc", "defgh", "ij"]).unwrap();
+        assert_eq!(buf_as_string(buf), "abc,d...,\n");
+
+        let mut wtr = WriterBuilder::default()
+            .max_record_len(8, LimitPolicy::Error)
+            .build();
+        let mut buf = vec![];
+        let err = wtr.write_record(&mut buf, ["abc", "defgh"]).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::LimitExceeded(Limit::RecordLen(8))
+        ));
+        // Fits before it is quoted, but not after.
+        wtr.write_record(&mut buf, ["abc", "d,ef"]).unwrap_err();
+        wtr.write_record(&mut buf, ["ab", "d,e"]).unwrap();
+        assert_eq!(buf_as_string(buf), "ab,\"d,e\"\n");
+    }
+
+    #[test]
+    fn max_bytes() {
+        use crate::{Limit, LimitPolicy};
+
+        let mut wtr = WriterBuilder::default()
+            .max_bytes(10, LimitPolicy::Error)
+            .build();
+        let mut buf = vec![];
+        wtr.write_record(&mut buf, ["abc"]).unwrap();
+        let err = wtr.write_record(&mut buf, ["defghi"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "CSV write error: record 1 (byte: 4): \
+             output is longer than the maximum of 10 bytes"
+        );
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::LimitExceeded(Limit::Bytes(10))
+        ));
+        wtr.write_record(&mut buf, ["defgh"]).unwrap();
+        assert_eq!(buf_as_string(buf), "abc\ndefgh\n");
+        assert_eq!(wtr.stats().records(), 2);
+    }
+
+    #[test]
+    fn sanitize() {
+        use crate::Sanitize;
+
+        let record = ["a\r\nb", "c\td\0", "e\\f\x1b"];
+
+        let mut wtr = WriterBuilder::default().build();
+        let mut buf = vec![];
+        wtr.write_record(&mut buf, record).unwrap();
+        assert_eq!(buf_as_string(buf), "\"a\r\nb\",c\td\0,e\\f\x1b\n");
+
+        let mut wtr = WriterBuilder::default().sanitize(Sanitize::Replace).build();
+        let mut buf = vec![];
+        wtr.write_record(&mut buf, record).unwrap();
+        assert_eq!(buf_as_string(buf), "a  b,c d ,e\\f \n");
+
+        let mut wtr = WriterBuilder::default()
+            .sanitize(Sanitize::Replace)
+            .sanitize_replacement("")
+            .build();
+        let mut buf = vec![];
+        wtr.write_record(&mut buf, record).unwrap();
+        assert_eq!(buf_as_string(buf), "ab,cd,e\\f\n");
+
+        let mut wtr = WriterBuilder::default().sanitize(Sanitize::Escape).build();
+        let mut buf = vec![];
+        wtr.write_record(&mut buf, record).unwrap();
+        assert_eq!(buf_as_string(buf), "a\\r\\nb,c\\td\\0,e\\\\f\\x1b\n");
+
+        let mut wtr = WriterBuilder::default()
+            .sanitize(Sanitize::Replace)
+            .terminator(Terminator::Any(b';'))
+            .build();
+        let mut buf = vec![];
+        wtr.write_record(&mut buf, ["a;b", "c"]).unwrap();
+        assert_eq!(buf_as_string(buf), "a b,c;");
+    }
+
+    #[test]
+    fn column_quote_style() {
+        use crate::QuoteStyle;
+
+        let mut wtr = WriterBuilder::default()
+            .column_quote_style("text", QuoteStyle::Always)
+            .column_quote_style("amount", QuoteStyle::Never)
+            .column_index_quote_style(0, QuoteStyle::NonNumeric)
+            .column_index_quote_style(1, QuoteStyle::Never)
+            .build();
+        let mut buf = vec![];
+        wtr.write_header(&mut buf, ["id", "text", "amount"])
+            .unwrap();
+        wtr.write_record(&mut buf, ["1", "a", "1.5"]).unwrap();
+        wtr.write_record(&mut buf, ["x", "b,c", "2"]).unwrap();
+        assert_eq!(
+            buf_as_string(buf),
+            "\"id\",\"text\",amount\n1,\"a\",1.5\n\"x\",\"b,c\",2\n"
+        );
+
+        // Without a header row, only the styles set by index apply.
+        let mut wtr = WriterBuilder::default()
+            .has_headers(false)
+            .quote_style(QuoteStyle::Always)
+            .column_quote_style("text", QuoteStyle::Never)
+            .column_index_quote_style(1, QuoteStyle::Necessary)
+            .build();
+        let mut buf = vec![];
+        wtr.write_header(&mut buf, ["id", "text"]).unwrap();
+        wtr.write_record(&mut buf, ["1", "a"]).unwrap();
+        wtr.write_record(&mut buf, ["2", "b,c"]).unwrap();
+        assert_eq!(buf_as_string(buf), "\"1\",a\n\"2\",\"b,c\"\n");
+    }
+
+    #[test]
+    fn quote_style_strings() {
+        use crate::QuoteStyle;
+
+        #[derive(Serialize)]
+        struct Row {
+            zip: &'static str,
+            id: &'static str,
+            count: u64,
+            big: i128,
+            ratio: f64,
+            ok: bool,
+            missing: Option<u32>,
+            empty: &'static str,
+        }
+
+        let mut wtr = WriterBuilder::default()
+            .quote_style(QuoteStyle::Strings)
+            .build();
+        let mut buf = vec![];
+        let row = Row {
+            zip: "02134",
+            id: "1e5",
+            count: 3,
+            big: -1,
+            ratio: 1.5,
+            ok: true,
+            missing: None,
+            empty: "",
+        };
+        wtr.serialize(&mut buf, row).unwrap();
+        wtr.write_record(&mut buf, ["1", "2", "3", "4", "5", "6", "7", "8"])
+            .unwrap();
+        assert_eq!(
+            buf_as_string(buf),
+            "\"zip\",\"id\",\"count\",\"big\",\"ratio\",\"ok\",\"missing\",\"empty\"\n\
+             \"02134\",\"1e5\",3,-1,1.5,true,,\"\"\n\
+             \"1\",\"2\",\"3\",\"4\",\"5\",\"6\",\"7\",\"8\"\n"
+        );
+        assert!(matches!(wtr.quote_style(), QuoteStyle::Strings));
+
+        // Numbers are still quoted when necessary.
+        let mut wtr = WriterBuilder::default()
+            .delimiter(b'.')
+            .column_index_quote_style(1, QuoteStyle::Strings)
+            .build();
+        let mut buf = vec![];
+        wtr.serialize(&mut buf, ("a", "b", 1.5)).unwrap();
+        wtr.serialize(&mut buf, ("a", 2, 2)).unwrap();
+        assert_eq!(buf_as_string(buf), "a.\"b\".\"1.5\"\na.2.2\n");
+    }
 }